@@ -0,0 +1,68 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Append-only audit log of DNS mutations, kept separate from the operational
+//! logs so it can be retained independently of log rotation.
+
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path, time::SystemTime};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// A single mutation attempt, serialized as one JSON object per line.
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    pub provider: &'static str,
+    /// Name of the credential used, never the secret itself
+    pub credential: Option<String>,
+    pub record: &'a str,
+    pub record_type: &'a str,
+    pub old: Option<String>,
+    /// The new value, or none if the record was deleted
+    pub new: Option<String>,
+    /// Servers the change was sent to, in order, including the ones that
+    /// failed. Only known for RFC 2136 providers.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<String>,
+    /// The error that caused the mutation to fail, if it failed
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    result: &'static str,
+    #[serde(flatten)]
+    entry: Entry<'a>,
+}
+
+/// Append an entry to the audit log at `path`. The file is opened in append
+/// mode for every entry, so external rotation or archival is picked up.
+pub fn record(path: &Path, entry: Entry) -> Result<()> {
+    let line = Line {
+        timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        result: match entry.error {
+            Some(_) => "failure",
+            None => "success",
+        },
+        entry,
+    };
+    let mut line = serde_json::to_string(&line).context("Failed to serialize audit entry")?;
+    line.push('\n');
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)
+        .context("Failed to open audit log")?
+        .write_all(line.as_bytes())
+        .context("Failed to write audit log")
+}
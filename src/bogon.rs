@@ -0,0 +1,103 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Detection of addresses that aren't reachable from the internet, like the
+//! shared address space used for CGNAT.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use serde::{Deserialize, Serialize};
+
+/// What to do when the detected address is a bogon.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Keep the records as they are, logging a warning
+    #[default]
+    Skip,
+    /// Treat it as a failed update
+    Fail,
+    /// Publish the address anyway
+    Update,
+}
+
+const V4: &[(Ipv4Net, &str)] = &[
+    (v4(0, 0, 0, 0, 8), "in the \"this network\" range"),
+    (v4(10, 0, 0, 0, 8), "private"),
+    (
+        v4(100, 64, 0, 0, 10),
+        "in the shared address space used for CGNAT",
+    ),
+    (v4(127, 0, 0, 0, 8), "a loopback address"),
+    (v4(169, 254, 0, 0, 16), "link-local"),
+    (v4(172, 16, 0, 0, 12), "private"),
+    (v4(192, 0, 0, 0, 24), "reserved for protocol assignments"),
+    (v4(192, 0, 2, 0, 24), "reserved for documentation"),
+    (v4(192, 168, 0, 0, 16), "private"),
+    (v4(198, 18, 0, 0, 15), "reserved for benchmarking"),
+    (v4(198, 51, 100, 0, 24), "reserved for documentation"),
+    (v4(203, 0, 113, 0, 24), "reserved for documentation"),
+    (v4(224, 0, 0, 0, 4), "a multicast address"),
+    (v4(240, 0, 0, 0, 4), "reserved"),
+];
+
+const V6: &[(Ipv6Net, &str)] = &[
+    (v6([0, 0, 0, 0, 0, 0, 0, 0], 128), "unspecified"),
+    (v6([0, 0, 0, 0, 0, 0, 0, 1], 128), "a loopback address"),
+    (
+        v6([0, 0, 0, 0, 0, 0xffff, 0, 0], 96),
+        "an IPv4-mapped address",
+    ),
+    (
+        v6([0x100, 0, 0, 0, 0, 0, 0, 0], 64),
+        "reserved for discarding",
+    ),
+    (
+        v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32),
+        "reserved for documentation",
+    ),
+    (
+        v6([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+        "a unique local address",
+    ),
+    (v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10), "link-local"),
+    (v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8), "a multicast address"),
+];
+
+/// All global unicast addresses are allocated from this range.
+const GLOBAL_UNICAST: Ipv6Net = v6([0x2000, 0, 0, 0, 0, 0, 0, 0], 3);
+
+const fn v4(a: u8, b: u8, c: u8, d: u8, prefix_len: u8) -> Ipv4Net {
+    Ipv4Net::new_assert(Ipv4Addr::new(a, b, c, d), prefix_len)
+}
+
+const fn v6(segments: [u16; 8], prefix_len: u8) -> Ipv6Net {
+    let [a, b, c, d, e, f, g, h] = segments;
+    Ipv6Net::new_assert(Ipv6Addr::new(a, b, c, d, e, f, g, h), prefix_len)
+}
+
+/// Why the address isn't reachable from the internet, if it isn't.
+pub fn reason(addr: IpAddr) -> Option<&'static str> {
+    match addr {
+        IpAddr::V4(addr) => V4
+            .iter()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(_, reason)| *reason),
+        IpAddr::V6(addr) => V6
+            .iter()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|(_, reason)| *reason)
+            .or_else(|| {
+                (!GLOBAL_UNICAST.contains(&addr)).then_some("outside the global unicast range")
+            }),
+    }
+}
@@ -0,0 +1,510 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    fs::{DirBuilder, File, OpenOptions, read_to_string},
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use hickory_proto::rr::{Name, RecordType};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    unistd::geteuid,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use thiserror::Error;
+use toml::{from_str, to_string};
+
+use crate::{config::ClientConfig, history::Db, outbound::Stats};
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Default)]
+pub struct Cache {
+    /// Last detected IPv4 address
+    pub v4: Option<Ipv4Addr>,
+    /// Last detected IPv6 address
+    pub v6: Option<Ipv6Addr>,
+    /// Unix timestamp of the last time a record was set
+    pub last_update: Option<u64>,
+    #[serde(default)]
+    pub failures: u64,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// Recent results of each IP source, oldest first
+    #[serde(default)]
+    pub sources: BTreeMap<String, VecDeque<Sample>>,
+    /// State of each record, keyed by its name and type, and then by the ID
+    /// of the provider it's published to
+    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+    #[serde(default)]
+    pub records: BTreeMap<RecordKey, BTreeMap<String, Published>>,
+    /// State of each provider and address family as written by earlier
+    /// versions, keyed like `rfc2136/ipv4`, until it's moved to `records`
+    #[serde(default, skip_serializing)]
+    published: BTreeMap<String, Published>,
+    /// Counters of the outbound calls to each provider and source since startup
+    #[serde(default)]
+    pub outbound: BTreeMap<String, Stats>,
+}
+
+/// What a provider was last successfully updated with, for one address family.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Published {
+    pub addr: Option<IpAddr>,
+    /// Addresses published along with `addr`, when publishing all of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional: Vec<IpAddr>,
+    /// Unix timestamp of the first failed update since the last successful one.
+    /// Set while the provider still has to catch up with the current address.
+    pub failing_since: Option<u64>,
+    /// Unix timestamp of the last successful update
+    #[serde(default)]
+    pub last_success: Option<u64>,
+    /// Unix timestamp of the last time the record was found unchanged on the
+    /// provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_verified: Option<u64>,
+}
+
+impl Published {
+    /// Whether the record hasn't been set or verified for `interval`.
+    pub fn stale(&self, interval: Duration) -> bool {
+        self.last_success
+            .max(self.last_verified)
+            .is_none_or(|checked| crate::now() >= checked + interval.as_secs())
+    }
+}
+
+/// Name and type of a record, written like `home.example.com. A`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct RecordKey {
+    pub name: Name,
+    pub record_type: RecordType,
+}
+
+impl Display for RecordKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.record_type)
+    }
+}
+
+impl FromStr for RecordKey {
+    type Err = anyhow::Error;
+
+    fn from_str(key: &str) -> Result<Self> {
+        let (name, record_type) = key
+            .rsplit_once(' ')
+            .ok_or_else(|| anyhow!("Missing record type in {:?}", key))?;
+        Ok(Self {
+            name: name.parse()?,
+            record_type: record_type.parse()?,
+        })
+    }
+}
+
+/// Name of the cache file within the cache directory
+pub const CACHE_FILE: &str = "cache.toml";
+
+/// Name of the lock file within the cache directory
+pub const LOCK_FILE: &str = "dyndnsd.lock";
+
+/// Number of results kept per IP source
+const SOURCE_HISTORY_LENGTH: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Sample {
+    /// Unix timestamp of the detection
+    pub timestamp: u64,
+    pub addr: Option<IpAddr>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+impl Sample {
+    pub fn new(result: Result<IpAddr, String>, latency: Duration) -> Self {
+        let (addr, error) = match result {
+            Ok(addr) => (Some(addr), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            timestamp: crate::now(),
+            addr,
+            latency_ms: latency.as_millis() as u64,
+            error,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("{0} is writable by other users")]
+    WorldWritable(PathBuf),
+    #[error("{path} is owned by uid {owner}, expected uid {expected} or root")]
+    ForeignOwner {
+        path: PathBuf,
+        owner: u32,
+        expected: u32,
+    },
+    #[error("The cache file could not be parsed: {0}")]
+    Unparsable(#[from] toml::de::Error),
+    #[error("The cache contains the implausible address {0}")]
+    ImplausibleAddress(IpAddr),
+}
+
+impl Cache {
+    pub fn record_source(&mut self, source: String, sample: Sample) {
+        let history = self.sources.entry(source).or_default();
+        history.push_back(sample);
+        while history.len() > SOURCE_HISTORY_LENGTH {
+            history.pop_front();
+        }
+    }
+
+    /// Move the provider state written by earlier versions, which was only
+    /// kept per address family, to the records. `domain` is the record the
+    /// state without a record name belongs to.
+    pub fn migrate(&mut self, domain: &Name) {
+        for (key, published) in std::mem::take(&mut self.published) {
+            // Keyed like `rfc2136/ipv4`, or `rfc2136/nas.example.com./ipv6`
+            // for other records.
+            let parsed = key.rsplit_once('/').and_then(|(rest, family)| {
+                let record_type = match family {
+                    "ipv4" => RecordType::A,
+                    "ipv6" => RecordType::AAAA,
+                    _ => return None,
+                };
+                let (id, name) = match rest.split_once('/') {
+                    Some((id, name)) => (id, name.parse().ok()?),
+                    None => (rest, domain.clone()),
+                };
+                Some((id.to_string(), RecordKey { name, record_type }))
+            });
+            let Some((id, record)) = parsed else {
+                tracing::warn!("Dropping the cached state of unknown key {}", key);
+                continue;
+            };
+            self.records
+                .entry(record)
+                .or_default()
+                .entry(id)
+                .or_insert(published);
+        }
+    }
+
+    fn check_addresses(&self) -> Result<(), StateError> {
+        let published = self
+            .records
+            .values()
+            .flat_map(BTreeMap::values)
+            .chain(self.published.values())
+            .flat_map(|published| {
+                published
+                    .addr
+                    .into_iter()
+                    .chain(published.additional.iter().copied())
+            });
+        let detected = self
+            .v4
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(self.v6.map(IpAddr::V6));
+        for addr in detected.chain(published) {
+            let implausible = match addr {
+                IpAddr::V4(v4) => {
+                    v4.is_unspecified()
+                        || v4.is_loopback()
+                        || v4.is_multicast()
+                        || v4.is_broadcast()
+                        || v4.is_link_local()
+                }
+                IpAddr::V6(v6) => v6.is_unspecified() || v6.is_loopback() || v6.is_multicast(),
+            };
+            if implausible {
+                return Err(StateError::ImplausibleAddress(addr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the detected and published addresses, so the next cycle updates
+    /// every record, whatever the providers were last updated with. The
+    /// counters and the history of the sources are kept.
+    pub fn flush(&mut self) {
+        self.v4 = None;
+        self.v6 = None;
+        self.records.clear();
+        self.published.clear();
+    }
+}
+
+/// Where the cache is kept, set as `cache` in the config.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// In the cache directory, or in the state database if one is configured
+    #[default]
+    File,
+    /// Only in memory, seeded from the providers on startup, for read-only
+    /// filesystems
+    Memory,
+}
+
+/// Where the cache is kept, the cache file in the cache directory, the state
+/// database if one is configured, or nowhere with `cache = "memory"`.
+pub enum Store {
+    File { dir: PathBuf, path: PathBuf },
+    Sqlite(Db),
+    Memory,
+}
+
+impl Store {
+    /// The store of the daemon, creating the state database if it's set and
+    /// doesn't exist yet.
+    pub fn new(cache_dir: &Path, mode: Mode, state_db: Option<&Path>) -> Result<Self> {
+        Ok(match (mode, state_db) {
+            (Mode::Memory, _) => Store::Memory,
+            (Mode::File, Some(path)) => Store::Sqlite(Db::open(path)?),
+            (Mode::File, None) => Store::File {
+                dir: cache_dir.to_owned(),
+                path: cache_dir.join(CACHE_FILE),
+            },
+        })
+    }
+
+    /// The store of the daemon using the config at `config_path`, for the
+    /// commands reading its state. Only `cache` and `state_db` are read from
+    /// the config, so this works with a config the daemon would reject as well.
+    pub fn configured(cache_dir: &Path, config_path: &Path) -> Result<Self> {
+        let config = ClientConfig::read(config_path);
+        Ok(match (config.cache, config.state_db) {
+            (Mode::File, Some(path)) => Store::Sqlite(Db::open_existing(&path)?),
+            (mode, _) => Store::new(cache_dir, mode, None)?,
+        })
+    }
+
+    /// The database the history is kept in, if there is one.
+    pub fn history(&self) -> Option<&Db> {
+        match self {
+            Store::Sqlite(db) => Some(db),
+            Store::File { .. } | Store::Memory => None,
+        }
+    }
+
+    /// Whether the cache is kept in memory only, so it can't be read by
+    /// other processes.
+    pub fn is_memory(&self) -> bool {
+        matches!(self, Store::Memory)
+    }
+
+    /// Load the cache without any checks, for read-only consumers of the
+    /// state, or `None` if nothing was cached yet.
+    pub fn load(&self) -> Result<Option<Cache>> {
+        let cache_string = match self {
+            Store::File { path, .. } => match read_to_string(path) {
+                Ok(cache_string) => Some(cache_string),
+                Err(error) if error.kind() == ErrorKind::NotFound => None,
+                Err(error) => return Err(error).context("Failed to read cache file"),
+            },
+            Store::Sqlite(db) => db.load_state()?,
+            Store::Memory => None,
+        };
+        cache_string
+            .map(|cache_string| from_str(&cache_string).context("Failed to parse cache file"))
+            .transpose()
+    }
+
+    /// Load the cache for the daemon, creating the cache directory if needed.
+    ///
+    /// Before the cache is trusted, the ownership and permissions of the cache
+    /// directory and file, or of the state database, are checked, as well as
+    /// the plausibility of the cached addresses. A cache that fails these
+    /// checks could suppress necessary updates, so it's discarded, or with
+    /// `strict` an error is returned instead.
+    pub fn load_checked(&self, strict: bool) -> Result<Cache> {
+        let result = match self {
+            Store::File { dir, path } => {
+                create_dir(dir)?;
+                check_path(dir).and_then(|_| match read_to_string(path) {
+                    Ok(cache_string) => {
+                        check_path(path)?;
+                        parse_checked(&cache_string)
+                    }
+                    Err(error) if error.kind() == ErrorKind::NotFound => Ok(Cache::default()),
+                    Err(error) => Err(error).context("Failed to read cache file"),
+                })
+            }
+            Store::Sqlite(db) => check_path(db.path()).and_then(|_| match db.load_state()? {
+                Some(cache_string) => parse_checked(&cache_string),
+                None => Ok(Cache::default()),
+            }),
+            Store::Memory => Ok(Cache::default()),
+        };
+        match result {
+            Ok(cache) => Ok(cache),
+            Err(error) if strict => Err(error.context("Refusing to use untrustworthy state")),
+            Err(error) => {
+                tracing::warn!("Discarding cache: {:#}", error);
+                Ok(Cache::default())
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(store = %self))]
+    pub fn write(&self, cache: &Cache) -> Result<()> {
+        let serialize = || to_string(cache).context("Failed to serialize cache file");
+        match self {
+            Store::File { path, .. } => {
+                let cache_str = serialize()?;
+                let mut cache_file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(path)
+                    .context("Failed to open cache file for writing")?;
+                cache_file
+                    .write_all(cache_str.as_bytes())
+                    .context("Failed to serialize cache into file")?;
+            }
+            Store::Sqlite(db) => db.save_state(&serialize()?)?,
+            Store::Memory => return Ok(()),
+        }
+        tracing::debug!("Wrote cache");
+        Ok(())
+    }
+}
+
+/// Exclusive lock of a [`Store`], held until it's dropped.
+pub struct Lock {
+    /// The lock file, if the store has one
+    _file: Option<Flock<File>>,
+}
+
+impl Store {
+    /// The lock file next to the cache file or state database.
+    fn lock_path(&self) -> Option<PathBuf> {
+        match self {
+            Store::File { dir, .. } => Some(dir.join(LOCK_FILE)),
+            Store::Sqlite(db) => {
+                let mut path = db.path().as_os_str().to_owned();
+                path.push(".lock");
+                Some(path.into())
+            }
+            Store::Memory => None,
+        }
+    }
+
+    /// Lock the state against other instances, so they don't race on it and
+    /// on the records, failing if another instance holds the lock already.
+    /// The lock file records the process ID of the holder. State kept in
+    /// memory isn't shared, and isn't locked.
+    pub fn lock(&self) -> Result<Lock> {
+        if let Store::File { dir, .. } = self {
+            create_dir(dir)?;
+        }
+        let Some(path) = self.lock_path() else {
+            return Ok(Lock { _file: None });
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+        let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => file,
+            Err((mut file, Errno::EWOULDBLOCK)) => {
+                let mut holder = String::new();
+                let holder = match file.read_to_string(&mut holder) {
+                    Ok(_) if !holder.trim().is_empty() => format!(" (pid {})", holder.trim()),
+                    _ => String::new(),
+                };
+                bail!(
+                    "Another instance of dyndnsd{} is already using {}",
+                    holder,
+                    self
+                );
+            }
+            Err((_, errno)) => {
+                return Err(errno).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        };
+        file.set_len(0)
+            .and_then(|_| file.write_all(format!("{}\n", std::process::id()).as_bytes()))
+            .with_context(|| format!("Failed to write lock file {}", path.display()))?;
+        Ok(Lock { _file: Some(file) })
+    }
+}
+
+impl Display for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Store::File { path, .. } => write!(f, "{}", path.display()),
+            Store::Sqlite(db) => write!(f, "{}", db.path().display()),
+            Store::Memory => f.write_str("the in-memory cache"),
+        }
+    }
+}
+
+pub(crate) fn create_dir(dir: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .context("Failed to create cache directory")
+}
+
+fn parse_checked(cache_string: &str) -> Result<Cache> {
+    let cache: Cache = from_str(cache_string)?;
+    cache.check_addresses()?;
+    Ok(cache)
+}
+
+/// Lock the cache shared by the concurrent updates of both address families.
+/// A panic while it was locked doesn't leave it inconsistent, so poisoning is
+/// ignored.
+pub fn lock(cache: &Mutex<Cache>) -> MutexGuard<'_, Cache> {
+    cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn check_path(path: &Path) -> Result<()> {
+    let metadata = path
+        .metadata()
+        .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+    let expected = geteuid().as_raw();
+    if metadata.uid() != expected && metadata.uid() != 0 {
+        return Err(StateError::ForeignOwner {
+            path: path.to_owned(),
+            owner: metadata.uid(),
+            expected,
+        }
+        .into());
+    }
+    if metadata.mode() & 0o002 != 0 {
+        return Err(StateError::WorldWritable(path.to_owned()).into());
+    }
+    Ok(())
+}
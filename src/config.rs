@@ -0,0 +1,562 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! The config file, usually `/etc/dyndnsd/config.toml`.
+
+use std::{
+    fs::read_to_string,
+    net::IpAddr,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use hickory_proto::rr::{Name, RecordType};
+use nix::unistd::geteuid;
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use toml::from_str;
+use zeroize::Zeroizing;
+
+use crate::{
+    audit,
+    bogon::Policy as BogonPolicy,
+    cache, dns,
+    duration::HumanDuration,
+    filter::Filters,
+    healthchecks::Healthchecks,
+    hooks::Hooks,
+    lan::Lan,
+    mqtt,
+    notify::Notifications,
+    outbound::Outbound,
+    provider::{self, Config as ProviderConfig},
+    proxy::{self, Proxy},
+    server,
+    source::Sources,
+    telemetry::Telemetry,
+    textfile, vault, zone,
+};
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// One or more providers the records are published to
+    #[serde(deserialize_with = "provider::deserialize")]
+    pub dns_provider_config: Vec<ProviderConfig>,
+    /// Zone containing `domain`, discovered through its SOA record if not set
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub zone: Option<Name>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub domain: Name,
+    /// Further names set to the same addresses as `domain`, relative to the
+    /// zone unless they end with a dot
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub aliases: Vec<Name>,
+    #[serde(default = "yes")]
+    pub ipv4: bool,
+    #[serde(default = "no")]
+    pub ipv6: bool,
+    /// Publish every detected IPv6 address as one RRset, instead of only the
+    /// first one
+    #[serde(default = "no")]
+    pub publish_all_ipv6: bool,
+    /// Keep a TXT record at `_dyndnsd.<record>` next to every record, with
+    /// the time of its last update and the version of dyndnsd
+    #[serde(default = "no")]
+    pub metadata_record: bool,
+    /// Reverse zones the PTR records of the published addresses are kept in
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub reverse_zones: Vec<Name>,
+    /// Delete the records when the daemon is stopped, or set them to the
+    /// `shutdown_fallback` addresses of their family
+    #[serde(default = "no")]
+    pub delete_on_shutdown: bool,
+    /// Addresses the records are set to on shutdown instead of deleting them
+    #[serde(default)]
+    pub shutdown_fallback: Vec<IpAddr>,
+    /// What to do with detected addresses that aren't reachable from the internet
+    #[serde(default)]
+    pub bogon_policy: BogonPolicy,
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_interval")]
+    pub interval: Duration,
+    /// Interval of the IPv4 updates, instead of `interval`
+    #[serde_as(as = "Option<HumanDuration>")]
+    #[serde(default)]
+    pub interval_v4: Option<Duration>,
+    /// Interval of the IPv6 updates, instead of `interval`
+    #[serde_as(as = "Option<HumanDuration>")]
+    #[serde(default)]
+    pub interval_v6: Option<Duration>,
+    /// How long an unchanged record is trusted, before it's checked against
+    /// the provider, or set again if the provider can't be queried
+    #[serde_as(as = "Option<HumanDuration>")]
+    #[serde(default)]
+    pub reassert_interval: Option<Duration>,
+    /// Skip the families without a default route, instead of failing to
+    /// update them
+    #[serde(default = "yes")]
+    pub skip_offline: bool,
+    /// Refuse to start instead of discarding an untrustworthy cache
+    #[serde(default = "no")]
+    pub strict_state: bool,
+    /// Refuse to start instead of warning about a config or key file other
+    /// users can access
+    #[serde(default = "no")]
+    pub strict_modes: bool,
+    /// Restrict the filesystem access and system calls of the daemon
+    #[serde(default = "no")]
+    pub sandbox: bool,
+    /// Query the records from the providers on startup, instead of trusting
+    /// the cache
+    #[serde(default = "no")]
+    pub seed_from_dns: bool,
+    /// Where the cache is kept
+    #[serde(default)]
+    pub cache: cache::Mode,
+    /// SQLite database the state and its history are kept in, instead of the
+    /// cache file
+    pub state_db: Option<PathBuf>,
+    /// Append-only log of all DNS mutations
+    pub audit_log: Option<PathBuf>,
+    /// Where the addresses are detected
+    #[serde(default)]
+    pub sources: Sources,
+    /// Post-processing of the detected addresses
+    #[serde(default)]
+    pub filters: Filters,
+    /// Other hosts in the LAN, whose IPv6 addresses share the detected prefix
+    #[serde(default)]
+    pub lan: Lan,
+    /// Timeouts, retries and rate limits of all outbound calls
+    #[serde(default)]
+    pub outbound: Outbound,
+    /// Channels notified about changed records and failed updates
+    #[serde(default)]
+    pub notifications: Notifications,
+    /// Commands run when records change or fail to be updated
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Check pinged after every cycle
+    pub healthchecks: Option<Healthchecks>,
+    /// File the metrics are written to after every cycle, for node_exporter's
+    /// textfile collector
+    pub prometheus_textfile: Option<PathBuf>,
+    /// Run an update cycle right away when NetworkManager reports a change of
+    /// the connection or its addresses
+    #[serde(default = "no")]
+    pub watch_networkmanager: bool,
+    /// Unix socket the running daemon can be controlled through
+    pub control_socket: Option<PathBuf>,
+    /// dyndns2 endpoint routers and other clients push their addresses to
+    pub server: Option<server::Config>,
+    /// OpenTelemetry collector the spans and metrics are exported to
+    pub telemetry: Option<Telemetry>,
+    /// Broker the current state is published to
+    pub mqtt: Option<mqtt::Config>,
+    /// Vault server secrets are read from
+    pub vault: Option<vault::Config>,
+    /// Proxy all HTTP requests are sent through, instead of the one set in
+    /// the environment
+    pub proxy: Option<Proxy>,
+}
+
+/// Make the relative `aliases` relative to `zone`, checking that all of them
+/// are within it.
+fn qualify(aliases: &mut [Name], zone: &Name) -> Result<()> {
+    for alias in aliases {
+        if !alias.is_fqdn() {
+            *alias = alias
+                .clone()
+                .append_domain(zone)
+                .with_context(|| format!("The alias {alias} is too long"))?;
+        }
+        if !zone.zone_of(alias) {
+            bail!("{} is not within the zone {}", alias, zone);
+        }
+    }
+    Ok(())
+}
+
+/// Name of the TXT record with the metadata of `record`.
+pub(crate) fn metadata_name(record: &Name) -> Result<Name> {
+    Name::from_ascii("_dyndnsd")?
+        .append_name(record)
+        .with_context(|| format!("The metadata record of {record} is too long"))
+}
+
+/// The parts of the config the commands reading the state of the daemon or
+/// talking to it need, read leniently so they work with a config the daemon
+/// would reject as well.
+#[derive(Deserialize, Default)]
+pub(crate) struct ClientConfig {
+    pub control_socket: Option<PathBuf>,
+    #[serde(default)]
+    pub cache: cache::Mode,
+    pub state_db: Option<PathBuf>,
+}
+
+impl ClientConfig {
+    pub fn read(path: &Path) -> Self {
+        read_to_string(path)
+            .ok()
+            .and_then(|config| from_str(&config).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl Config {
+    /// The configured providers, along with the ID their state is tracked under.
+    pub fn providers(&self) -> impl Iterator<Item = (String, &ProviderConfig)> {
+        provider::ids(&self.dns_provider_config)
+            .into_iter()
+            .zip(&self.dns_provider_config)
+    }
+
+    pub fn zone(&self) -> &Name {
+        self.zone
+            .as_ref()
+            .expect("the zone is discovered right after loading the config")
+    }
+
+    /// The records the daemon updates, with their type and zone: the A and
+    /// AAAA records of `domain`, its aliases and the dyndns2 hostnames, the
+    /// AAAA records of the LAN hosts, their metadata records, and the PTR
+    /// records in the reverse zones, represented by the zone itself as their
+    /// names depend on the addresses.
+    pub fn managed_records(&self) -> Result<Vec<(Name, RecordType, Name)>> {
+        let zone = self.zone();
+        let pushed: Vec<&Name> = self
+            .server
+            .iter()
+            .flat_map(server::Config::hostnames)
+            .collect();
+        let mut records = Vec::new();
+        let own = || std::iter::once(&self.domain).chain(&self.aliases);
+        if self.ipv4 {
+            records.extend(own().map(|name| (name.clone(), RecordType::A)));
+        }
+        if self.ipv6 {
+            records.extend(own().map(|name| (name.clone(), RecordType::AAAA)));
+            for host in &self.lan.hosts {
+                let names = std::iter::once(&host.domain).chain(&host.aliases);
+                records.extend(names.map(|name| (name.clone(), RecordType::AAAA)));
+            }
+        }
+        // Pushed addresses are published regardless of the enabled families.
+        for name in &pushed {
+            records.push(((*name).clone(), RecordType::A));
+            records.push(((*name).clone(), RecordType::AAAA));
+        }
+        if self.metadata_record {
+            let lan = self.lan.hosts.iter().filter(|_| self.ipv6);
+            let named = std::iter::once(&self.domain)
+                .chain(lan.map(|host| &host.domain))
+                .chain(pushed.iter().copied());
+            for name in named {
+                records.push((metadata_name(name)?, RecordType::TXT));
+            }
+        }
+        let mut records: Vec<_> = records
+            .into_iter()
+            .map(|(name, record_type)| (name, record_type, zone.clone()))
+            .collect();
+        records.extend(
+            self.reverse_zones
+                .iter()
+                .map(|zone| (zone.clone(), RecordType::PTR, zone.clone())),
+        );
+        Ok(records)
+    }
+
+    /// The closest configured reverse zone containing the PTR record of `addr`.
+    pub fn reverse_zone(&self, addr: IpAddr) -> Option<&Name> {
+        let name = Name::from(addr);
+        self.reverse_zones
+            .iter()
+            .filter(|zone| zone.zone_of(&name))
+            .max_by_key(|zone| zone.num_labels())
+    }
+
+    /// Read and validate the config, discovering the zone if it isn't set.
+    pub async fn load(path: &Path) -> Result<Self> {
+        let mut config = Self::read(path)?;
+        config.check_modes(path)?;
+        config.validate().await?;
+        Ok(config)
+    }
+
+    /// Read the config without checking it, for the settings needed before
+    /// it can be loaded. Secrets in Vault are read as well.
+    pub fn read(path: &Path) -> Result<Self> {
+        let config_string =
+            Zeroizing::new(read_to_string(path).context("couldn't read config file!")?);
+        let parse = || {
+            from_str(&config_string)
+                .map_err(|error| redact(&config_string, error))
+                .context("Failed to parse config file")
+        };
+        let vault = vault::Config::read(&config_string)
+            .map_err(|error| redact(&config_string, error))
+            .context("Failed to parse config file")?;
+        match vault {
+            Some(vault) => {
+                let session = vault.login()?;
+                vault::with_session(session, parse)
+            }
+            None => parse(),
+        }
+    }
+
+    /// Check that the config at `path` and the key files it refers to are
+    /// owned by the current user or root, and can't be read or changed by
+    /// other users, like OpenSSH's `StrictModes`. Files that fail the checks
+    /// are only warned about, unless `strict_modes` is set.
+    fn check_modes(&self, path: &Path) -> Result<()> {
+        let files = std::iter::once(path).chain(
+            self.dns_provider_config
+                .iter()
+                .flat_map(ProviderConfig::secret_files),
+        );
+        for file in files {
+            // Missing key files are reported by the validation.
+            let Ok(metadata) = file.metadata() else {
+                continue;
+            };
+            let euid = geteuid().as_raw();
+            let problem = if metadata.uid() != euid && metadata.uid() != 0 {
+                format!("is owned by uid {}", metadata.uid())
+            } else if metadata.mode() & 0o006 != 0 {
+                format!(
+                    "can be accessed by other users (mode {:o})",
+                    metadata.mode() & 0o777
+                )
+            } else {
+                continue;
+            };
+            if self.strict_modes {
+                bail!(
+                    "{} {}, refusing to use the secrets in it",
+                    file.display(),
+                    problem
+                );
+            }
+            tracing::warn!("{} {}, though it contains secrets", file.display(), problem);
+        }
+        Ok(())
+    }
+
+    /// Check the parts of the config serde can't, discovering the zone if it
+    /// isn't set. Configs that weren't loaded with [`Self::load`] have to be
+    /// validated before they're used. The configured proxy is only used for
+    /// the discovery here, it's applied once an [`Updater`] uses the config.
+    ///
+    /// [`Updater`]: crate::Updater
+    pub async fn validate(&mut self) -> Result<()> {
+        let config = self;
+        config.filters.validate().context("Invalid filters")?;
+        config.sources.validate().context("Invalid sources")?;
+        config.lan.validate().context("Invalid LAN hosts")?;
+        for (id, provider) in config.providers() {
+            provider
+                .validate()
+                .with_context(|| format!("Invalid provider {id}"))?;
+        }
+        if !config.ipv4 && !config.ipv6 {
+            bail!("Neither IPv4 nor IPv6 is enabled");
+        }
+        if !config.lan.hosts.is_empty() && !config.ipv6 {
+            bail!("LAN hosts are only updated with IPv6 enabled");
+        }
+        if let Some(server) = &config.server {
+            server.validate().context("Invalid server")?;
+        }
+        if let Some(path) = &config.prometheus_textfile {
+            textfile::validate(path).context("Invalid prometheus_textfile")?;
+        }
+        if config.cache == cache::Mode::Memory && config.state_db.is_some() {
+            bail!("A state_db can't be used with the cache kept in memory");
+        }
+        if config.sandbox
+            && (!config.hooks.is_empty()
+                || config.sources.runs_commands()
+                || config
+                    .providers()
+                    .any(|(_, provider)| provider.runs_commands()))
+        {
+            bail!("Hooks, exec sources and GSS-TSIG run commands, which the sandbox doesn't allow");
+        }
+        let arpa = [
+            Name::from_ascii("in-addr.arpa.")?,
+            Name::from_ascii("ip6.arpa.")?,
+        ];
+        if let Some(zone) = config
+            .reverse_zones
+            .iter()
+            .find(|zone| !arpa.iter().any(|arpa| arpa.zone_of(zone)))
+        {
+            bail!(
+                "{} is not a reverse zone below in-addr.arpa or ip6.arpa",
+                zone
+            );
+        }
+        if !config.shutdown_fallback.is_empty() && !config.delete_on_shutdown {
+            bail!("The shutdown_fallback is only used with delete_on_shutdown");
+        }
+        if let Some(proxy) = &config.proxy {
+            proxy.validate()?;
+        }
+        if config.zone.is_none() {
+            // The zone may have to be discovered through the proxy already.
+            let discovery = zone::discover(
+                &config.domain,
+                &config.dns_provider_config,
+                &config.outbound,
+            );
+            let zone = proxy::scope(config.proxy.clone(), discovery)
+                .await
+                .context("Failed to discover the zone, consider setting it in the config")?;
+            tracing::info!("Discovered zone {} for {}", zone, config.domain);
+            config.zone = Some(zone);
+        }
+        if !config.zone().zone_of(&config.domain) {
+            bail!("{} is not within the zone {}", config.domain, config.zone());
+        }
+        if let Some(host) = config
+            .lan
+            .hosts
+            .iter()
+            .find(|host| !config.zone().zone_of(&host.domain))
+        {
+            bail!("{} is not within the zone {}", host.domain, config.zone());
+        }
+        let zone = config.zone().clone();
+        qualify(&mut config.aliases, &zone)?;
+        for host in &mut config.lan.hosts {
+            qualify(&mut host.aliases, &zone)?;
+        }
+        if let Some(server) = &mut config.server {
+            for user in &mut server.users {
+                qualify(&mut user.hostnames, &zone)?;
+            }
+            let own = std::iter::once(&config.domain)
+                .chain(&config.aliases)
+                .chain(
+                    config
+                        .lan
+                        .hosts
+                        .iter()
+                        .flat_map(|host| std::iter::once(&host.domain).chain(&host.aliases)),
+                )
+                .find(|name| {
+                    // The domain may be given without the final dot.
+                    let mut name = (*name).clone();
+                    name.set_fqdn(true);
+                    server.hostnames().any(|hostname| *hostname == name)
+                });
+            if let Some(name) = own {
+                bail!(
+                    "{} is updated by dyndnsd itself, so it can't be pushed",
+                    name
+                );
+            }
+        }
+        let records = std::iter::once(&config.domain)
+            .chain(config.lan.hosts.iter().map(|host| &host.domain))
+            .chain(config.server.iter().flat_map(|server| server.hostnames()));
+        for record in records {
+            if config.metadata_record {
+                metadata_name(record)?;
+            }
+            for (id, provider) in config.providers() {
+                provider
+                    .validate_record(record)
+                    .with_context(|| format!("Invalid provider {id}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make `change` to `record` through `provider`, appending the attempt to
+    /// the audit log afterwards, if one is configured.
+    pub async fn audited(
+        &self,
+        provider: &ProviderConfig,
+        record: &Name,
+        record_type: RecordType,
+        old: Option<String>,
+        new: Option<String>,
+        change: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        self.audited_batch(provider, vec![(record, record_type, old, new)], change)
+            .await
+    }
+
+    /// Make `change` to several records at once through `provider`, appending
+    /// an attempt for each of the `records` with their old and new values to
+    /// the audit log afterwards, if one is configured.
+    pub async fn audited_batch(
+        &self,
+        provider: &ProviderConfig,
+        records: Vec<(&Name, RecordType, Option<String>, Option<String>)>,
+        change: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        let Some(audit_log) = &self.audit_log else {
+            return change.await;
+        };
+        let (result, servers) = dns::with_servers(change).await;
+        for (record, record_type, old, new) in records {
+            let entry = audit::Entry {
+                provider: provider.name(),
+                credential: provider.credential_name(record),
+                record: &record.to_string(),
+                record_type: &record_type.to_string(),
+                old,
+                new,
+                servers: servers.clone(),
+                error: result.as_ref().err().map(|error| format!("{:#}", error)),
+            };
+            if let Err(error) = audit::record(audit_log, entry) {
+                tracing::error!("Failed to write audit log: {:#?}", error);
+            }
+        }
+        result
+    }
+}
+
+/// A parse error of the config, without the excerpt of the config the error
+/// usually includes, as it could contain a secret.
+fn redact(config: &str, error: toml::de::Error) -> anyhow::Error {
+    match error.span() {
+        Some(span) => {
+            let line = config[..span.start].matches('\n').count() + 1;
+            anyhow!("line {}: {}", line, error.message())
+        }
+        None => anyhow!("{}", error.message()),
+    }
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn no() -> bool {
+    false
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60)
+}
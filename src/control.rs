@@ -0,0 +1,229 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Unix socket for controlling the running daemon. Clients send one command
+//! per line, `status`, `update`, `reload` or `flush`, and get a line of JSON
+//! back for each, with `ok` telling whether the command succeeded.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{self, Permissions},
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use serde_with::{DisplayFromStr, serde_as};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+
+use crate::{
+    cache::{Cache, Published, RecordKey},
+    config::ClientConfig,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    /// Report the detected addresses and the state of the records
+    Status,
+    /// Run an update cycle right away
+    Update,
+    /// Re-read the config file
+    Reload,
+    /// Forget the published addresses, so the next cycle updates every record
+    Flush,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(command: &str) -> Result<Self> {
+        match command {
+            "status" => Ok(Command::Status),
+            "update" => Ok(Command::Update),
+            "reload" => Ok(Command::Reload),
+            "flush" => Ok(Command::Flush),
+            _ => Err(anyhow!("Unknown command {:?}", command)),
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Command::Status => "status",
+            Command::Update => "update",
+            Command::Reload => "reload",
+            Command::Flush => "flush",
+        })
+    }
+}
+
+/// How long a client waits for the daemon to answer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A command received on the socket, waiting for the daemon to handle it.
+pub struct Request {
+    pub command: Command,
+    reply: oneshot::Sender<Result<Value>>,
+}
+
+impl Request {
+    /// Answer the client. The fields of an object are sent along with `ok`.
+    pub fn reply(self, response: Result<Value>) {
+        // The client may have disconnected in the meantime.
+        let _ = self.reply.send(response);
+    }
+}
+
+/// The reply to `status`.
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct Status {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    /// Unix timestamp of the last time a record was set
+    pub last_update: Option<u64>,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    /// Error of the last failed update cycle
+    pub last_error: Option<String>,
+    /// State of each record on each provider
+    #[serde_as(as = "BTreeMap<DisplayFromStr, _>")]
+    pub records: BTreeMap<RecordKey, BTreeMap<String, Published>>,
+}
+
+impl Status {
+    pub fn new(cache: &Cache, last_error: Option<String>) -> Self {
+        Self {
+            ipv4: cache.v4,
+            ipv6: cache.v6,
+            last_update: cache.last_update,
+            failures: cache.failures,
+            consecutive_failures: cache.consecutive_failures,
+            last_error,
+            records: cache.records.clone(),
+        }
+    }
+}
+
+/// The socket to reach the daemon at, `socket` if given, or else the one set
+/// in the config file, if it can be read.
+pub fn socket(socket: Option<PathBuf>, config_path: &Path) -> Option<PathBuf> {
+    socket.or_else(|| ClientConfig::read(config_path).control_socket)
+}
+
+/// Send `command` to the daemon listening on `socket`, returning its reply.
+pub async fn send(socket: &Path, command: Command) -> Result<Value> {
+    let response = timeout(CLIENT_TIMEOUT, async {
+        let mut stream = UnixStream::connect(socket).await?;
+        stream.write_all(format!("{command}\n").as_bytes()).await?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).await?;
+        Ok::<_, std::io::Error>(line)
+    })
+    .await
+    .with_context(|| format!("{} didn't answer", socket.display()))?
+    .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    let response: Value = serde_json::from_str(&response).context("Invalid reply")?;
+    if response["ok"] != Value::Bool(true) {
+        bail!("The daemon failed to {}: {}", command, response["error"]);
+    }
+    Ok(response)
+}
+
+/// Whether sending a command failed because no daemon is listening, rather
+/// than the daemon failing to handle it.
+pub fn unreachable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<std::io::Error>().is_some_and(|error| {
+            matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            )
+        })
+    })
+}
+
+/// Listen on `path`, passing the commands received to the returned channel.
+/// Only the owner of the daemon may connect.
+pub fn listen(path: &Path) -> Result<mpsc::Receiver<Request>> {
+    // A socket left behind by an earlier run would make binding fail.
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => {
+            return Err(error).with_context(|| format!("Failed to remove {}", path.display()));
+        }
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))
+        .context("Failed to restrict access to the control socket")?;
+    let (requests, receiver) = mpsc::channel(8);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, requests.clone()));
+                }
+                Err(error) => tracing::error!("Failed to accept control connection: {}", error),
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+async fn serve(stream: UnixStream, requests: mpsc::Sender<Request>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match line.trim().parse() {
+            Ok(command) => {
+                let (reply, response) = oneshot::channel();
+                if requests.send(Request { command, reply }).await.is_err() {
+                    // The daemon is shutting down.
+                    return;
+                }
+                match response.await {
+                    Ok(response) => response,
+                    Err(_) => return,
+                }
+            }
+            Err(error) => Err(error),
+        };
+        let response = match response {
+            Ok(Value::Object(mut fields)) => {
+                fields.insert("ok".to_string(), Value::Bool(true));
+                Value::Object(fields)
+            }
+            Ok(_) => json!({"ok": true}),
+            Err(error) => json!({"ok": false, "error": format!("{error:#}")}),
+        };
+        if writer
+            .write_all(format!("{response}\n").as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
@@ -1,40 +1,326 @@
 use std::{
+    cell::RefCell,
     fmt::Display,
-    net::{AddrParseError, Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     num::ParseIntError,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
-use anyhow::Context;
+use anyhow::{Context, anyhow, bail};
+use futures_util::{TryFutureExt, future};
 use hickory_client::client::{Client, ClientHandle};
 use hickory_proto::{
-    dnssec::{rdata::tsig::TsigAlgorithm, tsig::TSigner},
-    rr::{Name, RData, Record},
-    runtime::TokioRuntimeProvider,
+    ProtoError, ProtoErrorKind,
+    dnssec::{
+        Algorithm, DnssecDnsHandle, Proof, SigSigner, TrustAnchors,
+        crypto::signing_key_from_der,
+        rdata::{KEY, tsig::TsigAlgorithm},
+        tsig::TSigner,
+    },
+    op::{
+        Edns, Message, MessageFinalizer, MessageType, OpCode, Query, ResponseCode, UpdateMessage,
+        update_message::MAX_PAYLOAD_LEN,
+    },
+    quic::QuicClientStream,
+    rr::{
+        DNSClass, Name, RData, Record, RecordType,
+        rdata::{PTR, TXT},
+    },
     tcp::TcpClientStream,
     udp::UdpClientStream,
+    xfer::{DnsHandle, DnsRequestOptions, DnsResponse, FirstAnswer},
 };
-use serde::{Deserialize, Serialize};
-use serde_with::{DisplayFromStr, base64::Base64, serde_as};
+use rustls_pki_types::{PrivateKeyDer, pem::PemObject};
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use serde_with::{DisplayFromStr, OneOrMany, formats::PreferOne, serde_as};
 use thiserror::Error;
+use tracing::Instrument;
+
+use crate::{
+    duration::HumanDuration,
+    secret::{self, Secret},
+};
+
+mod bound;
+mod doh;
+mod gss;
+mod signed;
+
+tokio::task_local! {
+    /// Servers messages were sent to while running [`with_servers`]
+    static SERVERS: RefCell<Vec<String>>;
+}
+
+/// Run `operation`, returning the servers it sent messages to along with its
+/// output, in the order they were first contacted.
+pub async fn with_servers<T>(operation: impl Future<Output = T>) -> (T, Vec<String>) {
+    SERVERS
+        .scope(RefCell::new(Vec::new()), async {
+            let output = operation.await;
+            (output, SERVERS.with(RefCell::take))
+        })
+        .await
+}
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
-    #[serde_as(as = "DisplayFromStr")]
-    url: ConnectionUrl,
+    /// Servers to send updates to, tried in order until one accepts them
+    #[serde_as(as = "OneOrMany<DisplayFromStr, PreferOne>")]
+    url: Vec<ConnectionUrl>,
+    /// Name to validate the server's certificate against for `https://` and
+    /// `quic://` URLs, instead of its IP address
+    #[serde(default)]
+    tls_server_name: Option<String>,
+    /// ALPN protocol for `quic://` URLs, defaults to `doq`
+    #[serde(default)]
+    alpn: Option<String>,
+    /// Only apply an update if the record still holds what it was last set
+    /// to, instead of overwriting changes made by someone else
+    #[serde(default)]
+    prerequisites: bool,
+    /// Local address the messages are sent from, e.g. to pick an uplink on a
+    /// multi-homed host
+    #[serde(default)]
+    bind_address: Option<IpAddr>,
+    /// Interface the messages are sent through, regardless of the routing
+    /// table
+    #[serde(default)]
+    bind_interface: Option<String>,
+    /// Only accept answers to queries that are validated with DNSSEC
+    #[serde(default)]
+    validate_dnssec: bool,
+    /// DNSKEY records the answers are validated against, instead of the keys
+    /// of the root zone
+    #[serde(default)]
+    trust_anchor: Option<PathBuf>,
+    #[serde(flatten)]
+    auth: Auth,
+}
+
+/// Replacement of the records of one type at `names` with `rdatas`, only
+/// applied if the first name holds the `expected` records, or none if it's
+/// empty.
+#[derive(Clone, Debug)]
+pub struct Replacement {
+    names: Vec<Name>,
+    rdatas: Vec<RData>,
+    expected: Option<Vec<RData>>,
+}
+
+impl Replacement {
+    pub fn new(names: Vec<Name>, rdatas: Vec<RData>, expected: Option<Vec<RData>>) -> Self {
+        Self {
+            names,
+            rdatas,
+            expected,
+        }
+    }
+
+    /// The type of the records, which all have to be of the same type.
+    fn record_type(&self) -> anyhow::Result<RecordType> {
+        let Some(record_type) = self.rdatas.first().map(RData::record_type) else {
+            bail!("No records to set");
+        };
+        if self
+            .rdatas
+            .iter()
+            .any(|rdata| rdata.record_type() != record_type)
+        {
+            bail!("Records of different types can't form one RRset");
+        }
+        if self.names.is_empty() {
+            bail!("No names to set records at");
+        }
+        Ok(record_type)
+    }
+}
+
+/// The record didn't hold what the prerequisites of an update expected.
+#[derive(Error, Debug)]
+#[error("The records were changed by someone else")]
+struct Conflict;
+
+/// The server answered with an error code.
+#[derive(Error, Debug)]
+#[error("{0}")]
+struct Rejected(ResponseCode);
+
+/// Whether `error` is the server's answer, which another server would give
+/// as well, rather than a failure of the server or the connection to it.
+fn definitive(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<Conflict>()
+            || cause
+                .downcast_ref::<Rejected>()
+                .is_some_and(|Rejected(code)| {
+                    !matches!(code, ResponseCode::ServFail | ResponseCode::Refused)
+                })
+    })
+}
+
+/// How updates are authenticated. The TSIG settings are kept at the top level
+/// of the provider config, as they were the only option initially.
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+enum Auth {
+    Tsig(Tsig),
+    Keys { keys: Vec<ScopedTsig> },
+    GssTsig { gss_tsig: gss::Config },
+    Sig0 { sig0: Sig0 },
+}
+
+/// Select the variant by the keys present instead of trying each in turn, so
+/// errors point at the offending key rather than saying no variant matched.
+impl<'de> Deserialize<'de> for Auth {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut table = toml::Table::deserialize(deserializer)?;
+        if let Some(gss_tsig) = table.remove("gss_tsig") {
+            gss_tsig
+                .try_into()
+                .map(|gss_tsig| Auth::GssTsig { gss_tsig })
+                .map_err(|error| D::Error::custom(format!("invalid gss_tsig: {error}")))
+        } else if let Some(keys) = table.remove("keys") {
+            keys.try_into()
+                .map(|keys| Auth::Keys { keys })
+                .map_err(|error| D::Error::custom(format!("invalid keys: {error}")))
+        } else if let Some(sig0) = table.remove("sig0") {
+            sig0.try_into()
+                .map(|sig0| Auth::Sig0 { sig0 })
+                .map_err(|error| D::Error::custom(format!("invalid sig0: {error}")))
+        } else {
+            toml::Value::Table(table)
+                .try_into()
+                .map(Auth::Tsig)
+                .map_err(|error| D::Error::custom(format!("invalid TSIG config: {error}")))
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Tsig {
     #[serde_as(as = "DisplayFromStr")]
     key_name: Name,
-    #[serde_as(as = "Base64")]
-    key: Vec<u8>,
+    #[serde(deserialize_with = "secret::base64")]
+    key: Secret<Vec<u8>>,
     algorithm: TsigAlgorithm,
+    /// Key updates are retried with when the server rejects this one, so the
+    /// key can be rolled over on the server without downtime
+    #[serde(default)]
+    secondary: Option<Box<Tsig>>,
+    /// How far the clocks of dyndnsd and the server may be apart for a
+    /// signature to be accepted
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_fudge")]
+    fudge: Duration,
+}
+
+fn default_fudge() -> Duration {
+    Duration::from_secs(60)
+}
+
+impl Tsig {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.fudge.as_secs() > u64::from(u16::MAX) {
+            bail!(
+                "The fudge of the TSIG key {} is longer than {}s",
+                self.key_name,
+                u16::MAX
+            );
+        }
+        self.signer()?;
+        if let Some(secondary) = &self.secondary {
+            if secondary.secondary.is_some() {
+                bail!(
+                    "The secondary TSIG key {} can't have a secondary key",
+                    secondary.key_name
+                );
+            }
+            secondary.signer()?;
+        }
+        Ok(())
+    }
+
+    fn signer(&self) -> anyhow::Result<TSigner> {
+        if self.key.is_empty() {
+            bail!("The TSIG key {} is empty", self.key_name);
+        }
+        TSigner::new(
+            self.key.to_vec(),
+            self.algorithm.clone(),
+            self.key_name.clone(),
+            self.fudge.as_secs() as u16,
+        )
+        .with_context(|| format!("Unsupported TSIG algorithm {}", self.algorithm))
+    }
+}
+
+/// One of several TSIG keys, used for the records it's scoped to. A key
+/// without any scope is used for all other records.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ScopedTsig {
+    #[serde(flatten)]
+    tsig: Tsig,
+    /// Zones whose records are updated with this key
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    zones: Vec<Name>,
+    /// Records updated with this key, taking precedence over `zones`
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    records: Vec<Name>,
+}
+
+impl ScopedTsig {
+    fn is_default(&self) -> bool {
+        self.zones.is_empty() && self.records.is_empty()
+    }
+}
+
+/// SIG(0) signing with a private key, whose public half is published as a
+/// KEY record the server's update policy refers to.
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Sig0 {
+    #[serde_as(as = "DisplayFromStr")]
+    key_name: Name,
+    /// PEM encoded private key file
+    private_key: PathBuf,
+    algorithm: Algorithm,
+}
+
+impl Sig0 {
+    fn signer(&self) -> anyhow::Result<SigSigner> {
+        let der = PrivateKeyDer::from_pem_file(&self.private_key).with_context(|| {
+            format!(
+                "Failed to read SIG(0) private key from {}",
+                self.private_key.display()
+            )
+        })?;
+        let key = signing_key_from_der(&der, self.algorithm)
+            .context("Failed to load SIG(0) private key")?;
+        let public_key = key
+            .to_public_key()
+            .context("Failed to derive SIG(0) public key")?;
+        Ok(SigSigner::sig0(
+            KEY::new_sig0key(&public_key),
+            key,
+            self.key_name.clone(),
+        ))
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum ConnectionScheme {
     Tcp,
     Udp,
+    Https { path: String },
+    Quic,
 }
 
 #[derive(Clone, Debug)]
@@ -45,9 +331,11 @@ pub struct ConnectionUrl {
 
 impl Display for ConnectionUrl {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.scheme {
+        match &self.scheme {
             ConnectionScheme::Tcp => f.write_str("tcp://")?,
             ConnectionScheme::Udp => f.write_str("udp://")?,
+            ConnectionScheme::Https { .. } => f.write_str("https://")?,
+            ConnectionScheme::Quic => f.write_str("quic://")?,
         };
         if self.address.is_ipv6() {
             f.write_str("[")?
@@ -58,19 +346,41 @@ impl Display for ConnectionUrl {
         };
         f.write_str(":")?;
         f.write_str(&self.address.port().to_string())?;
+        if let ConnectionScheme::Https { path } = &self.scheme {
+            f.write_str(path)?;
+        }
         Ok(())
     }
 }
+impl ConnectionUrl {
+    /// The same server over TCP.
+    fn tcp(&self) -> Self {
+        Self {
+            scheme: ConnectionScheme::Tcp,
+            address: self.address,
+        }
+    }
+}
+
 impl FromStr for ConnectionUrl {
     type Err = ConnectionUrlError;
 
     fn from_str(url: &str) -> Result<Self, Self::Err> {
-        let (host, is_tcp) = if let Some(host) = url.strip_prefix("udp://") {
-            (host, false)
+        let (host, scheme, default_port) = if let Some(host) = url.strip_prefix("udp://") {
+            (host, ConnectionScheme::Udp, "53")
         } else if let Some(host) = url.strip_prefix("tcp://") {
-            (host, true)
+            (host, ConnectionScheme::Tcp, "53")
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            let (host, path) = match rest.find('/') {
+                Some(index) => rest.split_at(index),
+                None => (rest, "/dns-query"),
+            };
+            let path = path.to_string();
+            (host, ConnectionScheme::Https { path }, "443")
+        } else if let Some(host) = url.strip_prefix("quic://") {
+            (host, ConnectionScheme::Quic, "853")
         } else {
-            (url, false)
+            (url, ConnectionScheme::Udp, "53")
         };
         let (host, port) = if let Some(host) = host.strip_prefix('[') {
             let (host, maybe_port) = host
@@ -82,27 +392,17 @@ impl FromStr for ConnectionUrl {
                 maybe_port
                     .rsplit_once(':')
                     .map(|(_, port)| port)
-                    .unwrap_or("53"),
+                    .unwrap_or(default_port),
             )
         } else if let Some((host, port)) = host.rsplit_once(':') {
             (host, port)
         } else {
-            (host, "53")
+            (host, default_port)
         };
 
         let address = SocketAddr::new(host.parse()?, port.parse()?);
 
-        if is_tcp {
-            Ok(ConnectionUrl {
-                scheme: ConnectionScheme::Tcp,
-                address,
-            })
-        } else {
-            Ok(ConnectionUrl {
-                scheme: ConnectionScheme::Udp,
-                address,
-            })
-        }
+        Ok(ConnectionUrl { scheme, address })
     }
 }
 
@@ -119,31 +419,298 @@ pub enum ConnectionUrlError {
 }
 
 impl Config {
-    async fn client(&self) -> anyhow::Result<Client> {
-        let signer = TSigner::new(
-            self.key.clone(),
-            self.algorithm.clone(),
-            self.key_name.clone(),
-            60,
-        )?;
-        let client = match self.url.scheme {
+    /// Non-secret identifier of the credential used for `record`.
+    pub fn credential_name(&self, record: &Name) -> Option<String> {
+        match &self.auth {
+            Auth::Tsig(tsig) => Some(tsig.key_name.to_string()),
+            Auth::Keys { .. } => self.tsig(record).ok().map(|tsig| tsig.key_name.to_string()),
+            Auth::GssTsig { gss_tsig } => Some(gss_tsig.credential_name()),
+            Auth::Sig0 { sig0 } => Some(sig0.key_name.to_string()),
+        }
+    }
+
+    /// The TSIG key `record` is updated with: the key listing the record,
+    /// else the key of the closest zone containing it, else the default key.
+    fn tsig(&self, record: &Name) -> anyhow::Result<&Tsig> {
+        let keys = match &self.auth {
+            Auth::Tsig(tsig) => return Ok(tsig),
+            Auth::Keys { keys } => keys,
+            _ => bail!("Updates aren't signed with TSIG"),
+        };
+        keys.iter()
+            .find(|key| key.records.iter().any(|name| name.eq_ignore_root(record)))
+            .or_else(|| {
+                keys.iter()
+                    .filter_map(|key| {
+                        key.zones
+                            .iter()
+                            .filter(|zone| zone.zone_of(record))
+                            .map(|zone| (zone.num_labels(), key))
+                            .max_by_key(|(labels, _)| *labels)
+                    })
+                    .max_by_key(|(labels, _)| *labels)
+                    .map(|(_, key)| key)
+            })
+            .or_else(|| keys.iter().find(|key| key.is_default()))
+            .map(|key| &key.tsig)
+            .ok_or_else(|| anyhow!("No TSIG key is configured for {record}"))
+    }
+
+    /// Check that `record` can be updated with one of the configured keys.
+    pub fn validate_record(&self, record: &Name) -> anyhow::Result<()> {
+        match &self.auth {
+            Auth::Keys { .. } => self.tsig(record).map(drop),
+            _ => Ok(()),
+        }
+    }
+
+    /// Files holding the key, if it isn't part of the config.
+    pub fn secret_files(&self) -> Vec<&Path> {
+        match &self.auth {
+            Auth::Tsig(_) | Auth::Keys { .. } => Vec::new(),
+            Auth::Sig0 { sig0 } => vec![&sig0.private_key],
+            Auth::GssTsig { gss_tsig } => gss_tsig.secret_files(),
+        }
+    }
+
+    /// File with the DNSKEY records queries are validated against.
+    pub fn trust_anchor(&self) -> Option<&Path> {
+        self.trust_anchor.as_deref()
+    }
+
+    /// The keys answers are validated against, if DNSSEC validation is
+    /// enabled.
+    fn trust_anchors(&self) -> anyhow::Result<Option<Arc<TrustAnchors>>> {
+        if !self.validate_dnssec {
+            return Ok(None);
+        }
+        let anchors = match &self.trust_anchor {
+            Some(path) => TrustAnchors::from_file(path)
+                .with_context(|| format!("Failed to read the trust anchor {}", path.display()))?,
+            None => TrustAnchors::default(),
+        };
+        Ok(Some(Arc::new(anchors)))
+    }
+
+    /// Whether updates only apply to records still holding what they were
+    /// last set to.
+    pub fn prerequisites(&self) -> bool {
+        self.prerequisites
+    }
+
+    /// Whether the updates are sent with nsupdate, for GSS-TSIG.
+    pub fn uses_nsupdate(&self) -> bool {
+        matches!(self.auth, Auth::GssTsig { .. })
+    }
+
+    /// Check that the key decodes into a signer for a supported algorithm.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match &self.auth {
+            Auth::Tsig(tsig) => tsig.validate()?,
+            Auth::Keys { keys } => {
+                if keys.is_empty() {
+                    bail!("No TSIG keys are configured");
+                }
+                if keys.iter().filter(|key| key.is_default()).count() > 1 {
+                    bail!("Only one TSIG key can be used for all records without zones or records");
+                }
+                for key in keys {
+                    key.tsig.validate()?;
+                }
+            }
+            Auth::Sig0 { sig0 } => {
+                sig0.signer()?;
+            }
+            Auth::GssTsig { gss_tsig } => gss_tsig.validate(&self.url)?,
+        }
+        if self.trust_anchor.is_some() && !self.validate_dnssec {
+            bail!("The trust_anchor is only used with validate_dnssec");
+        }
+        if let Some(anchors) = self.trust_anchors()?
+            && anchors.is_empty()
+        {
+            bail!("The trust anchor doesn't contain any DNSKEY records");
+        }
+        if self.prerequisites && self.uses_nsupdate() {
+            bail!("Prerequisites aren't supported with GSS-TSIG");
+        }
+        if (self.bind_address.is_some() || self.bind_interface.is_some()) && self.uses_nsupdate() {
+            bail!("A bind address or interface isn't supported with GSS-TSIG");
+        }
+        if let Some(bind_address) = self.bind_address
+            && let Some(url) = self
+                .url
+                .iter()
+                .find(|url| url.address.is_ipv4() != bind_address.is_ipv4())
+        {
+            bail!("{} can't be reached from {}", url, bind_address);
+        }
+        if self.bind_interface.is_some()
+            && let Some(url) = self
+                .url
+                .iter()
+                .find(|url| matches!(url.scheme, ConnectionScheme::Quic))
+        {
+            bail!("A bind interface isn't supported with {}", url);
+        }
+        Ok(())
+    }
+
+    /// The keys updates of `record` are signed with, in the order they're
+    /// tried: the TSIG key and its secondary key, or the SIG(0) key.
+    fn signers(&self, record: &Name) -> anyhow::Result<Vec<(&Name, Arc<dyn MessageFinalizer>)>> {
+        match &self.auth {
+            Auth::Tsig(_) | Auth::Keys { .. } => {
+                let tsig = self.tsig(record)?;
+                std::iter::once(tsig)
+                    .chain(tsig.secondary.as_deref())
+                    .map(|tsig| {
+                        let signer: Arc<dyn MessageFinalizer> =
+                            Arc::new(signed::Tsig(tsig.signer()?));
+                        Ok((&tsig.key_name, signer))
+                    })
+                    .collect()
+            }
+            Auth::Sig0 { sig0 } => Ok(vec![(&sig0.key_name, Arc::new(sig0.signer()?))]),
+            Auth::GssTsig { .. } => bail!("GSS-TSIG updates are sent with nsupdate"),
+        }
+    }
+
+    /// Send the update of `record` made by `update` to `url`, retrying with
+    /// the secondary TSIG key if the server rejects the key or its signature.
+    async fn send_signed<F, Fut>(
+        &self,
+        url: &ConnectionUrl,
+        record: &Name,
+        update: F,
+    ) -> anyhow::Result<DnsResponse>
+    where
+        F: Fn(&mut Client) -> Fut,
+        Fut: Future<Output = Result<DnsResponse, ProtoError>>,
+    {
+        let mut rejected = None;
+        for (key_name, signer) in self.signers(record)? {
+            if let Some(rejected) = rejected {
+                tracing::info!(
+                    "The server rejected the key {}, retrying with the secondary key {}",
+                    rejected,
+                    key_name
+                );
+            }
+            let response = self.exchange(url, Some(signer), &update).await?;
+            if let Some(server_time) = signed::time_rejected(&response) {
+                let skew = crate::now() as i64 - server_time as i64;
+                bail!(
+                    "The server rejected the signature of the key {}, as the clock is {}s {} \
+                     the server's. Synchronize the clock, e.g. with NTP, or raise the fudge",
+                    key_name,
+                    skew.unsigned_abs(),
+                    if skew > 0 { "ahead of" } else { "behind" }
+                );
+            }
+            if !signed::key_rejected(&response) {
+                match rejected {
+                    Some(rejected) => tracing::warn!(
+                        "The server rejected the key {}, but accepted the secondary key {}",
+                        rejected,
+                        key_name
+                    ),
+                    None => tracing::debug!("The server accepted the key {}", key_name),
+                }
+                return Ok(response);
+            }
+            rejected = Some(key_name);
+        }
+        match rejected {
+            Some(key_name) => bail!("The server rejected the key {}", key_name),
+            None => bail!("No key to sign the update with"),
+        }
+    }
+
+    /// Send the message made by `send` to `url`, signed with `signer`. If a
+    /// UDP server truncated the response, or the message doesn't fit into a
+    /// datagram, it's sent again over TCP.
+    async fn exchange<F, Fut>(
+        &self,
+        url: &ConnectionUrl,
+        signer: Option<Arc<dyn MessageFinalizer>>,
+        send: F,
+    ) -> anyhow::Result<DnsResponse>
+    where
+        F: Fn(&mut Client) -> Fut,
+        Fut: Future<Output = Result<DnsResponse, ProtoError>>,
+    {
+        let mut client = self.client(url, signer.clone()).await?;
+        let response = send(&mut client).await;
+        if !matches!(url.scheme, ConnectionScheme::Udp) || !needs_tcp(&response) {
+            return Ok(response?);
+        }
+        tracing::debug!("{} needs TCP for this message, retrying over TCP", url);
+        let mut client = self.client(&url.tcp(), signer).await?;
+        Ok(send(&mut client).await?)
+    }
+
+    /// A client signing its messages with `signer`, or an unsigned one for
+    /// queries.
+    ///
+    /// Every operation connects anew instead of keeping a client around, so a
+    /// broken connection or background task never outlives the operation it
+    /// failed. A failed operation is only retried if the outbound call policy
+    /// sets `retries`, which it doesn't by default.
+    async fn client(
+        &self,
+        url: &ConnectionUrl,
+        signer: Option<Arc<dyn MessageFinalizer>>,
+    ) -> anyhow::Result<Client> {
+        let runtime = bound::BoundRuntime::new(self.bind_interface.as_deref());
+        let bind_addr = self.bind_address.map(|addr| SocketAddr::new(addr, 0));
+        let client = match &url.scheme {
             ConnectionScheme::Udp => {
-                let conn =
-                    UdpClientStream::builder(self.url.address, TokioRuntimeProvider::default())
-                        .with_signer(Some(Arc::new(signer)))
-                        .build();
+                let conn = UdpClientStream::builder(url.address, runtime)
+                    .with_bind_addr(bind_addr)
+                    .with_signer(signer)
+                    .build();
                 let (client, bg) = Client::connect(conn).await?;
                 tokio::spawn(bg);
                 client
             }
             ConnectionScheme::Tcp => {
-                let (stream, sender) = TcpClientStream::new(
-                    self.url.address,
-                    None,
-                    None,
-                    TokioRuntimeProvider::default(),
-                );
-                let (client, bg) = Client::new(stream, sender, Some(Arc::new(signer))).await?;
+                let (stream, sender) = TcpClientStream::new(url.address, bind_addr, None, runtime);
+                let (client, bg) = Client::new(stream, sender, signer).await?;
+                tokio::spawn(bg);
+                client
+            }
+            ConnectionScheme::Https { path } => {
+                let stream = doh::HttpsClientStream::new(
+                    url.address,
+                    path,
+                    self.tls_server_name.as_deref(),
+                    self.bind_address,
+                    self.bind_interface.as_deref(),
+                )?;
+                let stream = signed::SignedStream::new(stream, signer);
+                let (client, bg) = Client::connect(future::ready(Ok(stream))).await?;
+                tokio::spawn(bg);
+                client
+            }
+            ConnectionScheme::Quic => {
+                let mut crypto_config = hickory_proto::rustls::client_config();
+                if let Some(alpn) = &self.alpn {
+                    crypto_config.alpn_protocols = vec![alpn.as_bytes().to_vec()];
+                }
+                let server_name = match &self.tls_server_name {
+                    Some(server_name) => server_name.clone(),
+                    None => url.address.ip().to_string(),
+                };
+                let mut builder = QuicClientStream::builder();
+                builder.crypto_config(crypto_config);
+                if let Some(bind_addr) = bind_addr {
+                    builder.bind_addr(bind_addr);
+                }
+                let connect = builder
+                    .build(url.address, server_name)
+                    .map_ok(|stream| signed::SignedStream::new(stream, signer));
+                let (client, bg) = Client::connect(connect).await?;
                 tokio::spawn(bg);
                 client
             }
@@ -151,32 +718,484 @@ impl Config {
         Ok(client)
     }
 
-    async fn replace(&self, rdata: RData, name: Name, origin: Name) -> anyhow::Result<()> {
-        self.client()
-            .await?
-            .delete_rrset(
-                Record::update0(name.clone(), 0, rdata.record_type()),
-                origin.clone(),
+    /// Run `operation` against the configured servers in order, moving on to
+    /// the next one if a server can't be reached, fails or refuses it. Other
+    /// answers, like a conflict with the prerequisites, are returned right
+    /// away. Within [`with_servers`], the servers are recorded.
+    async fn with_fallback<'a, T, F, Fut>(&'a self, mut operation: F) -> anyhow::Result<T>
+    where
+        F: FnMut(&'a ConnectionUrl) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut last_error = None;
+        for (index, url) in self.url.iter().enumerate() {
+            // Outside of `with_servers`, there's nothing to record them in.
+            let _ = SERVERS.try_with(|servers| {
+                let mut servers = servers.borrow_mut();
+                let url = url.to_string();
+                if !servers.contains(&url) {
+                    servers.push(url);
+                }
+            });
+            match operation(url)
+                .instrument(tracing::info_span!("dns", server = %url))
+                .await
+            {
+                Ok(result) => return Ok(result),
+                Err(error) if definitive(&error) => return Err(error),
+                Err(error) if index + 1 < self.url.len() => {
+                    tracing::warn!("{} failed, trying the next server: {:#}", url, error);
+                    last_error = Some(error);
+                }
+                Err(error) => last_error = Some(error.context(format!("{url} failed"))),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No DNS server configured")))
+    }
+
+    /// Apply `replacement`. If its first RRset doesn't hold the `expected`
+    /// records, it's queried again and replaced if it still holds what was
+    /// just queried, so two updaters fighting over a name show up as
+    /// conflicts in the log.
+    async fn replace(&self, mut replacement: Replacement, origin: Name) -> anyhow::Result<()> {
+        let record_type = replacement.record_type()?;
+        let result = self
+            .with_fallback(|url| self.replace_at(url, std::slice::from_ref(&replacement), &origin))
+            .await;
+        match result {
+            Err(error) if error.downcast_ref::<Conflict>().is_some() => {
+                let name = &replacement.names[0];
+                let current = self.get_records(record_type, name.clone()).await?;
+                tracing::warn!(
+                    "{} {} was changed by someone else to {}, setting it again",
+                    name,
+                    record_type,
+                    match current.is_empty() {
+                        true => "nothing".to_string(),
+                        false => current
+                            .iter()
+                            .map(RData::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    }
+                );
+                replacement.expected = Some(current);
+                self.with_fallback(|url| {
+                    self.replace_at(url, std::slice::from_ref(&replacement), &origin)
+                })
+                .await
+            }
+            result => result,
+        }
+    }
+
+    /// Delete the old RRsets and add the new records of all `replacements`
+    /// in a single UPDATE message, so the server applies all or nothing. It's
+    /// signed with the key of the first name, and only applied if the first
+    /// RRset of each replacement holds its `expected` records.
+    async fn replace_at(
+        &self,
+        url: &ConnectionUrl,
+        replacements: &[Replacement],
+        origin: &Name,
+    ) -> anyhow::Result<()> {
+        let Some(first) = replacements
+            .first()
+            .and_then(|replacement| replacement.names.first())
+        else {
+            bail!("No records to set");
+        };
+        if let Auth::GssTsig { gss_tsig } = &self.auth {
+            let mut commands = Vec::new();
+            for replacement in replacements {
+                let record_type = replacement.record_type()?;
+                for name in &replacement.names {
+                    commands.push(format!("update delete {name} {record_type}"));
+                    commands.extend(
+                        replacement
+                            .rdatas
+                            .iter()
+                            .map(|rdata| format!("update add {name} 60 {record_type} {rdata}")),
+                    );
+                }
+            }
+            return gss_tsig.update(url, origin, &commands).await;
+        }
+        let mut zone = Query::new();
+        zone.set_name(origin.clone())
+            .set_query_class(DNSClass::IN)
+            .set_query_type(RecordType::SOA);
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update)
+            .set_recursion_desired(false);
+        message.add_zone(zone);
+        for replacement in replacements {
+            let record_type = replacement.record_type()?;
+            let name = &replacement.names[0];
+            match replacement.expected.as_deref() {
+                None => {}
+                // RFC 2136, 2.4.3: RRset does not exist
+                Some([]) => {
+                    let mut prerequisite = Record::update0(name.clone(), 0, record_type);
+                    prerequisite.set_dns_class(DNSClass::NONE);
+                    message.add_pre_requisite(prerequisite);
+                }
+                // RFC 2136, 2.4.2: RRset exists (value dependent)
+                Some(expected) => {
+                    for rdata in expected {
+                        message.add_pre_requisite(Record::from_rdata(
+                            name.clone(),
+                            0,
+                            rdata.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+        for replacement in replacements {
+            let record_type = replacement.record_type()?;
+            for name in &replacement.names {
+                let mut delete = Record::update0(name.clone(), 0, record_type);
+                delete.set_dns_class(DNSClass::ANY);
+                message.add_update(delete);
+                for rdata in &replacement.rdatas {
+                    message.add_update(Record::from_rdata(name.clone(), 60, rdata.clone()));
+                }
+            }
+        }
+        message
+            .extensions_mut()
+            .get_or_insert_with(Edns::new)
+            .set_max_payload(MAX_PAYLOAD_LEN)
+            .set_version(0);
+        // Servers only have to take datagrams up to the size they advertise
+        // themselves, so larger UPDATEs go over TCP right away.
+        let tcp;
+        let mut url = url;
+        if matches!(url.scheme, ConnectionScheme::Udp)
+            && message.to_vec()?.len() > usize::from(MAX_PAYLOAD_LEN)
+        {
+            tracing::debug!("The UPDATE is too large for UDP, sending it over TCP");
+            tcp = url.tcp();
+            url = &tcp;
+        }
+        let response = self
+            .send_signed(url, first, |client| {
+                client.send(message.clone()).first_answer()
+            })
+            .await
+            .context("Failed to replace records")?;
+        if replacements
+            .iter()
+            .any(|replacement| replacement.expected.is_some())
+            && matches!(
+                response.response_code(),
+                ResponseCode::NXRRSet | ResponseCode::YXRRSet | ResponseCode::NXDomain
             )
+        {
+            return Err(Conflict.into());
+        }
+        accepted(response).context("Failed to replace records")
+    }
+
+    /// Check whether the server allows updating `record_type` records at
+    /// `name`, without changing anything.
+    ///
+    /// A pure prerequisite UPDATE would not exercise the server's update
+    /// policy, so this sends an UPDATE deleting the `0.0.0.0`/`::` record,
+    /// which never exists. The server still checks its policy for the name
+    /// and type and refuses the update if the key isn't allowed to touch it.
+    pub async fn check_update_permission(
+        &self,
+        record_type: RecordType,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let sentinel = match record_type {
+            RecordType::A => RData::A(Ipv4Addr::UNSPECIFIED.into()),
+            RecordType::AAAA => RData::AAAA(Ipv6Addr::UNSPECIFIED.into()),
+            RecordType::TXT => RData::TXT(TXT::new(vec!["dyndnsd-permission-check".to_string()])),
+            RecordType::PTR => RData::PTR(PTR(Name::root())),
+            other => bail!("Unsupported record type {}", other),
+        };
+        self.with_fallback(|url| async {
+            if let Auth::GssTsig { gss_tsig } = &self.auth {
+                return gss_tsig
+                    .update(
+                        url,
+                        &origin,
+                        &[format!("update delete {name} {record_type} {sentinel}")],
+                    )
+                    .await
+                    .context("The server rejected the update");
+            }
+            let response = self
+                .send_signed(url, &name, |client| {
+                    client.delete_by_rdata(
+                        Record::from_rdata(name.clone(), 0, sentinel.clone()),
+                        origin.clone(),
+                    )
+                })
+                .await
+                .context("Failed to send no-op update")?;
+            accepted(response)
+        })
+        .await
+    }
+
+    /// Check that the clock is close enough to the server's for it to accept
+    /// TSIG signatures, with an UPDATE of the zone `origin` that doesn't
+    /// change anything, signed with the key of `record`. The other
+    /// authentication methods don't need checking.
+    pub async fn check_clock(&self, record: &Name, origin: Name) -> anyhow::Result<()> {
+        if !matches!(self.auth, Auth::Tsig(_) | Auth::Keys { .. }) {
+            return Ok(());
+        }
+        let mut zone = Query::new();
+        zone.set_name(origin)
+            .set_query_class(DNSClass::IN)
+            .set_query_type(RecordType::SOA);
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update)
+            .set_recursion_desired(false);
+        message.add_zone(zone);
+        let message = &message;
+        self.with_fallback(|url| async move {
+            let response = self
+                .send_signed(url, record, |client| {
+                    client.send(message.clone()).first_answer()
+                })
+                .await?;
+            if let Some(server_time) = signed::signed_at(&response) {
+                tracing::debug!(
+                    "The clock is {}s off from {}",
+                    (crate::now() as i64 - server_time as i64).abs(),
+                    url
+                );
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Query the records of the given name and type from the configured servers.
+    pub async fn get_records(
+        &self,
+        record_type: RecordType,
+        name: Name,
+    ) -> anyhow::Result<Vec<RData>> {
+        let anchors = self.trust_anchors()?;
+        self.with_fallback(|url| async {
+            let response = match &anchors {
+                None => {
+                    self.exchange(url, None, |client| {
+                        client.query(name.clone(), DNSClass::IN, record_type)
+                    })
+                    .await
+                }
+                Some(anchors) => {
+                    self.exchange(url, None, |client| {
+                        DnssecDnsHandle::with_trust_anchor(client.clone(), anchors.clone())
+                            .lookup(
+                                Query::query(name.clone(), record_type),
+                                DnsRequestOptions::default(),
+                            )
+                            .first_answer()
+                    })
+                    .await
+                }
+            }
+            .context("Failed to query records")?;
+            if anchors.is_some() {
+                validated(&response, &name, record_type)?;
+            }
+            match response.response_code() {
+                ResponseCode::NoError | ResponseCode::NXDomain => Ok(response
+                    .answers()
+                    .iter()
+                    .filter(|record| record.record_type() == record_type)
+                    .map(|record| record.data().clone())
+                    .collect()),
+                code => {
+                    Err(anyhow::Error::new(Rejected(code)).context("The server refused the query"))
+                }
+            }
+        })
+        .await
+    }
+
+    /// Replace all records of the given name and type with `rdata`.
+    pub async fn set_record(&self, rdata: RData, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.set_records(vec![rdata], name, origin).await
+    }
+
+    /// Replace all records of the given name and type with `rdatas`.
+    pub async fn set_records(
+        &self,
+        rdatas: Vec<RData>,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        self.replace(Replacement::new(vec![name], rdatas, None), origin)
             .await
-            .context("Failed to delete old record")?;
-        self.client()
-            .await?
-            .create(Record::from_rdata(name, 60, rdata), origin)
+    }
+
+    /// Replace all records of the given type at each of `names` with
+    /// `rdatas`, in a single UPDATE. With `prerequisites` enabled and
+    /// `expected` given, the first name has to hold those records.
+    pub async fn set_records_at(
+        &self,
+        rdatas: Vec<RData>,
+        names: &[Name],
+        origin: Name,
+        expected: Option<&[RData]>,
+    ) -> anyhow::Result<()> {
+        let expected = expected.filter(|_| self.prerequisites).map(<[_]>::to_vec);
+        self.replace(Replacement::new(names.to_vec(), rdatas, expected), origin)
             .await
-            .context("Failed to set new record")?;
-        Ok(())
+    }
+
+    /// Whether updates of all `records` are signed with the same key, so
+    /// they can be sent in one UPDATE with [`Self::set_batch`].
+    pub fn can_batch(&self, records: &[&Name]) -> bool {
+        match &self.auth {
+            Auth::Keys { .. } => {
+                let keys: Vec<_> = records
+                    .iter()
+                    .map(|record| self.tsig(record).map(|tsig| &tsig.key_name).ok())
+                    .collect();
+                keys.windows(2).all(|pair| pair[0] == pair[1])
+            }
+            _ => true,
+        }
+    }
+
+    /// Apply all `replacements` in a single UPDATE. If the prerequisites of
+    /// one of them fail, they're applied one by one instead, resolving the
+    /// conflicts of each on its own.
+    pub async fn set_batch(
+        &self,
+        mut replacements: Vec<Replacement>,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        if !self.prerequisites {
+            for replacement in &mut replacements {
+                replacement.expected = None;
+            }
+        }
+        let result = self
+            .with_fallback(|url| self.replace_at(url, &replacements, &origin))
+            .await;
+        match result {
+            Err(error) if error.downcast_ref::<Conflict>().is_some() => {
+                tracing::warn!("Records were changed by someone else, setting them one by one");
+                let mut result = Ok(());
+                for replacement in replacements {
+                    let replaced = self.replace(replacement, origin.clone()).await;
+                    result = result.and(replaced);
+                }
+                result
+            }
+            result => result,
+        }
+    }
+
+    /// Delete all records of the given name and type.
+    pub async fn delete_records(
+        &self,
+        record_type: RecordType,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        self.with_fallback(|url| async {
+            if let Auth::GssTsig { gss_tsig } = &self.auth {
+                return gss_tsig
+                    .update(
+                        url,
+                        &origin,
+                        &[format!("update delete {name} {record_type}")],
+                    )
+                    .await;
+            }
+            let response = self
+                .send_signed(url, &name, |client| {
+                    client.delete_rrset(
+                        Record::update0(name.clone(), 0, record_type),
+                        origin.clone(),
+                    )
+                })
+                .await
+                .context("Failed to delete records")?;
+            accepted(response)
+        })
+        .await
     }
 
     pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
-        self.replace(RData::A(addr.into()), name, origin)
+        self.set_record(RData::A(addr.into()), name, origin)
             .await
             .context("Failed to replace A record")
     }
 
     pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
-        self.replace(RData::AAAA(addr.into()), name, origin)
+        self.set_record(RData::AAAA(addr.into()), name, origin)
             .await
             .context("Failed to replace AAAA record")
     }
 }
+
+/// Whether the message has to be sent over TCP, as the response was
+/// truncated or the message is too large for a UDP datagram.
+fn needs_tcp(response: &Result<DnsResponse, ProtoError>) -> bool {
+    match response {
+        Ok(response) => response.truncated(),
+        Err(error) => matches!(
+            error.kind(),
+            ProtoErrorKind::Io(error) if error.raw_os_error() == Some(nix::libc::EMSGSIZE)
+        ),
+    }
+}
+
+/// Fail unless the answer for `name` and `record_type`, or the proof that
+/// there is none, was validated with DNSSEC.
+fn validated(response: &DnsResponse, name: &Name, record_type: RecordType) -> anyhow::Result<()> {
+    let answers: Vec<_> = response
+        .answers()
+        .iter()
+        .filter(|record| record.record_type() == record_type)
+        .collect();
+    let records = match answers.is_empty() {
+        true => response.name_servers().iter().collect(),
+        false => answers,
+    };
+    match records
+        .iter()
+        .find(|record| record.proof() != Proof::Secure)
+    {
+        Some(record) => bail!(
+            "The answer for {} {} isn't validated with DNSSEC: {} is {}",
+            name,
+            record_type,
+            record.name(),
+            record.proof()
+        ),
+        None if records.is_empty() => bail!(
+            "The answer for {} {} isn't validated with DNSSEC: it's empty",
+            name,
+            record_type
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Fail unless the server accepted the update.
+fn accepted(response: DnsResponse) -> anyhow::Result<()> {
+    match response.response_code() {
+        ResponseCode::NoError => Ok(()),
+        code => Err(anyhow::Error::new(Rejected(code)).context("The server rejected the update")),
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Tokio runtime for the hickory client, optionally binding its sockets to a
+//! network interface, so messages leave through it regardless of the routing
+//! table.
+
+use std::{io, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use hickory_proto::runtime::{
+    RuntimeProvider, TokioHandle, TokioTime, iocompat::AsyncIoTokioAsStd,
+};
+use tokio::{
+    net::{TcpSocket, TcpStream, UdpSocket},
+    time::timeout,
+};
+
+/// How long establishing a TCP connection may take, unless the client sets
+/// a timeout, the same as hickory's own default.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The Tokio runtime, binding the sockets to `interface` if it's set.
+#[derive(Clone)]
+pub struct BoundRuntime {
+    handle: TokioHandle,
+    interface: Option<Arc<str>>,
+}
+
+impl BoundRuntime {
+    pub fn new(interface: Option<&str>) -> Self {
+        Self {
+            handle: TokioHandle::default(),
+            interface: interface.map(Arc::from),
+        }
+    }
+}
+
+impl RuntimeProvider for BoundRuntime {
+    type Handle = TokioHandle;
+    type Timer = TokioTime;
+    type Udp = UdpSocket;
+    type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+    fn create_handle(&self) -> Self::Handle {
+        self.handle.clone()
+    }
+
+    fn connect_tcp(
+        &self,
+        server_addr: SocketAddr,
+        bind_addr: Option<SocketAddr>,
+        wait_for: Option<Duration>,
+    ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Tcp>>>> {
+        let interface = self.interface.clone();
+        Box::pin(async move {
+            let socket = match server_addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4(),
+                SocketAddr::V6(_) => TcpSocket::new_v6(),
+            }?;
+            if let Some(interface) = interface {
+                socket.bind_device(Some(interface.as_bytes()))?;
+            }
+            if let Some(bind_addr) = bind_addr {
+                socket.bind(bind_addr)?;
+            }
+            socket.set_nodelay(true)?;
+            let wait_for = wait_for.unwrap_or(CONNECT_TIMEOUT);
+            match timeout(wait_for, socket.connect(server_addr)).await {
+                Ok(stream) => Ok(AsyncIoTokioAsStd(stream?)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connecting to {server_addr} timed out after {wait_for:?}"),
+                )),
+            }
+        })
+    }
+
+    fn bind_udp(
+        &self,
+        local_addr: SocketAddr,
+        _server_addr: SocketAddr,
+    ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Udp>>>> {
+        let interface = self.interface.clone();
+        Box::pin(async move {
+            let socket = UdpSocket::bind(local_addr).await?;
+            if let Some(interface) = interface {
+                socket.bind_device(Some(interface.as_bytes()))?;
+            }
+            Ok(socket)
+        })
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! DNS-over-HTTPS (RFC 8484) transport for the hickory client, sending each
+//! message as a POST request.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::Stream;
+use hickory_proto::{
+    ProtoError,
+    xfer::{DnsRequest, DnsRequestSender, DnsResponse, DnsResponseStream},
+};
+
+use crate::proxy;
+
+const CONTENT_TYPE: &str = "application/dns-message";
+
+pub struct HttpsClientStream {
+    client: reqwest::Client,
+    url: String,
+    is_shutdown: bool,
+}
+
+impl HttpsClientStream {
+    /// Connect to `address`, using `server_name` for the Host header and
+    /// certificate validation if given, and the IP address otherwise. The
+    /// connection is made from `local_address` and through `interface`, if
+    /// they're given.
+    pub fn new(
+        address: SocketAddr,
+        path: &str,
+        server_name: Option<&str>,
+        local_address: Option<IpAddr>,
+        interface: Option<&str>,
+    ) -> Result<Self, ProtoError> {
+        let mut builder = proxy::builder()
+            .https_only(true)
+            .timeout(Duration::from_secs(5))
+            .local_address(local_address);
+        if let Some(interface) = interface {
+            builder = builder.interface(interface);
+        }
+        let host = match server_name {
+            Some(server_name) => {
+                builder = builder.resolve(server_name, address);
+                server_name.to_string()
+            }
+            None if address.is_ipv6() => format!("[{}]", address.ip()),
+            None => address.ip().to_string(),
+        };
+        let client = builder
+            .build()
+            .map_err(|error| ProtoError::from(error.to_string()))?;
+        Ok(Self {
+            client,
+            url: format!("https://{}:{}{}", host, address.port(), path),
+            is_shutdown: false,
+        })
+    }
+}
+
+impl DnsRequestSender for HttpsClientStream {
+    fn send_message(&mut self, request: DnsRequest) -> DnsResponseStream {
+        if self.is_shutdown {
+            return ProtoError::from("Can not send messages after the stream is shut down").into();
+        }
+        let body = match request.to_vec() {
+            Ok(body) => body,
+            Err(error) => return error.into(),
+        };
+
+        let request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, CONTENT_TYPE)
+            .header(reqwest::header::ACCEPT, CONTENT_TYPE)
+            .body(body);
+        Box::pin(async move {
+            let response = request
+                .send()
+                .await
+                .map_err(|error| ProtoError::from(error.to_string()))?;
+            if !response.status().is_success() {
+                return Err(ProtoError::from(format!(
+                    "DNS-over-HTTPS server returned {}",
+                    response.status()
+                )));
+            }
+            let body = response
+                .bytes()
+                .await
+                .map_err(|error| ProtoError::from(error.to_string()))?;
+            DnsResponse::from_buffer(body.to_vec())
+        })
+        .into()
+    }
+
+    fn shutdown(&mut self) {
+        self.is_shutdown = true;
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.is_shutdown
+    }
+}
+
+impl Stream for HttpsClientStream {
+    type Item = Result<(), ProtoError>;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_shutdown {
+            Poll::Ready(None)
+        } else {
+            Poll::Ready(Some(Ok(())))
+        }
+    }
+}
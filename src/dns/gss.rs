@@ -0,0 +1,155 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! GSS-TSIG (RFC 3645) authenticated updates, as used by Active Directory
+//! integrated zones.
+//!
+//! The Kerberos handshake and signing are delegated to `nsupdate -g` from BIND,
+//! which uses the system's GSSAPI library, rather than reimplementing them.
+
+use std::{
+    fs::metadata,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use super::{ConnectionScheme, ConnectionUrl};
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Keytab the client credentials are acquired from
+    keytab: Option<PathBuf>,
+    /// Credential cache with a ticket, e.g. maintained by k5start, used if no
+    /// keytab is configured. Defaults to the default credential cache.
+    ccache: Option<PathBuf>,
+    /// Client principal, only used to identify the credential in the audit log
+    principal: Option<String>,
+    /// Host name of the server, from which its service principal
+    /// `DNS/<server_name>` is derived. Defaults to the address of the URL.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    server_name: Option<Name>,
+    #[serde(default = "default_nsupdate")]
+    nsupdate: PathBuf,
+}
+
+impl Config {
+    pub fn credential_name(&self) -> String {
+        match (&self.principal, &self.keytab) {
+            (Some(principal), _) => principal.clone(),
+            (None, Some(keytab)) => format!("keytab:{}", keytab.display()),
+            (None, None) => "gss-tsig".to_string(),
+        }
+    }
+
+    /// Files holding the credentials.
+    pub fn secret_files(&self) -> Vec<&Path> {
+        self.keytab
+            .iter()
+            .chain(&self.ccache)
+            .map(PathBuf::as_path)
+            .collect()
+    }
+
+    /// Check that nsupdate can reach the servers and the keytab exists.
+    pub fn validate(&self, urls: &[ConnectionUrl]) -> anyhow::Result<()> {
+        if let Some(url) = urls.iter().find(|url| {
+            matches!(
+                url.scheme,
+                ConnectionScheme::Https { .. } | ConnectionScheme::Quic
+            )
+        }) {
+            bail!(
+                "nsupdate can only send updates over UDP or TCP, not to {}",
+                url
+            );
+        }
+        if let Some(keytab) = &self.keytab {
+            metadata(keytab)
+                .with_context(|| format!("Failed to access keytab {}", keytab.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Send an update consisting of the given nsupdate commands.
+    pub async fn update(
+        &self,
+        url: &ConnectionUrl,
+        origin: &Name,
+        commands: &[String],
+    ) -> anyhow::Result<()> {
+        if let ConnectionScheme::Https { .. } | ConnectionScheme::Quic = url.scheme {
+            bail!("nsupdate can only send updates over UDP or TCP");
+        }
+        let server = match &self.server_name {
+            Some(server_name) => server_name.to_string(),
+            None => url.address.ip().to_string(),
+        };
+        let mut script = format!(
+            "server {} {}\nzone {}\n",
+            server,
+            url.address.port(),
+            origin
+        );
+        for command in commands {
+            script.push_str(command);
+            script.push('\n');
+        }
+        script.push_str("send\n");
+
+        let mut command = Command::new(&self.nsupdate);
+        command.arg("-g");
+        if let ConnectionScheme::Tcp = url.scheme {
+            command.arg("-v");
+        }
+        if let Some(keytab) = &self.keytab {
+            command.env("KRB5_CLIENT_KTNAME", keytab);
+        }
+        if let Some(ccache) = &self.ccache {
+            command.env("KRB5CCNAME", ccache);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.nsupdate.display()))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(script.as_bytes())
+                .await
+                .context("Failed to pass the update to nsupdate")?;
+        }
+        let output = child
+            .wait_with_output()
+            .await
+            .context("Failed to wait for nsupdate")?;
+        if !output.status.success() {
+            bail!(
+                "nsupdate failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_nsupdate() -> PathBuf {
+    PathBuf::from("nsupdate")
+}
@@ -0,0 +1,183 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Message signing for transports that don't sign messages themselves, unlike
+//! hickory's UDP and TCP streams, and TSIG signing telling rejected keys apart.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{Stream, StreamExt};
+use hickory_proto::{
+    ProtoError,
+    dnssec::{
+        rdata::{DNSSECRData, TSIG},
+        tsig::TSigner,
+    },
+    op::{Message, MessageFinalizer, MessageVerifier, ResponseCode},
+    rr::{Name, RData, Record},
+    serialize::binary::{BinDecodable, BinDecoder, BinEncodable},
+    xfer::{DnsRequest, DnsRequestSender, DnsResponse, DnsResponseStream},
+};
+
+/// TSIG signing that lets responses through in which the server rejected the
+/// key, the signature or its time. The server can't sign most of those, so
+/// hickory's signer refuses them, leaving no way to retry with another key or
+/// to tell a skewed clock apart.
+pub struct Tsig(pub TSigner);
+
+impl MessageFinalizer for Tsig {
+    fn finalize_message(
+        &self,
+        message: &Message,
+        current_time: u32,
+    ) -> Result<(Vec<Record>, Option<MessageVerifier>), ProtoError> {
+        let (records, verifier) = self.0.finalize_message(message, current_time)?;
+        let verifier = verifier.map(|mut verify| -> MessageVerifier {
+            Box::new(move |response: &[u8]| {
+                verify(response).or_else(|error| {
+                    match DnsResponse::from_buffer(response.to_vec()) {
+                        Ok(response)
+                            if key_rejected(&response) || time_rejected(&response).is_some() =>
+                        {
+                            Ok(response)
+                        }
+                        _ => Err(error),
+                    }
+                })
+            })
+        });
+        Ok((records, verifier))
+    }
+}
+
+/// Whether the server rejected the key or signature of the request, which it
+/// answers with NOTAUTH and a TSIG record without a MAC (RFC 8945, 5.2).
+pub fn key_rejected(response: &DnsResponse) -> bool {
+    response.response_code() == ResponseCode::NotAuth
+        && response
+            .signature()
+            .iter()
+            .any(|record| match record.data() {
+                RData::DNSSEC(DNSSECRData::TSIG(tsig)) => tsig.mac().is_empty(),
+                _ => false,
+            })
+}
+
+/// The time the server signed `response` at.
+pub fn signed_at(response: &DnsResponse) -> Option<u64> {
+    tsig(response).map(TSIG::time)
+}
+
+/// The current time of the server, if it rejected the signature of the
+/// request as made outside of its time window, which it answers with BADTIME
+/// and its time in the other data of the TSIG record (RFC 8945, 5.2.3).
+pub fn time_rejected(response: &DnsResponse) -> Option<u64> {
+    let (error, other) = tsig_error(tsig(response)?)?;
+    if error != u16::from(ResponseCode::BADTIME) {
+        return None;
+    }
+    let other: [u8; 6] = other.try_into().ok()?;
+    Some(
+        other
+            .iter()
+            .fold(0, |time, byte| (time << 8) | u64::from(*byte)),
+    )
+}
+
+fn tsig(response: &DnsResponse) -> Option<&TSIG> {
+    response
+        .signature()
+        .iter()
+        .find_map(|record| match record.data() {
+            RData::DNSSEC(DNSSECRData::TSIG(tsig)) => Some(tsig),
+            _ => None,
+        })
+}
+
+/// The error and other data of `tsig`, which hickory doesn't expose, read
+/// back from its wire format (RFC 8945, 4.2).
+fn tsig_error(tsig: &TSIG) -> Option<(u16, Vec<u8>)> {
+    let bytes = tsig.to_bytes().ok()?;
+    let mut decoder = BinDecoder::new(&bytes);
+    Name::read(&mut decoder).ok()?;
+    // Time signed and fudge
+    decoder.read_slice(8).ok()?;
+    let mac_size = decoder.read_u16().ok()?.unverified();
+    // MAC and original ID
+    decoder.read_slice(usize::from(mac_size) + 2).ok()?;
+    let error = decoder.read_u16().ok()?.unverified();
+    let other_len = decoder.read_u16().ok()?.unverified();
+    let other = decoder.read_vec(usize::from(other_len)).ok()?.unverified();
+    Some((error, other))
+}
+
+pub struct SignedStream<S> {
+    inner: S,
+    signer: Option<Arc<dyn MessageFinalizer>>,
+}
+
+impl<S> SignedStream<S> {
+    pub fn new(inner: S, signer: Option<Arc<dyn MessageFinalizer>>) -> Self {
+        Self { inner, signer }
+    }
+}
+
+impl<S: DnsRequestSender> DnsRequestSender for SignedStream<S> {
+    fn send_message(&mut self, mut request: DnsRequest) -> DnsResponseStream {
+        let Some(signer) = &self.signer else {
+            return self.inner.send_message(request);
+        };
+        if !signer.should_finalize_message(&request) {
+            return self.inner.send_message(request);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as u32)
+            .unwrap_or_default();
+        let verifier = match request.finalize(&**signer, now) {
+            Ok(verifier) => verifier,
+            Err(error) => return error.into(),
+        };
+        let mut responses = self.inner.send_message(request);
+        let Some(mut verifier) = verifier else {
+            return responses;
+        };
+        Box::pin(async move {
+            let response = responses
+                .next()
+                .await
+                .unwrap_or_else(|| Err(ProtoError::from("No response received")))?;
+            verifier(response.as_buffer())
+        })
+        .into()
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.inner.is_shutdown()
+    }
+}
+
+impl<S: DnsRequestSender> Stream for SignedStream<S> {
+    type Item = Result<(), ProtoError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::{fmt, time::Duration};
+
+use serde::{
+    Deserializer, Serializer,
+    de::{Error, Unexpected, Visitor},
+};
+use serde_with::{DeserializeAs, SerializeAs};
+
+/// Durations written either as humantime strings like `"5m"` or `"1h 30m"`,
+/// or as a plain number of seconds for compatibility with older configs.
+/// Zero durations are rejected.
+pub struct HumanDuration;
+
+impl SerializeAs<Duration> for HumanDuration {
+    fn serialize_as<S: Serializer>(source: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(*source))
+    }
+}
+
+impl<'de> DeserializeAs<'de, Duration> for HumanDuration {
+    fn deserialize_as<D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserializer.deserialize_any(DurationVisitor)
+    }
+}
+
+struct DurationVisitor;
+
+impl Visitor<'_> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a positive duration like \"5m\" or a number of seconds")
+    }
+
+    fn visit_u64<E: Error>(self, seconds: u64) -> Result<Duration, E> {
+        if seconds == 0 {
+            return Err(E::invalid_value(Unexpected::Unsigned(seconds), &self));
+        }
+        Ok(Duration::from_secs(seconds))
+    }
+
+    fn visit_i64<E: Error>(self, seconds: i64) -> Result<Duration, E> {
+        match u64::try_from(seconds) {
+            Ok(seconds) => self.visit_u64(seconds),
+            Err(_) => Err(E::invalid_value(Unexpected::Signed(seconds), &self)),
+        }
+    }
+
+    fn visit_str<E: Error>(self, value: &str) -> Result<Duration, E> {
+        match humantime::parse_duration(value) {
+            Ok(duration) if !duration.is_zero() => Ok(duration),
+            Ok(_) => Err(E::invalid_value(Unexpected::Str(value), &self)),
+            Err(error) => Err(E::custom(format!("invalid duration {value:?}: {error}"))),
+        }
+    }
+}
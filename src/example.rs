@@ -0,0 +1,245 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! A commented example config, optionally filled in with the RFC 2136
+//! settings entered by the user.
+
+use anyhow::Result;
+use dyndnsd::dns::ConnectionUrl;
+use hickory_proto::rr::Name;
+
+use crate::setup;
+
+/// The settings the example is filled in with.
+struct Values {
+    url: String,
+    zone: String,
+    domain: String,
+    key_name: String,
+    key: String,
+    algorithm: String,
+}
+
+impl Default for Values {
+    fn default() -> Self {
+        Self {
+            url: "udp://192.0.2.53:53".into(),
+            zone: "example.com".into(),
+            domain: "home.example.com".into(),
+            key_name: "dyndnsd".into(),
+            key: "c2VjcmV0IGtleSBzaGFyZWQgd2l0aCB0aGUgc2VydmVy".into(),
+            algorithm: "hmac-sha256".into(),
+        }
+    }
+}
+
+/// Print the example config to stdout, asking for the values first if
+/// `interactive` is set. The prompts go to stderr, so the output can be
+/// redirected into a file.
+pub fn print(interactive: bool) -> Result<()> {
+    let values = if interactive {
+        ask()?
+    } else {
+        Values::default()
+    };
+    print!("{}", render(&values));
+    Ok(())
+}
+
+fn ask() -> Result<Values> {
+    let url = loop {
+        let answer = setup::prompt("Server, e.g. udp://192.0.2.53:53", None)?;
+        match answer.parse::<ConnectionUrl>() {
+            Ok(_) => break answer,
+            Err(error) => eprintln!("Invalid server: {error}"),
+        }
+    };
+    let zone = loop {
+        match setup::prompt("Zone, e.g. example.com", None)?.parse::<Name>() {
+            Ok(zone) => break zone,
+            Err(error) => eprintln!("Invalid name: {error}"),
+        }
+    };
+    let domain = loop {
+        let default = format!("home.{zone}");
+        match setup::prompt("Record to keep updated", Some(&default))?.parse::<Name>() {
+            Ok(name) if zone.zone_of(&name) => break name,
+            Ok(name) => eprintln!("{name} is not part of the zone {zone}"),
+            Err(error) => eprintln!("Invalid name: {error}"),
+        }
+    };
+    Ok(Values {
+        url,
+        zone: zone.to_string(),
+        domain: domain.to_string(),
+        key_name: setup::prompt("TSIG key name", Some("dyndnsd"))?,
+        key: setup::prompt_secret("TSIG key (base64)")?,
+        algorithm: setup::prompt("TSIG algorithm", Some("hmac-sha256"))?,
+    })
+}
+
+/// A TOML string literal of `value`.
+fn quote(value: &str) -> String {
+    toml::Value::from(value).to_string()
+}
+
+fn render(values: &Values) -> String {
+    format!(
+        r#"# dyndnsd config, usually installed as /etc/dyndnsd/config.toml.
+# Check it with `dyndnsd check-config <path>` before installing it. Every
+# setting is described in more detail in the README.
+
+# Record kept up to date, and the zone containing it. Without a zone, it's
+# discovered through the SOA records of the record and its parents.
+domain = {domain}
+zone = {zone}
+# Further names set to the same addresses, relative to the zone
+#aliases = ["www"]
+
+# Which address families are updated, as A and AAAA records
+ipv4 = true
+ipv6 = false
+# Publish all detected IPv6 addresses instead of only the first one
+#publish_all_ipv6 = false
+# Keep a TXT record at _dyndnsd.<record> with the time of the last update
+#metadata_record = false
+# Reverse zones the PTR records of the published addresses are kept in
+#reverse_zones = ["2.0.192.in-addr.arpa"]
+# Delete the records when the daemon stops, or set them to a fallback address
+#delete_on_shutdown = false
+#shutdown_fallback = ["192.0.2.1"]
+
+# How often the addresses are checked, optionally per family
+interval = "60s"
+#interval_v4 = "1m"
+#interval_v6 = "1h"
+
+# Skip families without a default route instead of failing to update them
+#skip_offline = true
+# Refuse to start instead of discarding a cache that looks tampered with
+#strict_state = false
+# Refuse to start instead of warning when other users can access this file
+#strict_modes = false
+# Restrict the daemon's filesystem access and system calls, see the README
+#sandbox = false
+# What to do with detected addresses that aren't reachable from the
+# internet: skip, fail or update
+#bogon_policy = "skip"
+# Keep the state in memory only, for read-only filesystems
+#cache = "memory"
+# SQLite database the state and its history are kept in, instead of the
+# cache file, see `dyndnsd history`
+#state_db = "/var/lib/dyndnsd/state.sqlite"
+# Append-only JSON log of every attempted record change
+#audit_log = "/var/log/dyndnsd/audit.jsonl"
+# Metrics written after every cycle, for node_exporter's textfile collector
+#prometheus_textfile = "/var/lib/node_exporter/textfile/dyndnsd.prom"
+# Update right away when NetworkManager reports a new connection or address
+#watch_networkmanager = false
+# Unix socket for `dyndnsd status` and other commands to the running daemon
+#control_socket = "/run/dyndnsd/control.sock"
+
+# The provider the record is published to. RFC 2136 UPDATEs are sent to the
+# servers in order until one accepts them, signed with TSIG. Repeat the
+# section as [[dns_provider_config]] to publish to several providers, and see
+# the README for the other providers and authentication methods.
+[dns_provider_config]
+provider = "rfc2136"
+url = {url}  # or tcp://, https:// or quic://
+key_name = {key_name}
+key = {key}  # or {{ credential = "tsig-key" }}, read from systemd's credentials
+algorithm = {algorithm}
+#fudge = "60s"  # how far the clocks may be apart for signatures to be accepted
+
+# Timeouts, retries and rate limits of all outbound calls
+#[outbound]
+#timeout = "30s"
+#retries = 0
+#backoff = "1s"
+#min_interval = "5s"
+
+# Proxy for all HTTP requests, instead of the one set in HTTP_PROXY etc.
+#[proxy]
+#url = "socks5h://proxy.example.com:1080"
+
+# Where the addresses are detected, tried in order until one succeeds.
+# Defaults to OpenDNS, Google, ipify and whatismyipaddress.com.
+#[[sources.ipv4]]
+#source = "stun"
+#servers = ["stun.l.google.com:19302", "stun.cloudflare.com:3478"]
+#
+#[[sources.ipv4]]
+#source = "ipify"
+
+# Post-processing of the detected addresses, applied in order
+#[[filters.ipv4]]
+#filter = "drop"
+#ranges = ["100.64.0.0/10"]
+
+# AAAA records of other hosts in the LAN, sharing the detected IPv6 prefix
+#[lan]
+#prefix_length = 64
+#
+#[[lan.hosts]]
+#domain = "nas.example.com"
+#suffix = "::211:32ff:fe12:3456"
+
+# Notifications about changed records and failing updates
+#[[notifications.webhook]]
+#url = "https://alerts.example.com/dyndnsd"
+
+# Commands run with /bin/sh when a record changed or failed to be updated
+#[hooks]
+#on_change = ["systemctl restart wg-quick@wg0"]
+#on_failure = []
+#timeout = "1m"
+
+# dyndns2 endpoint routers can push their addresses to
+#[server]
+#listen = "[::]:8245"
+#allowed_networks = ["192.168.0.0/16", "fd00::/8"]
+#client_limit = {{ burst = 20, interval = "30s" }}
+#auth_failure_limit = {{ burst = 5, interval = "1m" }}
+#hostname_limit = {{ burst = 5, interval = "5m" }}
+# One per device, the password hashed with `dyndnsd hash-password`
+#[[server.users]]
+#username = "router"
+#password_hash = "$pbkdf2-sha256$i=600000$..."
+#hostnames = ["router"]
+
+# healthchecks.io compatible check pinged after every cycle
+#[healthchecks]
+#url = "https://hc-ping.com/your-check-uuid"
+
+# MQTT broker the state is published to, with Home Assistant discovery
+#[mqtt]
+#host = "mqtt.example.com"
+#tls = true
+#discovery_prefix = "homeassistant"
+
+# OpenTelemetry collector the traces and metrics are exported to
+#[telemetry]
+#endpoint = "http://localhost:4318"
+
+# Vault server secrets like the TSIG key are read from, given as e.g.
+# key = {{ vault = "dyndnsd/tsig", field = "key" }}
+#[vault]
+#address = "https://vault.example.com:8200"
+#token = {{ credential = "vault-token" }}
+"#,
+        domain = quote(&values.domain),
+        zone = quote(&values.zone),
+        url = quote(&values.url),
+        key_name = quote(&values.key_name),
+        key = quote(&values.key),
+        algorithm = quote(&values.algorithm),
+    )
+}
@@ -0,0 +1,248 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Post-processing of detected addresses, configured as a pipeline of filters
+//! per address family.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Result, bail};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Filters {
+    #[serde(default)]
+    pub ipv4: Vec<Filter>,
+    #[serde(default)]
+    pub ipv6: Vec<Filter>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "filter", rename_all = "snake_case")]
+pub enum Filter {
+    /// Drop addresses within any of the ranges
+    Drop { ranges: Vec<IpNet> },
+    /// Move addresses from one range into another of the same size, keeping
+    /// the host part
+    Map { from: IpNet, to: IpNet },
+    /// Keep only the addresses matching the longest matching prefix. If none
+    /// of the prefixes match, all addresses are kept.
+    Prefer { prefixes: Vec<IpNet> },
+    /// Drop repeated addresses, keeping the first occurrence
+    Dedupe,
+}
+
+impl Filters {
+    /// Check that every range used fits the family of its pipeline.
+    pub fn validate(&self) -> Result<()> {
+        for (family, filters, is_v4) in [("ipv4", &self.ipv4, true), ("ipv6", &self.ipv6, false)] {
+            for filter in filters {
+                let ranges = match filter {
+                    Filter::Drop { ranges } => ranges.clone(),
+                    Filter::Map { from, to } => {
+                        if from.prefix_len() != to.prefix_len() {
+                            bail!("Cannot map {} to {}, the ranges differ in size", from, to);
+                        }
+                        vec![*from, *to]
+                    }
+                    Filter::Prefer { prefixes } => prefixes.clone(),
+                    Filter::Dedupe => Vec::new(),
+                };
+                if let Some(range) = ranges
+                    .iter()
+                    .find(|range| matches!(range, IpNet::V4(_)) != is_v4)
+                {
+                    bail!("The {} filters contain the foreign range {}", family, range);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the candidate addresses through the pipeline of filters, in order.
+pub fn apply(filters: &[Filter], mut addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    for filter in filters {
+        match filter {
+            Filter::Drop { ranges } => {
+                addrs.retain(|addr| !ranges.iter().any(|range| range.contains(addr)))
+            }
+            Filter::Map { from, to } => {
+                for addr in &mut addrs {
+                    if from.contains(&*addr) {
+                        *addr = map(*addr, from, to);
+                    }
+                }
+            }
+            Filter::Prefer { prefixes } => {
+                let longest_match = |addr: &IpAddr| {
+                    prefixes
+                        .iter()
+                        .filter(|prefix| prefix.contains(addr))
+                        .map(|prefix| prefix.prefix_len())
+                        .max()
+                };
+                if let Some(best) = addrs.iter().filter_map(longest_match).max() {
+                    addrs.retain(|addr| longest_match(addr) == Some(best));
+                }
+            }
+            Filter::Dedupe => {
+                let mut seen = Vec::with_capacity(addrs.len());
+                addrs.retain(|addr| {
+                    let new = !seen.contains(addr);
+                    seen.push(*addr);
+                    new
+                });
+            }
+        }
+    }
+    addrs
+}
+
+fn map(addr: IpAddr, from: &IpNet, to: &IpNet) -> IpAddr {
+    match (addr, from, to) {
+        (IpAddr::V4(addr), IpNet::V4(from), IpNet::V4(to)) => {
+            let host = u32::from(addr) & u32::from(from.hostmask());
+            Ipv4Addr::from(u32::from(to.network()) | host).into()
+        }
+        (IpAddr::V6(addr), IpNet::V6(from), IpNet::V6(to)) => {
+            let host = u128::from(addr) & u128::from(from.hostmask());
+            Ipv6Addr::from(u128::from(to.network()) | host).into()
+        }
+        (addr, _, _) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn drop_ranges() {
+        let filters = [Filter::Drop {
+            ranges: nets(&["10.0.0.0/8", "fd00::/8"]),
+        }];
+        assert_eq!(
+            apply(
+                &filters,
+                addrs(&["10.1.2.3", "192.0.2.1", "fd12::1", "2001:db8::1"])
+            ),
+            addrs(&["192.0.2.1", "2001:db8::1"])
+        );
+    }
+
+    #[test]
+    fn map_keeps_host_part() {
+        let filters = [
+            Filter::Map {
+                from: "10.0.0.0/8".parse().unwrap(),
+                to: "100.64.0.0/8".parse().unwrap(),
+            },
+            Filter::Map {
+                from: "2001:db8:aa00::/56".parse().unwrap(),
+                to: "2001:db8:bb00::/56".parse().unwrap(),
+            },
+        ];
+        assert_eq!(
+            apply(
+                &filters,
+                addrs(&["10.1.2.3", "192.0.2.1", "2001:db8:aa00:12::1"])
+            ),
+            addrs(&["100.1.2.3", "192.0.2.1", "2001:db8:bb00:12::1"])
+        );
+    }
+
+    #[test]
+    fn prefer_longest_match() {
+        let filters = [Filter::Prefer {
+            prefixes: nets(&["2001:db8::/32", "2001:db8:1::/48"]),
+        }];
+        assert_eq!(
+            apply(
+                &filters,
+                addrs(&[
+                    "2001:db8:2::1",
+                    "2001:db8:1::1",
+                    "2001:db9::1",
+                    "2001:db8:1::2"
+                ])
+            ),
+            addrs(&["2001:db8:1::1", "2001:db8:1::2"])
+        );
+    }
+
+    #[test]
+    fn prefer_without_match_keeps_all() {
+        let filters = [Filter::Prefer {
+            prefixes: nets(&["2001:db8::/32"]),
+        }];
+        let candidates = addrs(&["2001:db9::1", "2001:dba::1"]);
+        assert_eq!(apply(&filters, candidates.clone()), candidates);
+    }
+
+    #[test]
+    fn dedupe_keeps_first() {
+        assert_eq!(
+            apply(
+                &[Filter::Dedupe],
+                addrs(&["192.0.2.2", "192.0.2.1", "192.0.2.2", "192.0.2.1"])
+            ),
+            addrs(&["192.0.2.2", "192.0.2.1"])
+        );
+    }
+
+    #[test]
+    fn validate_rejects_foreign_range() {
+        let filters = Filters {
+            ipv4: vec![Filter::Drop {
+                ranges: nets(&["10.0.0.0/8", "fd00::/8"]),
+            }],
+            ipv6: Vec::new(),
+        };
+        assert!(filters.validate().is_err());
+        let filters = Filters {
+            ipv4: Vec::new(),
+            ipv6: vec![Filter::Prefer {
+                prefixes: nets(&["192.0.2.0/24"]),
+            }],
+        };
+        assert!(filters.validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_map_of_different_sizes() {
+        let filters = Filters {
+            ipv4: vec![Filter::Map {
+                from: "10.0.0.0/8".parse().unwrap(),
+                to: "100.64.0.0/10".parse().unwrap(),
+            }],
+            ipv6: Vec::new(),
+        };
+        assert!(filters.validate().is_err());
+        let filters = Filters {
+            ipv4: vec![Filter::Map {
+                from: "10.0.0.0/8".parse().unwrap(),
+                to: "100.0.0.0/8".parse().unwrap(),
+            }],
+            ipv6: Vec::new(),
+        };
+        assert!(filters.validate().is_ok());
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Pings to a healthchecks.io compatible check after every cycle, so it
+//! raises an alert when the daemon stops running or its updates fail.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{outbound::Outbound, proxy};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Healthchecks {
+    /// Ping URL of the check, like `https://hc-ping.com/<uuid>`
+    url: String,
+}
+
+impl Healthchecks {
+    /// Ping the check, or its `/fail` endpoint with the error if the cycle
+    /// failed. Failures are logged, but don't affect the update.
+    pub async fn ping(&self, result: &Result<()>, outbound: &Outbound) {
+        let (url, body) = match result {
+            Ok(()) => (self.url.clone(), String::new()),
+            Err(error) => (
+                format!("{}/fail", self.url.trim_end_matches('/')),
+                format!("{error:#}"),
+            ),
+        };
+        let send = || async {
+            proxy::client()
+                .post(&url)
+                .body(body.clone())
+                .send()
+                .await
+                .context("Failed to send request to healthchecks")?
+                .error_for_status()?;
+            Ok(())
+        };
+        if let Err(error) = outbound.call("healthchecks", send).await {
+            tracing::error!("Failed to ping healthchecks: {:#}", error);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! SQLite database keeping the state instead of the cache file, along with
+//! the history of the detected addresses, the record updates and the failed
+//! update cycles.
+
+use std::{
+    fs::OpenOptions,
+    io::ErrorKind,
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Row, params};
+use serde::Serialize;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS state (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        cache TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS changes (
+        timestamp INTEGER NOT NULL,
+        family TEXT NOT NULL,
+        old TEXT,
+        new TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS updates (
+        timestamp INTEGER NOT NULL,
+        record TEXT NOT NULL,
+        record_type TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        old TEXT,
+        new TEXT NOT NULL,
+        latency_ms INTEGER NOT NULL,
+        error TEXT
+    );
+    CREATE TABLE IF NOT EXISTS failures (
+        timestamp INTEGER NOT NULL,
+        error TEXT NOT NULL
+    );
+";
+
+/// How long a query waits for another process holding the database lock
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A change of a detected address.
+#[derive(Serialize)]
+pub struct Change {
+    /// Unix timestamp of the detection
+    pub timestamp: u64,
+    /// `ipv4` or `ipv6`
+    pub family: String,
+    /// The address detected before, if any
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// An attempt to set a record on a provider.
+#[derive(Serialize)]
+pub struct Update {
+    /// Unix timestamp of the attempt
+    pub timestamp: u64,
+    pub record: String,
+    pub record_type: String,
+    /// ID of the provider
+    pub provider: String,
+    pub old: Option<String>,
+    pub new: String,
+    /// How long the provider took, including retries
+    pub latency_ms: u64,
+    /// Why the attempt failed, if it failed
+    pub error: Option<String>,
+}
+
+/// A failed update cycle.
+#[derive(Serialize)]
+pub struct Failure {
+    /// Unix timestamp of the end of the cycle
+    pub timestamp: u64,
+    pub error: String,
+}
+
+pub struct Db {
+    path: PathBuf,
+    /// The updates of both address families share the connection.
+    conn: Mutex<Connection>,
+}
+
+impl Db {
+    /// Open the database for the daemon, creating it if it doesn't exist yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        // Created up front, as SQLite would create it world-readable.
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+        {
+            Err(error) if error.kind() != ErrorKind::AlreadyExists => {
+                return Err(error).with_context(|| format!("Failed to create {}", path.display()));
+            }
+            _ => {}
+        }
+        Self::connect(path)
+    }
+
+    /// Open the database written by the daemon, failing if there is none.
+    pub fn open_existing(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            bail!("{} doesn't exist, has the daemon run yet?", path.display());
+        }
+        Self::connect(path)
+    }
+
+    fn connect(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .and_then(|conn| {
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            // Lets `dyndnsd status` and `history` read while the daemon writes.
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.execute_batch(SCHEMA)?;
+            Ok(conn)
+        })
+        .with_context(|| format!("Failed to open state database {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The serialized cache, if one was saved yet.
+    pub(crate) fn load_state(&self) -> Result<Option<String>> {
+        self.conn()
+            .query_row("SELECT cache FROM state WHERE id = 1", [], |row| row.get(0))
+            .optional()
+            .context("Failed to read the state")
+    }
+
+    pub(crate) fn save_state(&self, cache: &str) -> Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO state (id, cache) VALUES (1, ?1)
+                 ON CONFLICT (id) DO UPDATE SET cache = excluded.cache",
+                [cache],
+            )
+            .context("Failed to save the state")?;
+        Ok(())
+    }
+
+    pub fn record_change(&self, family: &str, old: Option<IpAddr>, new: IpAddr) -> Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO changes (timestamp, family, old, new) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    crate::now() as i64,
+                    family,
+                    old.map(|old| old.to_string()),
+                    new.to_string()
+                ],
+            )
+            .context("Failed to record the address change")?;
+        Ok(())
+    }
+
+    pub fn record_update(&self, update: &Update) -> Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO updates
+                 (timestamp, record, record_type, provider, old, new, latency_ms, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    update.timestamp as i64,
+                    update.record,
+                    update.record_type,
+                    update.provider,
+                    update.old,
+                    update.new,
+                    update.latency_ms as i64,
+                    update.error
+                ],
+            )
+            .context("Failed to record the update")?;
+        Ok(())
+    }
+
+    pub fn record_failure(&self, error: &str) -> Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO failures (timestamp, error) VALUES (?1, ?2)",
+                params![crate::now() as i64, error],
+            )
+            .context("Failed to record the failure")?;
+        Ok(())
+    }
+
+    /// The last `limit` changes of the detected addresses, newest first.
+    pub fn changes(&self, limit: usize) -> Result<Vec<Change>> {
+        self.query(
+            "SELECT timestamp, family, old, new FROM changes
+             ORDER BY rowid DESC LIMIT ?1",
+            limit,
+            |row| {
+                Ok(Change {
+                    timestamp: row.get::<_, i64>(0)? as u64,
+                    family: row.get(1)?,
+                    old: row.get(2)?,
+                    new: row.get(3)?,
+                })
+            },
+        )
+    }
+
+    /// The last `limit` attempts to set a record, newest first.
+    pub fn updates(&self, limit: usize) -> Result<Vec<Update>> {
+        self.query(
+            "SELECT timestamp, record, record_type, provider, old, new, latency_ms, error
+             FROM updates ORDER BY rowid DESC LIMIT ?1",
+            limit,
+            |row| {
+                Ok(Update {
+                    timestamp: row.get::<_, i64>(0)? as u64,
+                    record: row.get(1)?,
+                    record_type: row.get(2)?,
+                    provider: row.get(3)?,
+                    old: row.get(4)?,
+                    new: row.get(5)?,
+                    latency_ms: row.get::<_, i64>(6)? as u64,
+                    error: row.get(7)?,
+                })
+            },
+        )
+    }
+
+    /// The last `limit` failed update cycles, newest first.
+    pub fn failures(&self, limit: usize) -> Result<Vec<Failure>> {
+        self.query(
+            "SELECT timestamp, error FROM failures ORDER BY rowid DESC LIMIT ?1",
+            limit,
+            |row| {
+                Ok(Failure {
+                    timestamp: row.get::<_, i64>(0)? as u64,
+                    error: row.get(1)?,
+                })
+            },
+        )
+    }
+
+    fn query<T>(
+        &self,
+        sql: &str,
+        limit: usize,
+        map: impl FnMut(&Row) -> rusqlite::Result<T>,
+    ) -> Result<Vec<T>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(sql)?;
+        let rows = statement
+            .query_map([limit as i64], map)?
+            .collect::<rusqlite::Result<_>>()
+            .context("Failed to read the history")?;
+        Ok(rows)
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! User supplied commands run when a record changed or failed to be updated,
+//! e.g. to restart a VPN or regenerate firewall rules.
+
+use std::{process::Stdio, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tokio::{process::Command, time::timeout};
+
+use crate::{duration::HumanDuration, notify::Event};
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Hooks {
+    /// Commands run after a record was changed
+    #[serde(default)]
+    on_change: Vec<String>,
+    /// Commands run when updating a record starts failing
+    #[serde(default)]
+    on_failure: Vec<String>,
+    /// Time after which a command is killed
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_timeout")]
+    timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            on_change: Vec::new(),
+            on_failure: Vec::new(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+impl Hooks {
+    /// Whether no commands are configured.
+    pub fn is_empty(&self) -> bool {
+        self.on_change.is_empty() && self.on_failure.is_empty()
+    }
+
+    /// Run the commands for the event one after the other. Failures are
+    /// logged, but don't affect the update.
+    pub async fn run(&self, event: &Event) {
+        let commands = match (event.success, event.retry) {
+            (true, _) => &self.on_change,
+            (false, false) => &self.on_failure,
+            (false, true) => return,
+        };
+        for command in commands {
+            if let Err(error) = self.run_command(command, event).await {
+                tracing::error!("Hook failed: {:#}", error);
+            }
+        }
+    }
+
+    /// Run the command with `sh`, passing the event in `DYNDNSD_*` variables.
+    async fn run_command(&self, command: &str, event: &Event) -> Result<()> {
+        tracing::debug!("Running hook {}", command);
+        let child = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .env("DYNDNSD_RECORD", &event.record)
+            .env("DYNDNSD_RECORD_TYPE", &event.record_type)
+            .env("DYNDNSD_PROVIDER", &event.provider)
+            .env("DYNDNSD_OLD", event.old.as_deref().unwrap_or_default())
+            .env("DYNDNSD_NEW", &event.new)
+            .env("DYNDNSD_ERROR", event.error.as_deref().unwrap_or_default())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run {command}"))?;
+        let output = timeout(self.timeout, child.wait_with_output())
+            .await
+            .with_context(|| format!("{command} timed out"))?
+            .with_context(|| format!("Failed to run {command}"))?;
+        if !output.status.success() {
+            bail!(
+                "{} failed ({}): {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(60)
+}
@@ -0,0 +1,79 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! `dyndnsd hook`, run from the hooks of dhcpcd, dhclient or pppd, which pass
+//! the address the interface got in their environment. The records are
+//! updated once with it, instead of detecting the address.
+
+use std::{env, net::IpAddr, path::Path};
+
+use anyhow::{Context, Result, bail};
+use dyndnsd::{Config, Updater};
+
+/// Reasons dhcpcd and dhclient run their hooks with when an address was
+/// bound or renewed
+const BOUND: &[&str] = &[
+    "BOUND", "RENEW", "REBIND", "REBOOT", "STATIC", "BOUND6", "RENEW6", "REBIND6", "REBOOT6",
+];
+
+/// Variables holding the new address: dhcpcd's and dhclient's IPv4 address,
+/// dhclient's DHCPv6 address, dhcpcd's first DHCPv6 address, and the local
+/// address pppd's `ip-up` is run with.
+const ADDRESSES: &[&str] = &[
+    "new_ip_address",
+    "new_ip6_address",
+    "new_dhcp6_ia_na1_ia_addr1",
+    "IPLOCAL",
+];
+
+/// Update the records with the addresses in the environment, unless the hook
+/// was run for something other than a new address.
+pub async fn run(config: Config, cache_dir: &Path) -> Result<()> {
+    let interface = env::var("interface")
+        .or_else(|_| env::var("IFNAME"))
+        .unwrap_or_else(|_| "the interface".to_string());
+    match env::var("reason") {
+        Ok(reason) if !BOUND.contains(&reason.as_str()) => {
+            tracing::debug!("Nothing to do for {} on {}", reason, interface);
+            return Ok(());
+        }
+        Err(_) if env::var_os("IPLOCAL").is_none() => {
+            bail!(
+                "Not run from a dhcpcd, dhclient or pppd hook, as neither reason nor IPLOCAL is set"
+            );
+        }
+        _ => {}
+    }
+    let mut addrs = Vec::new();
+    for variable in ADDRESSES {
+        // dhcpcd leaves the variables of the other family empty.
+        let Some(value) = env::var(variable).ok().filter(|value| !value.is_empty()) else {
+            continue;
+        };
+        let addr: IpAddr = value
+            .parse()
+            .with_context(|| format!("{variable} isn't an address: {value:?}"))?;
+        addrs.push(addr);
+    }
+    addrs.retain(|addr| {
+        if addr.is_ipv4() {
+            config.ipv4
+        } else {
+            config.ipv6
+        }
+    });
+    if addrs.is_empty() {
+        tracing::info!("{} got no address of an enabled family", interface);
+        return Ok(());
+    }
+    tracing::info!("{} got {:?}", interface, addrs);
+    Updater::new(config, cache_dir)?.update_with(&addrs).await
+}
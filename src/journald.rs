@@ -0,0 +1,39 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Logging through the native protocol of the systemd journal, keeping the
+//! fields of events and their spans as structured fields, like `RECORD=`.
+
+use std::{
+    env,
+    fs::File,
+    io,
+    os::{fd::AsFd, unix::fs::MetadataExt},
+};
+
+/// A layer writing to the journal, if stderr is connected to it, like it is
+/// for systemd services.
+pub fn layer() -> Option<tracing_journald::Layer> {
+    // systemd sets JOURNAL_STREAM to the device and inode of the stream, which
+    // only match stderr if it wasn't redirected.
+    let stream = env::var("JOURNAL_STREAM").ok()?;
+    let stderr = File::from(io::stderr().as_fd().try_clone_to_owned().ok()?);
+    let metadata = stderr.metadata().ok()?;
+    if stream != format!("{}:{}", metadata.dev(), metadata.ino()) {
+        return None;
+    }
+    let layer = tracing_journald::layer().ok()?;
+    Some(
+        layer
+            .with_field_prefix(None)
+            .with_syslog_identifier(env!("CARGO_PKG_NAME").to_string()),
+    )
+}
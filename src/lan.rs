@@ -0,0 +1,89 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! IPv6 addresses of other hosts in the LAN, composed of the prefix of the
+//! detected address and the interface identifier of each host.
+
+use std::net::Ipv6Addr;
+
+use anyhow::{Result, bail};
+use hickory_proto::rr::Name;
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Lan {
+    /// Length of the prefix taken from the detected address
+    #[serde(default = "default_prefix_length")]
+    pub prefix_length: u8,
+    #[serde(default)]
+    pub hosts: Vec<Host>,
+}
+
+impl Default for Lan {
+    fn default() -> Self {
+        Self {
+            prefix_length: default_prefix_length(),
+            hosts: Vec::new(),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Host {
+    #[serde_as(as = "DisplayFromStr")]
+    pub domain: Name,
+    /// Address of the host within the prefix, like `::211:32ff:fe12:3456`.
+    /// With a prefix shorter than 64 bits, it includes the subnet.
+    pub suffix: Ipv6Addr,
+    /// Further names set to the same address, relative to the zone unless
+    /// they end with a dot
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub aliases: Vec<Name>,
+}
+
+impl Lan {
+    /// Check that every suffix fits behind the prefix.
+    pub fn validate(&self) -> Result<()> {
+        let Ok(prefix) = Ipv6Net::new(Ipv6Addr::UNSPECIFIED, self.prefix_length) else {
+            bail!("Invalid prefix length {}", self.prefix_length);
+        };
+        for host in &self.hosts {
+            if u128::from(host.suffix) & u128::from(prefix.netmask()) != 0 {
+                bail!(
+                    "The suffix {} of {} overlaps the /{} prefix",
+                    host.suffix,
+                    host.domain,
+                    self.prefix_length
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The address of every host within the prefix of `detected`.
+    pub fn addresses(&self, detected: Ipv6Addr) -> impl Iterator<Item = (&Host, Ipv6Addr)> {
+        let prefix = Ipv6Net::new(detected, self.prefix_length)
+            .expect("the prefix length is validated after loading the config")
+            .network();
+        self.hosts.iter().map(move |host| {
+            let addr = Ipv6Addr::from(u128::from(prefix) | u128::from(host.suffix));
+            (host, addr)
+        })
+    }
+}
+
+fn default_prefix_length() -> u8 {
+    64
+}
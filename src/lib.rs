@@ -0,0 +1,62 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! The update logic of dyndnsd, for embedding it without running the daemon.
+//!
+//! A [`Config`] is usually loaded from a file with [`Config::load`], but can
+//! be deserialized from any other source as well, as long as it's passed
+//! through [`Config::validate`] afterwards. An [`Updater`] then keeps
+//! the records up to date, either with [`Updater::run`], or by calling
+//! [`Updater::tick`] whenever [`Updater::next_due`] is reached, alongside
+//! other work.
+
+mod audit;
+pub mod bogon;
+pub mod cache;
+mod config;
+pub mod control;
+pub mod dns;
+mod duration;
+pub mod filter;
+pub mod healthchecks;
+pub mod history;
+pub mod hooks;
+pub mod lan;
+pub mod mqtt;
+pub mod networkmanager;
+pub mod notify;
+pub mod outbound;
+pub mod provider;
+pub mod proxy;
+mod route;
+pub mod sandbox;
+pub mod secret;
+pub mod server;
+pub mod source;
+pub mod telemetry;
+pub mod textfile;
+mod updater;
+pub mod vault;
+mod zone;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use crate::{
+    config::Config, provider::Config as DnsProvider, source::Source as IpSource, updater::Updater,
+};
+
+/// The current Unix timestamp.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
@@ -9,137 +9,533 @@
 // dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
 // law. See the LICENSE.md for details.
 
-mod dns;
+mod example;
+mod ip_hook;
+mod journald;
+mod setup;
+mod snmp;
+mod status;
 
-use anyhow::{Context, Result};
-use hickory_proto::rr::Name;
-use serde::{Deserialize, Serialize};
-use serde_with::{DisplayFromStr, serde_as};
-use tokio::time::interval;
-use toml::{from_str, to_string};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{Parser, Subcommand};
+use dyndnsd::{
+    Config, Updater,
+    cache::Store,
+    control::{self, Request},
+    networkmanager, now, proxy, sandbox,
+    server::{self, PasswordHash},
+    telemetry,
+};
+use hickory_proto::{
+    rr::{Name, RData, RecordType, rdata::TXT},
+    serialize::txt::RDataParser,
+};
+use serde_json::Value;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    time::sleep_until,
+};
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+use zeroize::Zeroizing;
 
 use std::{
-    fs::{File, create_dir_all, read_to_string},
-    io::Write,
-    net::{Ipv4Addr, Ipv6Addr},
-    path::PathBuf,
-    time::Duration,
+    io,
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
-use crate::dns::Config as DnsConfig;
-
-#[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
-struct Config {
-    dns_provider_config: DnsConfig,
-    #[serde_as(as = "DisplayFromStr")]
-    zone: Name,
-    #[serde_as(as = "DisplayFromStr")]
-    domain: Name,
-    #[serde(default = "yes")]
-    ipv4: bool,
-    #[serde(default = "no")]
-    ipv6: bool,
-    #[serde(default = "default_duration")]
-    interval: u64,
+const CONFIG_PATH: &str = "/etc/dyndnsd/config.toml";
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct Cache {
-    v4: Option<Ipv4Addr>,
-    v6: Option<Ipv6Addr>,
+#[derive(Subcommand)]
+enum Command {
+    /// Check that the configured credentials are allowed to update the configured records
+    Check,
+    /// Update the records once with the address a dhcpcd, dhclient or pppd
+    /// hook is run with, instead of detecting it
+    Hook,
+    /// Create, read back and delete a temporary TXT record below the
+    /// configured domain, to check the credentials and connectivity
+    Selftest,
+    /// Check that the config file is valid, without contacting the providers
+    CheckConfig {
+        /// Config file to check, instead of the installed one
+        path: Option<PathBuf>,
+    },
+    /// Print the config with the defaults filled in and the secrets redacted
+    PrintEffectiveConfig,
+    /// Show the state of the daemon
+    Status {
+        /// Include the recent results of each IP source
+        #[arg(long)]
+        sources: bool,
+        /// Print the state as JSON
+        #[arg(long)]
+        json: bool,
+        /// Control socket of the running daemon, instead of the configured one
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Show the recent address changes, record updates and failures, as kept
+    /// in the state database
+    History {
+        /// Number of entries shown of each kind
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// Print the history as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Forget the published addresses, so the next cycle updates every record
+    FlushCache {
+        /// Control socket of the running daemon, instead of the configured one
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Manually manage records using the configured provider credentials
+    Record {
+        /// ID of the provider to use, required if more than one is configured
+        #[arg(long)]
+        provider: Option<String>,
+        #[command(subcommand)]
+        action: RecordAction,
+    },
+    /// Interactively create the config file and a systemd unit
+    Setup,
+    /// Print a commented example config
+    GenerateConfig {
+        /// Ask for the RFC 2136 settings to fill in
+        #[arg(long)]
+        interactive: bool,
+    },
+    /// Hash a password for a user of the dyndns2 endpoint, reading it from
+    /// stdin
+    HashPassword,
+    /// Expose the daemon status to snmpd, for use as a `pass_persist` script
+    SnmpPassPersist {
+        /// OID of the subtree the status is exposed under
+        #[arg(long, default_value = snmp::DEFAULT_BASE_OID)]
+        base_oid: String,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+#[derive(Subcommand)]
+enum RecordAction {
+    /// Show the records of the given name and type
+    Get {
+        /// Name of the records, relative to the zone unless it ends with a dot
+        name: Name,
+        record_type: RecordType,
+    },
+    /// Replace the records of the given name and type with a single record
+    Set {
+        /// Name of the record, relative to the zone unless it ends with a dot
+        name: Name,
+        record_type: RecordType,
+        /// Value of the record, in zone file format
+        value: String,
+    },
+    /// Delete all records of the given name and type
+    Delete {
+        /// Name of the records, relative to the zone unless it ends with a dot
+        name: Name,
+        record_type: RecordType,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (telemetry_layer, telemetry_handle) = telemetry::layer();
+    let registry = tracing_subscriber::registry()
+        .with(telemetry_layer)
+        .with(filter);
+    // Services started by systemd log to the journal directly, so the fields
+    // of events and spans are kept. Otherwise, stdout is left to commands like
+    // the SNMP subagent.
+    match journald::layer() {
+        Some(journald) => registry.with(journald).init(),
+        None => registry.with(fmt::layer().with_writer(io::stderr)).init(),
+    }
 
-    let config_string =
-        read_to_string("/etc/dyndnsd/config.toml").context("couldn't read config file!")?;
-    let config: Config = from_str(&config_string).context("Failed to parse config file")?;
     let cache_dir = PathBuf::from("/var/cache/dyndnsd");
-    let cache_path = cache_dir.join("cache.toml");
-    let mut cache = match read_to_string(&cache_path).map(|str| from_str(&str)) {
-        Ok(Ok(cache)) => cache,
-        _ => {
-            create_dir_all(cache_dir)?;
-            Cache::default()
+    if cli.command.is_none() {
+        // Landlock only restricts the threads started afterwards, so this
+        // can't wait until the config is loaded within the runtime.
+        let config = Config::read(Path::new(CONFIG_PATH))?;
+        if config.sandbox {
+            sandbox::restrict_filesystem(&config, &cache_dir, Path::new(CONFIG_PATH))?;
         }
-    };
+    }
+    tokio::runtime::Runtime::new()
+        .context("Failed to start the runtime")?
+        .block_on(run(cli, telemetry_handle, cache_dir))
+}
 
-    let mut interval = interval(Duration::new(config.interval, 0));
-    loop {
-        if let Err(error) = update(&config, &mut cache, &cache_path).await {
-            log::error!("Failed to update record: {:#?}", error);
+async fn run(cli: Cli, telemetry_handle: telemetry::Handle, cache_dir: PathBuf) -> Result<()> {
+    let store = || Store::configured(&cache_dir, Path::new(CONFIG_PATH));
+
+    match cli.command {
+        Some(Command::SnmpPassPersist { base_oid }) => {
+            return snmp::pass_persist(&base_oid, &store()?);
+        }
+        Some(Command::Status {
+            sources,
+            json,
+            socket,
+        }) => {
+            return status::print(&store()?, Path::new(CONFIG_PATH), socket, sources, json).await;
+        }
+        Some(Command::History { limit, json }) => {
+            return status::history(&store()?, limit, json);
+        }
+        Some(Command::FlushCache { socket }) => return flush_cache(&store()?, socket).await,
+        Some(Command::Setup) => return setup::run(Path::new(CONFIG_PATH)).await,
+        Some(Command::GenerateConfig { interactive }) => return example::print(interactive),
+        Some(Command::HashPassword) => {
+            let password = Zeroizing::new(setup::prompt_secret("Password")?);
+            if password.is_empty() {
+                bail!("The password is empty");
+            }
+            println!("{}", PasswordHash::new(&password)?);
+            return Ok(());
+        }
+        Some(Command::CheckConfig { path }) => {
+            let path = path.unwrap_or_else(|| PathBuf::from(CONFIG_PATH));
+            Config::load(&path).await?;
+            println!("{}: ok", path.display());
+            return Ok(());
         }
-        interval.tick().await;
+        _ => {}
     }
-}
 
-async fn update(config: &Config, cache: &mut Cache, cache_path: &PathBuf) -> Result<()> {
-    if config.ipv4 {
-        let current = public_ip::addr_v4()
-            .await
-            .context("Failed to query current IPv4 address")?;
-        log::debug!("fetched current IP: {}", current);
-        match cache.v4 {
-            Some(old) if old == current => {
-                log::debug!("ipv4 unchanged, continuing...");
+    let config = Config::load(Path::new(CONFIG_PATH)).await?;
+    // The updater sets it as well, the subcommands below don't create one.
+    proxy::set(config.proxy.clone());
+
+    match cli.command {
+        Some(Command::Check) => return check(&config).await,
+        Some(Command::Selftest) => return selftest(&config).await,
+        Some(Command::Hook) => return ip_hook::run(config, &cache_dir).await,
+        Some(Command::PrintEffectiveConfig) => {
+            print!(
+                "{}",
+                toml::to_string(&config).context("Failed to serialize config")?
+            );
+            return Ok(());
+        }
+        Some(Command::Record { provider, action }) => {
+            return record(&config, provider.as_deref(), action).await;
+        }
+        _ => {}
+    }
+
+    let exporter = config
+        .telemetry
+        .as_ref()
+        .map(|telemetry| telemetry.start(&telemetry_handle))
+        .transpose()
+        .context("Failed to set up telemetry")?;
+    let mut control = config
+        .control_socket
+        .as_deref()
+        .map(control::listen)
+        .transpose()?;
+    let mut server = config.server.as_ref().map(server::listen).transpose()?;
+    let mut network_changes = config.watch_networkmanager.then(networkmanager::watch);
+    let mut updater = Updater::new(config, &cache_dir)?;
+    updater.check_clocks().await;
+    updater.seed().await;
+    // Commands waiting for the next update cycle to finish
+    let mut waiting: Vec<Request> = Vec::new();
+
+    let mut terminate =
+        signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
+    let mut interrupt =
+        signal(SignalKind::interrupt()).context("Failed to install SIGINT handler")?;
+    if updater.config().sandbox {
+        sandbox::restrict_syscalls()?;
+    }
+
+    loop {
+        let cycle_start = Instant::now();
+        match updater.tick().await {
+            Some(result) => {
+                if let Some(exporter) = &exporter {
+                    exporter.record(cycle_start.elapsed(), result.is_ok());
+                }
+                for request in waiting.drain(..) {
+                    let reply = match &result {
+                        Ok(()) => Ok(Value::Null),
+                        Err(error) => Err(anyhow!("{error:#}")),
+                    };
+                    request.reply(reply);
+                }
             }
-            _ => {
-                log::info!("ipv4 changed, setting record");
-                config
-                    .dns_provider_config
-                    .set_ipv4(current, config.domain.clone(), config.zone.clone())
-                    .await?;
-                cache.v4 = Some(current);
-                write_cache(cache, cache_path)
-                    .context("Failed to write current IPv4 address to cache")?;
+            None => {
+                for request in waiting.drain(..) {
+                    request.reply(Err(anyhow!("No address family is online")));
+                }
             }
         }
-    }
-    if config.ipv6 {
-        let current = public_ip::addr_v6()
-            .await
-            .context("Failed to query current IPv6 address")?;
-        log::debug!("fetched current IP: {}", current);
-        match cache.v6 {
-            Some(old) if old == current => {
-                log::debug!("ipv6 unchanged, continuing...")
+        let request = tokio::select! {
+            _ = sleep_until(updater.next_due()) => None,
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            request = next_request(&mut control) => request,
+            push = next_request(&mut server) => {
+                if let Some(push) = push {
+                    updater.push(push).await;
+                }
+                continue;
             }
-            _ => {
-                log::info!("ipv6 changed, setting record");
-                config
-                    .dns_provider_config
-                    .set_ipv6(current, config.domain.clone(), config.zone.clone())
-                    .await?;
-                cache.v6 = Some(current);
-                write_cache(cache, cache_path)
-                    .context("Failed to write current IPv6 address to cache")?;
+            Some(()) = next_request(&mut network_changes) => {
+                updater.update_now();
+                continue;
             }
+        };
+        let Some(request) = request else {
+            continue;
+        };
+        match request.command {
+            control::Command::Status => {
+                request.reply(serde_json::to_value(updater.status()).map_err(Into::into));
+            }
+            control::Command::Update => {
+                updater.update_now();
+                waiting.push(request);
+            }
+            control::Command::Flush => request.reply(updater.flush().map(|()| Value::Null)),
+            control::Command::Reload => match Config::load(Path::new(CONFIG_PATH)).await {
+                Ok(config) => {
+                    tracing::info!("Reloaded config");
+                    updater.set_config(config);
+                    request.reply(Ok(Value::Null));
+                }
+                Err(error) => {
+                    tracing::error!("Failed to reload config: {:#}", error);
+                    request.reply(Err(error));
+                }
+            },
         }
     }
+    tracing::info!("Shutting down");
+    updater.withdraw().await;
+    if let Some(exporter) = exporter {
+        exporter.shutdown();
+    }
+    if let Some(path) = &updater.config().control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    // Dropping the config explicitly wipes the secrets it holds from memory.
+    drop(updater);
     Ok(())
 }
 
-fn write_cache(cache: &mut Cache, cache_path: &PathBuf) -> Result<()> {
-    let cache_str = to_string(cache).context("Failed to serialize cache file")?;
-    let mut cache_file =
-        File::create(cache_path).context("Failed to open cache file for writing")?;
-    cache_file
-        .write_all(cache_str.as_bytes())
-        .context("Failed to serialize cache into file")?;
+/// The next request received on the control socket or the dyndns2 endpoint,
+/// or change reported by NetworkManager, if it's enabled.
+async fn next_request<T>(receiver: &mut Option<tokio::sync::mpsc::Receiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn check(config: &Config) -> Result<()> {
+    let records = config.managed_records()?;
+    let mut failed = false;
+    for (id, provider) in config.providers() {
+        for (name, record_type, origin) in &records {
+            match config
+                .outbound
+                .call(&id, || {
+                    provider.check_update_permission(*record_type, name.clone(), origin.clone())
+                })
+                .await
+            {
+                Ok(()) => println!("{} {} via {}: ok", name, record_type, id),
+                Err(error) => {
+                    println!("{} {} via {}: {:#}", name, record_type, id, error);
+                    failed = true;
+                }
+            }
+        }
+    }
+    if failed {
+        bail!("Not all records can be updated");
+    }
     Ok(())
 }
 
-fn yes() -> bool {
-    true
+/// Flush the cache of the running daemon, or the stored cache if the daemon
+/// can't be reached.
+async fn flush_cache(store: &Store, socket: Option<PathBuf>) -> Result<()> {
+    if let Some(socket) = control::socket(socket, Path::new(CONFIG_PATH)) {
+        match control::send(&socket, control::Command::Flush).await {
+            Ok(_) => {
+                println!("Flushed the cache of the running daemon");
+                return Ok(());
+            }
+            Err(error) if control::unreachable(&error) => {
+                tracing::debug!("{:#}, flushing the cache file instead", error);
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    if store.is_memory() {
+        bail!("The daemon keeps its cache in memory, flush it through a control_socket");
+    }
+    // A running daemon would overwrite the flushed cache.
+    let _lock = store
+        .lock()
+        .context("Stop the daemon or set a control_socket to flush its cache")?;
+    let Some(mut cache) = store.load()? else {
+        println!("Nothing is cached yet");
+        return Ok(());
+    };
+    cache.flush();
+    store.write(&cache)?;
+    println!("Flushed {store}");
+    Ok(())
 }
 
-fn no() -> bool {
-    false
+async fn selftest(config: &Config) -> Result<()> {
+    let stamp = now();
+    let name = Name::from_ascii(format!("_dyndnsd-selftest-{stamp}"))?
+        .append_domain(&config.domain)
+        .context("Failed to build the name of the test record")?;
+    let rdata = RData::TXT(TXT::new(vec![format!("dyndnsd selftest {stamp}")]));
+    let mut failed = false;
+    for (id, provider) in config.providers() {
+        if !provider.can_get_records() {
+            println!("{id}: skipped, the test record couldn't be read back");
+            continue;
+        }
+        let result = config
+            .audited(
+                provider,
+                &name,
+                RecordType::TXT,
+                None,
+                Some(rdata.to_string()),
+                config.outbound.call(&id, || {
+                    provider.set_record(rdata.clone(), name.clone(), config.zone().clone())
+                }),
+            )
+            .await;
+        if let Err(error) = result {
+            println!("{id}: creating {name} TXT failed: {error:#}");
+            failed = true;
+            continue;
+        }
+        let served = match config
+            .outbound
+            .call(&id, || provider.get_records(RecordType::TXT, name.clone()))
+            .await
+        {
+            Ok(rdatas) if rdatas.contains(&rdata) => true,
+            Ok(_) => {
+                println!("{id}: {name} TXT was accepted, but isn't served");
+                false
+            }
+            Err(error) => {
+                println!("{id}: reading {name} TXT failed: {error:#}");
+                false
+            }
+        };
+        let result = config
+            .audited(
+                provider,
+                &name,
+                RecordType::TXT,
+                None,
+                None,
+                config.outbound.call(&id, || {
+                    provider.delete_records(RecordType::TXT, name.clone(), config.zone().clone())
+                }),
+            )
+            .await;
+        match result {
+            Ok(()) if served => println!("{id}: ok"),
+            Ok(()) => failed = true,
+            Err(error) => {
+                println!("{id}: deleting {name} TXT failed, remove it manually: {error:#}");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        bail!("Self-test failed");
+    }
+    Ok(())
 }
 
-fn default_duration() -> u64 {
-    60
+async fn record(config: &Config, provider: Option<&str>, action: RecordAction) -> Result<()> {
+    let mut providers = config.providers();
+    let (id, provider) = match provider {
+        Some(wanted) => providers
+            .find(|(id, _)| id == wanted)
+            .with_context(|| format!("No provider with the ID {wanted} is configured"))?,
+        None => match (providers.next(), providers.next()) {
+            (Some(provider), None) => provider,
+            _ => bail!("Multiple providers are configured, select one with --provider"),
+        },
+    };
+    let absolute = |name: Name| {
+        if name.is_fqdn() {
+            Ok(name)
+        } else {
+            name.append_domain(config.zone())
+                .context("Failed to qualify record name")
+        }
+    };
+    match action {
+        RecordAction::Get { name, record_type } => {
+            let name = absolute(name)?;
+            for rdata in provider.get_records(record_type, name.clone()).await? {
+                println!("{} {} {}", name, record_type, rdata);
+            }
+        }
+        RecordAction::Set {
+            name,
+            record_type,
+            value,
+        } => {
+            let name = absolute(name)?;
+            let rdata =
+                RData::try_from_str(record_type, &value).context("Failed to parse record value")?;
+            tracing::info!("Setting {} {} to {} on {}", name, record_type, rdata, id);
+            config
+                .audited(
+                    provider,
+                    &name,
+                    record_type,
+                    None,
+                    Some(rdata.to_string()),
+                    provider.set_record(rdata.clone(), name.clone(), config.zone().clone()),
+                )
+                .await?;
+        }
+        RecordAction::Delete { name, record_type } => {
+            let name = absolute(name)?;
+            tracing::info!("Deleting {} {} on {}", name, record_type, id);
+            config
+                .audited(
+                    provider,
+                    &name,
+                    record_type,
+                    None,
+                    None,
+                    provider.delete_records(record_type, name.clone(), config.zone().clone()),
+                )
+                .await?;
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,224 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! The detected addresses and the update status, published as retained MQTT
+//! messages for home automation systems, optionally along with Home Assistant
+//! discovery payloads.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use rumqttc::{
+    AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration, Transport,
+    tokio_rustls::rustls::{ClientConfig, RootCertStore, crypto::ring},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::{cache::Cache, outbound::Outbound, secret::Secret};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    host: String,
+    /// Defaults to 8883 with TLS and 1883 without
+    port: Option<u16>,
+    #[serde(default)]
+    tls: bool,
+    username: Option<String>,
+    password: Option<Secret<String>>,
+    #[serde(default = "default_client_id")]
+    client_id: String,
+    /// Prefix of the state topics, like `dyndnsd/ipv4`
+    #[serde(default = "default_topic_prefix")]
+    topic_prefix: String,
+    /// Prefix of Home Assistant's discovery topics, usually `homeassistant`.
+    /// Without it, no discovery payloads are published.
+    discovery_prefix: Option<String>,
+    /// Messages of the last successful publish
+    #[serde(skip)]
+    published: Mutex<Option<Vec<(String, String)>>>,
+}
+
+impl Config {
+    /// Publish the state in `cache`, unless it's unchanged since the last
+    /// time. The discovery payloads are published along with the first state.
+    /// Failures are logged, but don't affect the update.
+    pub async fn update(&self, cache: &Cache, outbound: &Outbound) {
+        let state = self.state(cache);
+        let first = {
+            let published = self.published.lock().unwrap_or_else(|p| p.into_inner());
+            if published.as_ref() == Some(&state) {
+                tracing::debug!("MQTT state unchanged, continuing...");
+                return;
+            }
+            published.is_none()
+        };
+        let mut messages = state.clone();
+        if first {
+            messages.extend(self.discovery());
+        }
+        match outbound.call("mqtt", || self.publish(&messages)).await {
+            Ok(()) => *self.published.lock().unwrap_or_else(|p| p.into_inner()) = Some(state),
+            Err(error) => tracing::error!("Failed to publish state to MQTT: {:#}", error),
+        }
+    }
+
+    fn state(&self, cache: &Cache) -> Vec<(String, String)> {
+        let status = match cache.consecutive_failures {
+            0 => "ok",
+            _ => "failing",
+        };
+        let last_update = cache
+            .last_update
+            .map(|timestamp| {
+                humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(timestamp))
+                    .to_string()
+            })
+            .unwrap_or_default();
+        [
+            (
+                "ipv4",
+                cache.v4.map(|addr| addr.to_string()).unwrap_or_default(),
+            ),
+            (
+                "ipv6",
+                cache.v6.map(|addr| addr.to_string()).unwrap_or_default(),
+            ),
+            ("last_update", last_update),
+            ("status", status.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, payload)| (self.topic(name), payload))
+        .collect()
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.topic_prefix, name)
+    }
+
+    /// Configs of a Home Assistant entity for each state topic.
+    fn discovery(&self) -> Vec<(String, String)> {
+        let Some(prefix) = &self.discovery_prefix else {
+            return Vec::new();
+        };
+        let entity = |component: &str, name: &str, title: &str, extra: Value| {
+            let mut config = json!({
+                "name": title,
+                "state_topic": self.topic(name),
+                "unique_id": format!("{}_{}", self.client_id, name),
+                "device": {
+                    "identifiers": [self.client_id],
+                    "name": self.client_id,
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                },
+            });
+            if let (Some(config), Value::Object(extra)) = (config.as_object_mut(), extra) {
+                config.extend(extra);
+            }
+            (
+                format!(
+                    "{}/{}/{}/{}/config",
+                    prefix, component, self.client_id, name
+                ),
+                config.to_string(),
+            )
+        };
+        vec![
+            entity(
+                "sensor",
+                "ipv4",
+                "Public IPv4 address",
+                json!({"icon": "mdi:ip-network"}),
+            ),
+            entity(
+                "sensor",
+                "ipv6",
+                "Public IPv6 address",
+                json!({"icon": "mdi:ip-network"}),
+            ),
+            entity(
+                "sensor",
+                "last_update",
+                "Last DNS update",
+                json!({"device_class": "timestamp"}),
+            ),
+            entity(
+                "binary_sensor",
+                "status",
+                "DNS update",
+                json!({"device_class": "problem", "payload_on": "failing", "payload_off": "ok"}),
+            ),
+        ]
+    }
+
+    /// Connect to the broker, publish the retained messages, and disconnect
+    /// once the broker acknowledged all of them.
+    async fn publish(&self, messages: &[(String, String)]) -> Result<()> {
+        let port = self.port.unwrap_or(if self.tls { 8883 } else { 1883 });
+        let mut options = MqttOptions::new(&self.client_id, &self.host, port);
+        if let Some(username) = &self.username {
+            let password = self.password.as_deref().cloned().unwrap_or_default();
+            options.set_credentials(username, password);
+        }
+        if self.tls {
+            options.set_transport(Transport::tls_with_config(tls_config()?));
+        }
+        // Room for all messages and the disconnect, so queueing them doesn't
+        // wait for the event loop.
+        let (client, mut event_loop) = AsyncClient::new(options, messages.len() + 1);
+        for (topic, payload) in messages {
+            client
+                .publish(topic, QoS::AtLeastOnce, true, payload.clone())
+                .await?;
+        }
+        let mut pending = messages.len();
+        loop {
+            let event = event_loop
+                .poll()
+                .await
+                .with_context(|| format!("Failed to publish to {}", self.host))?;
+            match event {
+                Event::Incoming(Packet::PubAck(_)) => {
+                    pending -= 1;
+                    if pending == 0 {
+                        client.disconnect().await?;
+                    }
+                }
+                Event::Outgoing(Outgoing::Disconnect) => return Ok(()),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// TLS with the webpki roots, like the DNS client uses. The provider is
+/// chosen explicitly, as more than one is compiled in.
+fn tls_config() -> Result<TlsConfiguration> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(config.into())
+}
+
+fn default_client_id() -> String {
+    String::from("dyndnsd")
+}
+
+fn default_topic_prefix() -> String {
+    String::from("dyndnsd")
+}
@@ -0,0 +1,420 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Updates triggered by NetworkManager, which announces changes of the
+//! primary connection and of the addresses of the interfaces on the system
+//! D-Bus. Only as much of D-Bus is spoken as subscribing to those signals
+//! takes: authenticating, saying hello, adding the match rules, and telling
+//! the signals apart from the replies.
+
+use std::{env, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use nix::unistd::getuid;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+/// Where the system bus listens, unless `DBUS_SYSTEM_BUS_ADDRESS` says otherwise
+const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+
+/// How long to wait for further signals before updating, as NetworkManager
+/// announces a change in several steps
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// How long to wait before connecting to the bus again after losing it
+const RECONNECT: Duration = Duration::from_secs(10);
+
+/// The property changes of the NetworkManager manager, e.g. of its primary
+/// connection, and of the IPv4 and IPv6 configs of the active connections
+const MATCH_RULES: &[&str] = &[
+    "type='signal',sender='org.freedesktop.NetworkManager',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='org.freedesktop.NetworkManager'",
+    "type='signal',sender='org.freedesktop.NetworkManager',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='org.freedesktop.NetworkManager.IP4Config'",
+    "type='signal',sender='org.freedesktop.NetworkManager',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='org.freedesktop.NetworkManager.IP6Config'",
+];
+
+/// Largest message the D-Bus specification allows
+const MAX_MESSAGE_LEN: usize = 1 << 27;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+/// Watch NetworkManager, sending to the returned channel whenever the
+/// connection or its addresses changed. The bus is connected to again
+/// whenever the connection to it is lost.
+pub fn watch() -> mpsc::Receiver<()> {
+    let (signals, mut received) = mpsc::channel(16);
+    let (changes, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        loop {
+            if let Err(error) = subscribe(&signals).await {
+                tracing::warn!(
+                    "Not receiving NetworkManager's signals, retrying in {:?}: {:#}",
+                    RECONNECT,
+                    error
+                );
+            }
+            if signals.is_closed() {
+                return;
+            }
+            sleep(RECONNECT).await;
+        }
+    });
+    tokio::spawn(async move {
+        while received.recv().await.is_some() {
+            while let Ok(Some(())) = timeout(SETTLE, received.recv()).await {}
+            tracing::debug!("NetworkManager reported a change");
+            if changes.send(()).await.is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Pass NetworkManager's signals to `signals`, until the bus connection is
+/// lost.
+async fn subscribe(signals: &mpsc::Sender<()>) -> Result<()> {
+    let mut bus = Bus::connect().await?;
+    bus.call("Hello", None).await?;
+    for rule in MATCH_RULES {
+        bus.call("AddMatch", Some(rule)).await?;
+    }
+    tracing::info!("Watching NetworkManager for changes of the connection");
+    loop {
+        let message = bus.read().await?;
+        if message.kind == SIGNAL
+            && message.member.as_deref() == Some("PropertiesChanged")
+            && signals.send(()).await.is_err()
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// A connection to the system bus.
+struct Bus {
+    stream: BufReader<UnixStream>,
+    serial: u32,
+}
+
+/// The header fields of a received message that matter here.
+struct Message {
+    kind: u8,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+}
+
+impl Bus {
+    async fn connect() -> Result<Self> {
+        let path = system_bus()?;
+        let mut stream = UnixStream::connect(&path).await.with_context(|| {
+            format!("Failed to connect to the system bus at {}", path.display())
+        })?;
+        // The bus checks the uid against the credentials of the socket.
+        let uid: String = getuid()
+            .as_raw()
+            .to_string()
+            .bytes()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        stream
+            .write_all(format!("\0AUTH EXTERNAL {uid}\r\n").as_bytes())
+            .await?;
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        if !line.starts_with("OK ") {
+            bail!("The system bus refused the authentication: {}", line.trim());
+        }
+        stream.get_mut().write_all(b"BEGIN\r\n").await?;
+        Ok(Self { stream, serial: 0 })
+    }
+
+    /// Call `member` of the bus itself, with an optional string argument,
+    /// and wait for the reply.
+    async fn call(&mut self, member: &str, arg: Option<&str>) -> Result<()> {
+        self.serial += 1;
+        let serial = self.serial;
+        self.stream
+            .get_mut()
+            .write_all(&method_call(serial, member, arg))
+            .await?;
+        loop {
+            let message = self.read().await?;
+            if message.reply_serial != Some(serial) {
+                // Like the NameAcquired signal sent after the hello.
+                continue;
+            }
+            match message.kind {
+                METHOD_RETURN => return Ok(()),
+                ERROR => bail!(
+                    "The system bus failed {}: {}",
+                    member,
+                    message.error_name.unwrap_or_default()
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    /// The next message received on the bus.
+    async fn read(&mut self) -> Result<Message> {
+        read_message(&mut self.stream).await
+    }
+}
+
+/// Read the next message from `reader`, skipping its body.
+async fn read_message(reader: &mut (impl AsyncRead + Unpin)) -> Result<Message> {
+    let mut fixed = [0; 16];
+    reader
+        .read_exact(&mut fixed)
+        .await
+        .context("Lost the connection to the system bus")?;
+    let little_endian = match fixed[0] {
+        b'l' => true,
+        b'B' => false,
+        other => bail!("Unknown endianness {:?} of a D-Bus message", other as char),
+    };
+    let mut header = Cursor {
+        bytes: &fixed,
+        pos: 0,
+        little_endian,
+    };
+    header.pos = 4;
+    let body_len = header.u32()? as usize;
+    header.pos = 12;
+    let fields_len = header.u32()? as usize;
+    if body_len + fields_len > MAX_MESSAGE_LEN {
+        bail!(
+            "A D-Bus message of {} bytes is too long",
+            body_len + fields_len
+        );
+    }
+    // The body starts at the next multiple of 8 after the header fields.
+    let mut rest = vec![0; fields_len.next_multiple_of(8) + body_len];
+    reader
+        .read_exact(&mut rest)
+        .await
+        .context("Lost the connection to the system bus")?;
+    let mut message = Message {
+        kind: fixed[1],
+        member: None,
+        error_name: None,
+        reply_serial: None,
+    };
+    let mut fields = Cursor {
+        bytes: &rest[..fields_len],
+        pos: 0,
+        little_endian,
+    };
+    // The fields start at offset 16, so their alignment within the
+    // message is the same as within the array.
+    while fields.pos < fields_len {
+        fields.align(8);
+        let code = fields.byte()?;
+        let signature = fields.signature()?;
+        match signature.as_str() {
+            "s" | "o" => {
+                let value = fields.string()?;
+                match code {
+                    3 => message.member = Some(value),
+                    4 => message.error_name = Some(value),
+                    _ => {}
+                }
+            }
+            "u" => {
+                let value = fields.u32()?;
+                if code == 5 {
+                    message.reply_serial = Some(value);
+                }
+            }
+            "g" => {
+                fields.signature()?;
+            }
+            other => bail!("Unexpected type {} of a D-Bus header field", other),
+        }
+    }
+    Ok(message)
+}
+
+/// The path of the system bus socket.
+fn system_bus() -> Result<PathBuf> {
+    let Ok(address) = env::var("DBUS_SYSTEM_BUS_ADDRESS") else {
+        return Ok(PathBuf::from(SYSTEM_BUS));
+    };
+    // Of the possibly several addresses, the first Unix socket path is used.
+    address
+        .split(';')
+        .filter_map(|address| address.strip_prefix("unix:"))
+        .flat_map(|options| options.split(','))
+        .find_map(|option| option.strip_prefix("path="))
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("No Unix socket path in DBUS_SYSTEM_BUS_ADDRESS {address:?}"))
+}
+
+/// A little endian method call of `member` of the bus itself.
+fn method_call(serial: u32, member: &str, arg: Option<&str>) -> Vec<u8> {
+    let mut fields = Vec::new();
+    let mut field = |code: u8, signature: u8, value: &str| {
+        fields.resize(fields.len().next_multiple_of(8), 0);
+        fields.extend([code, 1, signature, 0]);
+        if signature == b'g' {
+            fields.push(value.len() as u8);
+        } else {
+            fields.extend((value.len() as u32).to_le_bytes());
+        }
+        fields.extend(value.as_bytes());
+        fields.push(0);
+    };
+    field(1, b'o', "/org/freedesktop/DBus");
+    field(2, b's', "org.freedesktop.DBus");
+    field(3, b's', member);
+    field(6, b's', "org.freedesktop.DBus");
+    let mut body = Vec::new();
+    if let Some(arg) = arg {
+        field(8, b'g', "s");
+        body.extend((arg.len() as u32).to_le_bytes());
+        body.extend(arg.as_bytes());
+        body.push(0);
+    }
+    let mut message = vec![b'l', METHOD_CALL, 0, 1];
+    message.extend((body.len() as u32).to_le_bytes());
+    message.extend(serial.to_le_bytes());
+    message.extend((fields.len() as u32).to_le_bytes());
+    message.extend(&fields);
+    message.resize(message.len().next_multiple_of(8), 0);
+    message.extend(body);
+    message
+}
+
+/// Reads the values of D-Bus' wire format, failing at the end of the bytes.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    little_endian: bool,
+}
+
+impl Cursor<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("Truncated D-Bus message"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn align(&mut self, alignment: usize) {
+        self.pos = self.pos.next_multiple_of(alignment);
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.align(4);
+        let little_endian = self.little_endian;
+        let bytes = self.take(4)?.try_into().expect("4 bytes");
+        Ok(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let string = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(string)
+    }
+
+    fn signature(&mut self) -> Result<String> {
+        let len = self.byte()? as usize;
+        let signature = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULE: &str = MATCH_RULES[0];
+
+    #[tokio::test]
+    async fn method_call_round_trip() {
+        let mut bytes = method_call(1, "Hello", None);
+        assert_eq!(bytes.len() % 8, 0);
+        bytes.extend(method_call(2, "AddMatch", Some(RULE)));
+        let mut reader = bytes.as_slice();
+        let hello = read_message(&mut reader).await.unwrap();
+        assert_eq!(hello.kind, METHOD_CALL);
+        assert_eq!(hello.member.as_deref(), Some("Hello"));
+        assert_eq!(hello.reply_serial, None);
+        let add_match = read_message(&mut reader).await.unwrap();
+        assert_eq!(add_match.kind, METHOD_CALL);
+        assert_eq!(add_match.member.as_deref(), Some("AddMatch"));
+        // The padding and the body are skipped, up to the end of the message.
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn big_endian_reply() {
+        // The reply to the hello: REPLY_SERIAL, DESTINATION and SIGNATURE
+        // fields, padded to the next multiple of 8 before each of them and
+        // before the body holding the unique name.
+        let mut fields = vec![5, 1, b'u', 0, 0, 0, 0, 42];
+        fields.extend([6, 1, b's', 0, 0, 0, 0, 4]);
+        fields.extend(b":1.7\0");
+        fields.extend([0; 3]);
+        fields.extend([8, 1, b'g', 0, 1, b's', 0]);
+        let mut bytes = vec![b'B', METHOD_RETURN, 1, 1, 0, 0, 0, 9, 0, 0, 0, 3];
+        bytes.extend((fields.len() as u32).to_be_bytes());
+        bytes.extend(&fields);
+        bytes.resize(bytes.len().next_multiple_of(8), 0);
+        bytes.extend([0, 0, 0, 4]);
+        bytes.extend(b":1.7\0");
+        // A following message is still read from the right offset.
+        bytes.extend(method_call(2, "AddMatch", Some(RULE)));
+        let mut reader = bytes.as_slice();
+        let reply = read_message(&mut reader).await.unwrap();
+        assert_eq!(reply.kind, METHOD_RETURN);
+        assert_eq!(reply.reply_serial, Some(42));
+        assert_eq!(reply.member, None);
+        let next = read_message(&mut reader).await.unwrap();
+        assert_eq!(next.member.as_deref(), Some("AddMatch"));
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_messages() {
+        let message = method_call(1, "Hello", None);
+        let mut truncated = &message[..message.len() - 4];
+        assert!(read_message(&mut truncated).await.is_err());
+        let mut endianness = message.clone();
+        endianness[0] = b'x';
+        assert!(read_message(&mut endianness.as_slice()).await.is_err());
+        // A field claiming to be longer than the fields array
+        let mut overlong = message.clone();
+        overlong[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_message(&mut overlong.as_slice()).await.is_err());
+    }
+}
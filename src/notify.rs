@@ -0,0 +1,181 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications about changed records and failed updates, sent to the
+//! configured channels.
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::outbound::Outbound;
+
+mod discord;
+mod email;
+mod gotify;
+mod matrix;
+mod ntfy;
+mod telegram;
+mod webhook;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Notifications {
+    /// JSON payloads POSTed to user supplied URLs
+    #[serde(default)]
+    webhook: Vec<webhook::Config>,
+    /// Push notifications through an ntfy topic
+    ntfy: Option<ntfy::Config>,
+    /// Messages to a Matrix room
+    matrix: Option<matrix::Config>,
+    /// Messages from a Telegram bot
+    telegram: Option<telegram::Config>,
+    /// Mails sent through an SMTP server
+    email: Option<email::Config>,
+    /// Push notifications through a Gotify server
+    gotify: Option<gotify::Config>,
+    /// Embeds posted through a Discord webhook
+    discord: Option<discord::Config>,
+}
+
+/// A record that was changed, or failed to be changed.
+#[derive(Serialize, Debug)]
+pub struct Event {
+    pub record: String,
+    pub record_type: String,
+    /// ID of the provider the record is published to
+    pub provider: String,
+    pub old: Option<String>,
+    pub new: String,
+    pub timestamp: String,
+    pub success: bool,
+    /// The error that caused the update to fail, if it failed
+    pub error: Option<String>,
+    /// Unix timestamp of the first failed update, while the update is failing
+    #[serde(skip)]
+    pub failing_since: Option<u64>,
+    /// Whether the update failed before already. Only alerts about
+    /// persisting failures consider these events.
+    #[serde(skip)]
+    pub retry: bool,
+}
+
+impl Event {
+    pub fn new(
+        record: String,
+        record_type: String,
+        provider: String,
+        old: Option<String>,
+        new: String,
+        result: &Result<()>,
+    ) -> Self {
+        Self {
+            record,
+            record_type,
+            provider,
+            old,
+            new,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|error| format!("{error:#}")),
+            failing_since: None,
+            retry: false,
+        }
+    }
+
+    /// Short summary, for channels with a separate title.
+    pub fn title(&self) -> String {
+        match self.success {
+            true => format!("{} {} changed", self.record, self.record_type),
+            false => format!("Failed to update {} {}", self.record, self.record_type),
+        }
+    }
+
+    /// Description of the event in plain text.
+    pub fn message(&self) -> String {
+        match (&self.old, &self.error) {
+            (_, Some(error)) => format!(
+                "Setting the {} record of {} to {} on {} failed: {}",
+                self.record_type, self.record, self.new, self.provider, error
+            ),
+            (Some(old), None) => format!(
+                "The {} record of {} changed from {} to {} on {}",
+                self.record_type, self.record, old, self.new, self.provider
+            ),
+            (None, None) => format!(
+                "The {} record of {} was set to {} on {}",
+                self.record_type, self.record, self.new, self.provider
+            ),
+        }
+    }
+}
+
+impl Notifications {
+    /// Send the event to every configured channel. Failures are logged, but
+    /// don't affect the update.
+    pub async fn send(&self, event: &Event, outbound: &Outbound) {
+        if let Some(email) = &self.email {
+            match email.compose(event) {
+                Ok(Some((message, alert))) => {
+                    let sent = deliver(outbound, "email", || email.send(&message)).await;
+                    // Otherwise, the alert is sent again with the next failure.
+                    if let Some(key) = alert.filter(|_| sent) {
+                        email.mark_alerted(key);
+                    }
+                }
+                Ok(None) => (),
+                Err(error) => tracing::error!("Failed to compose email notification: {:#}", error),
+            }
+        }
+        if event.retry {
+            return;
+        }
+        for webhook in &self.webhook {
+            deliver(outbound, "webhook", || webhook.send(event)).await;
+        }
+        if let Some(ntfy) = &self.ntfy {
+            deliver(outbound, "ntfy", || ntfy.send(event)).await;
+        }
+        if let Some(matrix) = &self.matrix {
+            let transaction_id = matrix::transaction_id();
+            deliver(outbound, "matrix", || matrix.send(event, &transaction_id)).await;
+        }
+        if let Some(telegram) = &self.telegram {
+            match telegram.admit() {
+                Some(suppressed) => {
+                    deliver(outbound, "telegram", || telegram.send(event, suppressed)).await;
+                }
+                None => tracing::debug!("Dropped telegram notification because of the rate limit"),
+            }
+        }
+        if let Some(gotify) = &self.gotify {
+            deliver(outbound, "gotify", || gotify.send(event)).await;
+        }
+        if let Some(discord) = &self.discord {
+            deliver(outbound, "discord", || discord.send(event)).await;
+        }
+    }
+}
+
+/// Send through `channel`, logging failures. Returns whether it was sent.
+async fn deliver<F, Fut>(outbound: &Outbound, channel: &str, send: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    match outbound.call(channel, send).await {
+        Ok(()) => true,
+        Err(error) => {
+            tracing::error!("Failed to send {} notification: {:#}", channel, error);
+            false
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications posted as embeds through a Discord webhook.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::Event;
+use crate::{proxy, secret::Secret};
+
+const GREEN: u32 = 0x2ecc71;
+const RED: u32 = 0xe74c3c;
+/// Maximum length of an embed field value
+const FIELD_LIMIT: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// URL of the webhook, which includes its token
+    webhook_url: Secret<String>,
+    /// Name the messages are posted under, instead of the webhook's
+    username: Option<String>,
+}
+
+impl Config {
+    pub async fn send(&self, event: &Event) -> Result<()> {
+        let mut fields = vec![
+            field("Record", &format!("{} {}", event.record, event.record_type)),
+            field("Provider", &event.provider),
+            field("Old", event.old.as_deref().unwrap_or("-")),
+            field("New", &event.new),
+        ];
+        if let Some(error) = &event.error {
+            fields.push(json!({"name": "Error", "value": truncate(error)}));
+        }
+        let mut payload = json!({
+            "embeds": [{
+                "title": event.title(),
+                "color": if event.success { GREEN } else { RED },
+                "fields": fields,
+                "timestamp": event.timestamp,
+            }],
+        });
+        if let Some(username) = &self.username {
+            payload["username"] = json!(username);
+        }
+        // The webhook token is part of the URL, so it's kept out of errors.
+        proxy::client()
+            .post(self.webhook_url.as_str())
+            .json(&payload)
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .context("Failed to send request to Discord")?
+            .error_for_status()
+            .map_err(reqwest::Error::without_url)?;
+        Ok(())
+    }
+}
+
+fn field(name: &str, value: &str) -> serde_json::Value {
+    json!({"name": name, "value": truncate(value), "inline": true})
+}
+
+fn truncate(value: &str) -> String {
+    match value.char_indices().nth(FIELD_LIMIT - 1) {
+        Some((end, _)) => format!("{}…", &value[..end]),
+        None => value.to_string(),
+    }
+}
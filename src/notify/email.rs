@@ -0,0 +1,143 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications sent as mails through an SMTP server.
+
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    message::{Mailbox, header::ContentType},
+    transport::smtp::authentication::Credentials,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+
+use super::Event;
+use crate::{duration::HumanDuration, secret::Secret};
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    server: String,
+    /// Defaults to 587 for STARTTLS, 465 for implicit TLS and 25 without TLS
+    port: Option<u16>,
+    #[serde(default)]
+    tls: Tls,
+    username: Option<String>,
+    password: Option<Secret<String>>,
+    #[serde_as(as = "DisplayFromStr")]
+    from: Mailbox,
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    to: Vec<Mailbox>,
+    /// How long updates of a record have to keep failing before an alert is
+    /// sent
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_alert_after")]
+    alert_after: Duration,
+    /// Records that were alerted about and haven't been updated since
+    #[serde(skip)]
+    alerted: Mutex<HashSet<String>>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Tls {
+    #[default]
+    Starttls,
+    Implicit,
+    /// Plain text, only for relays on the same host or network
+    None,
+}
+
+impl Config {
+    /// The mail for the event, if there is one: a summary of every change,
+    /// and a single alert once updates have been failing for `alert_after`.
+    /// For alerts, the key to pass to [`Config::mark_alerted`] once the mail is
+    /// sent is returned as well.
+    pub fn compose(&self, event: &Event) -> Result<Option<(Message, Option<String>)>> {
+        let key = format!("{}/{}/{}", event.provider, event.record, event.record_type);
+        let mut alerted = self.alerted.lock().unwrap_or_else(|p| p.into_inner());
+        let mut alert = None;
+        let (subject, body) = match event.failing_since {
+            None => {
+                alerted.remove(&key);
+                (event.title(), event.message())
+            }
+            Some(failing_since) => {
+                let failing_for = SystemTime::now()
+                    .duration_since(UNIX_EPOCH + Duration::from_secs(failing_since))
+                    .unwrap_or_default();
+                if failing_for < self.alert_after || alerted.contains(&key) {
+                    return Ok(None);
+                }
+                alert = Some(key);
+                let failing_for =
+                    humantime::format_duration(Duration::from_secs(failing_for.as_secs()));
+                (
+                    format!("Alert: {} for {}", event.title(), failing_for),
+                    format!(
+                        "{}\n\nUpdates have been failing for {}. No further alert is sent \
+                         until the record is updated.",
+                        event.message(),
+                        failing_for
+                    ),
+                )
+            }
+        };
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = message.body(body).context("Failed to build mail")?;
+        Ok(Some((message, alert)))
+    }
+
+    /// Remember that the alert with `key` was sent, so there is no second one
+    /// until the record is updated.
+    pub fn mark_alerted(&self, key: String) {
+        let mut alerted = self.alerted.lock().unwrap_or_else(|p| p.into_inner());
+        alerted.insert(key);
+    }
+
+    pub async fn send(&self, message: &Message) -> Result<()> {
+        let mut transport = match self.tls {
+            Tls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.server)?,
+            Tls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.server)?,
+            Tls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.server),
+        };
+        if let Some(port) = self.port {
+            transport = transport.port(port);
+        }
+        if let Some(username) = &self.username {
+            let password = self.password.as_deref().cloned().unwrap_or_default();
+            transport = transport.credentials(Credentials::new(username.clone(), password));
+        }
+        transport
+            .build()
+            .send(message.clone())
+            .await
+            .with_context(|| format!("Failed to send mail through {}", self.server))?;
+        Ok(())
+    }
+}
+
+fn default_alert_after() -> Duration {
+    Duration::from_secs(60 * 60)
+}
@@ -0,0 +1,52 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications pushed through a Gotify server.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::Event;
+use crate::{proxy, secret::Secret};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// Base URL of the server, like `https://gotify.example.com`
+    url: String,
+    /// Token of the application the messages are sent as
+    token: Secret<String>,
+    /// Priority of all notifications, from 0 to 10. Without it, changes are
+    /// sent with priority 5 and failures with 8.
+    priority: Option<u8>,
+}
+
+impl Config {
+    pub async fn send(&self, event: &Event) -> Result<()> {
+        let priority = match event.success {
+            true => self.priority.unwrap_or(5),
+            false => self.priority.unwrap_or(8),
+        };
+        proxy::client()
+            .post(format!("{}/message", self.url.trim_end_matches('/')))
+            .header("X-Gotify-Key", self.token.as_str())
+            .json(&json!({
+                "title": event.title(),
+                "message": event.message(),
+                "priority": priority,
+            }))
+            .send()
+            .await
+            .context("Failed to send request to Gotify")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications sent as messages to a Matrix room.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::Event;
+use crate::{proxy, secret::Secret};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// Client-server API base URL, like `https://matrix.example.com`
+    homeserver: String,
+    access_token: Secret<String>,
+    /// ID of the room, like `!abcdefg:example.com`, which the user has to
+    /// be a member of
+    room_id: String,
+}
+
+/// ID of a new message, kept for its retries, so the homeserver can drop
+/// duplicates.
+pub fn transaction_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    format!("dyndnsd-{nanos}")
+}
+
+impl Config {
+    pub async fn send(&self, event: &Event, transaction_id: &str) -> Result<()> {
+        let mut url = Url::parse(&self.homeserver).context("Invalid homeserver URL")?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid homeserver URL"))?
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                &self.room_id,
+                "send",
+                "m.room.message",
+                transaction_id,
+            ]);
+        proxy::client()
+            .put(url)
+            .bearer_auth(self.access_token.as_str())
+            .json(&json!({
+                "msgtype": "m.notice",
+                "body": event.message(),
+            }))
+            .send()
+            .await
+            .context("Failed to send message to Matrix")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
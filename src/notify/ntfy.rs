@@ -0,0 +1,78 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications published to an ntfy topic.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Event;
+use crate::{proxy, secret::Secret};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// URL of the topic, like `https://ntfy.sh/mytopic`
+    topic_url: String,
+    /// Access token for protected topics
+    token: Option<Secret<String>>,
+    /// Priority of all notifications. Without it, changes are sent with the
+    /// default priority and failures with a high one.
+    priority: Option<Priority>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Priority {
+    Min,
+    Low,
+    Default,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    fn header(self) -> &'static str {
+        match self {
+            Priority::Min => "min",
+            Priority::Low => "low",
+            Priority::Default => "default",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        }
+    }
+}
+
+impl Config {
+    pub async fn send(&self, event: &Event) -> Result<()> {
+        let (priority, tags) = match event.success {
+            true => (
+                self.priority.unwrap_or(Priority::Default),
+                "globe_with_meridians",
+            ),
+            false => (self.priority.unwrap_or(Priority::High), "warning"),
+        };
+        let mut request = proxy::client()
+            .post(&self.topic_url)
+            .header("Title", event.title())
+            .header("Priority", priority.header())
+            .header("Tags", tags)
+            .body(event.message());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token.as_str());
+        }
+        request
+            .send()
+            .await
+            .context("Failed to send request to ntfy")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications sent as messages by a Telegram bot.
+
+use std::{
+    mem,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_with::serde_as;
+
+use super::Event;
+use crate::{duration::HumanDuration, proxy, secret::Secret};
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    bot_token: Secret<String>,
+    /// Numeric ID of the chat, or `@username` of a channel
+    chat_id: String,
+    /// Base URL of the Bot API, for self-hosted API servers
+    #[serde(default = "default_api_url")]
+    api_url: String,
+    /// Minimum time between two messages. Notifications within it are
+    /// dropped and counted in the next message.
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_min_interval")]
+    min_interval: Duration,
+    #[serde(skip)]
+    limit: Mutex<Limit>,
+}
+
+#[derive(Default, Debug)]
+struct Limit {
+    last: Option<Instant>,
+    suppressed: usize,
+}
+
+impl Config {
+    /// Whether a message may be sent now. If it may, returns how many
+    /// notifications were dropped since the last message.
+    pub fn admit(&self) -> Option<usize> {
+        let mut limit = self.limit.lock().unwrap_or_else(|p| p.into_inner());
+        let now = Instant::now();
+        if limit
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.min_interval)
+        {
+            limit.suppressed += 1;
+            return None;
+        }
+        limit.last = Some(now);
+        Some(mem::take(&mut limit.suppressed))
+    }
+
+    pub async fn send(&self, event: &Event, suppressed: usize) -> Result<()> {
+        let mut text = format!("{}\n{}", event.title(), event.message());
+        if suppressed > 0 {
+            text.push_str(&format!(
+                "\n({suppressed} earlier notifications were dropped)"
+            ));
+        }
+        let url = format!(
+            "{}/bot{}/sendMessage",
+            self.api_url.trim_end_matches('/'),
+            self.bot_token.as_str()
+        );
+        // The bot token is part of the URL, so it's kept out of errors.
+        proxy::client()
+            .post(url)
+            .json(&json!({
+                "chat_id": self.chat_id,
+                "text": text,
+            }))
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .context("Failed to send message to Telegram")?
+            .error_for_status()
+            .map_err(reqwest::Error::without_url)?;
+        Ok(())
+    }
+}
+
+fn default_api_url() -> String {
+    String::from("https://api.telegram.org")
+}
+
+fn default_min_interval() -> Duration {
+    Duration::from_secs(60)
+}
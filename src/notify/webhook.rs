@@ -0,0 +1,36 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Notifications POSTed as JSON to a user supplied URL.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Event;
+use crate::proxy;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    url: String,
+}
+
+impl Config {
+    pub async fn send(&self, event: &Event) -> Result<()> {
+        proxy::client()
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await
+            .context("Failed to send request")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Timeouts, retries, rate limiting and metrics shared by all outbound calls,
+//! so they behave the same for every detection source and provider.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::duration::HumanDuration;
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Outbound {
+    /// Time a single attempt may take before it's aborted
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_timeout")]
+    timeout: Duration,
+    /// Number of times a failed call is retried
+    #[serde(default)]
+    retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_backoff")]
+    backoff: Duration,
+    /// Minimum time between two calls to the same target
+    #[serde_as(as = "Option<HumanDuration>")]
+    #[serde(default)]
+    min_interval: Option<Duration>,
+    #[serde(skip)]
+    targets: Mutex<BTreeMap<String, Target>>,
+}
+
+/// Settings overriding those of [`Outbound`] for a group of calls.
+#[serde_as]
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Overrides {
+    #[serde_as(as = "Option<HumanDuration>")]
+    #[serde(default)]
+    timeout: Option<Duration>,
+    #[serde(default)]
+    retries: Option<u32>,
+    #[serde_as(as = "Option<HumanDuration>")]
+    #[serde(default)]
+    backoff: Option<Duration>,
+}
+
+#[derive(Default, Debug)]
+struct Target {
+    last_call: Option<Instant>,
+    stats: Stats,
+}
+
+/// Counters of the calls to a single target.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Stats {
+    pub calls: u64,
+    pub failures: u64,
+    pub retries: u64,
+    pub timeouts: u64,
+    pub last_latency_ms: u64,
+}
+
+impl Default for Outbound {
+    fn default() -> Self {
+        Self {
+            timeout: default_timeout(),
+            retries: 0,
+            backoff: default_backoff(),
+            min_interval: None,
+            targets: Mutex::default(),
+        }
+    }
+}
+
+impl Outbound {
+    /// Call `f` for `target`, e.g. a provider or detection source, applying
+    /// the rate limit, timeout and retries.
+    pub async fn call<T, F, Fut>(&self, target: &str, f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.call_with(target, &Overrides::default(), f).await
+    }
+
+    /// Like [`Self::call`], with the settings given in `overrides` taking
+    /// precedence.
+    pub async fn call_with<T, F, Fut>(
+        &self,
+        target: &str,
+        overrides: &Overrides,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let timeout = overrides.timeout.unwrap_or(self.timeout);
+        let retries = overrides.retries.unwrap_or(self.retries);
+        let backoff = overrides.backoff.unwrap_or(self.backoff);
+        let mut attempt = 0;
+        loop {
+            self.wait_for_turn(target).await;
+            let start = Instant::now();
+            let result = match tokio::time::timeout(timeout, f()).await {
+                Ok(result) => result,
+                Err(_) => {
+                    self.update_stats(target, |stats| stats.timeouts += 1);
+                    Err(anyhow!("Timed out after {:?}", timeout))
+                }
+            };
+            let latency = start.elapsed();
+            tracing::debug!(
+                "{} attempt {} took {:?}, {}",
+                target,
+                attempt + 1,
+                latency,
+                if result.is_ok() {
+                    "succeeded"
+                } else {
+                    "failed"
+                }
+            );
+            self.update_stats(target, |stats| {
+                stats.calls += 1;
+                stats.last_latency_ms = latency.as_millis() as u64;
+                if result.is_err() {
+                    stats.failures += 1;
+                }
+            });
+            match result {
+                Err(error) if attempt < retries => {
+                    let delay = backoff.saturating_mul(2u32.saturating_pow(attempt));
+                    tracing::warn!(
+                        "Call to {} failed, retrying in {:?}: {:#}",
+                        target,
+                        delay,
+                        error
+                    );
+                    self.update_stats(target, |stats| stats.retries += 1);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Counters of all targets called so far.
+    pub fn stats(&self) -> BTreeMap<String, Stats> {
+        self.targets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .map(|(name, target)| (name.clone(), target.stats.clone()))
+            .collect()
+    }
+
+    async fn wait_for_turn(&self, target: &str) {
+        let Some(min_interval) = self.min_interval else {
+            return;
+        };
+        let wait = {
+            let mut targets = self
+                .targets
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let state = targets.entry(target.to_string()).or_default();
+            let now = Instant::now();
+            let next = state
+                .last_call
+                .map(|last_call| last_call + min_interval)
+                .filter(|next| *next > now);
+            state.last_call = Some(next.unwrap_or(now));
+            next.map(|next| next - now)
+        };
+        if let Some(wait) = wait {
+            tracing::debug!("Rate limiting {}, waiting {:?}", target, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn update_stats(&self, target: &str, update: impl FnOnce(&mut Stats)) {
+        let mut targets = self
+            .targets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        update(&mut targets.entry(target.to_string()).or_default().stats);
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_backoff() -> Duration {
+    Duration::from_secs(1)
+}
@@ -0,0 +1,447 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+mod azure;
+mod dynu;
+mod dynv6;
+mod freedns;
+mod gcloud;
+mod he;
+mod inwx;
+mod linode;
+mod namecheap;
+mod netcup;
+mod njalla;
+mod nsupdate_info;
+mod porkbun;
+mod scaleway;
+mod vultr;
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::{Name, RData, RecordType};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::dns;
+
+/// DNS provider configuration, selected by the `provider` key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum Config {
+    Rfc2136(Box<dns::Config>),
+    Porkbun(porkbun::Config),
+    Dynv6(dynv6::Config),
+    Namecheap(namecheap::Config),
+    Inwx(inwx::Config),
+    Njalla(njalla::Config),
+    Linode(linode::Config),
+    Vultr(vultr::Config),
+    Scaleway(scaleway::Config),
+    GoogleCloudDns(gcloud::Config),
+    Azure(azure::Config),
+    Freedns(freedns::Config),
+    Dynu(dynu::Config),
+    Netcup(netcup::Config),
+    He(he::Config),
+    NsupdateInfo(nsupdate_info::Config),
+}
+
+impl Config {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Config::Rfc2136(_) => "rfc2136",
+            Config::Porkbun(_) => "porkbun",
+            Config::Dynv6(_) => "dynv6",
+            Config::Namecheap(_) => "namecheap",
+            Config::Inwx(_) => "inwx",
+            Config::Njalla(_) => "njalla",
+            Config::Linode(_) => "linode",
+            Config::Vultr(_) => "vultr",
+            Config::Scaleway(_) => "scaleway",
+            Config::GoogleCloudDns(_) => "google_cloud_dns",
+            Config::Azure(_) => "azure",
+            Config::Freedns(_) => "freedns",
+            Config::Dynu(_) => "dynu",
+            Config::Netcup(_) => "netcup",
+            Config::He(_) => "he",
+            Config::NsupdateInfo(_) => "nsupdate_info",
+        }
+    }
+
+    /// Non-secret identifier of the credential used for `record`, if the
+    /// provider has one.
+    pub fn credential_name(&self, record: &Name) -> Option<String> {
+        match self {
+            Config::Rfc2136(config) => config.credential_name(record),
+            Config::Inwx(config) => Some(config.username().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Whether [`Self::check_update_permission`] is supported by the provider.
+    pub fn can_check_update_permission(&self) -> bool {
+        matches!(self, Config::Rfc2136(_))
+    }
+
+    /// Whether updates only apply to records still holding what they were
+    /// last set to.
+    pub fn checks_prerequisites(&self) -> bool {
+        matches!(self, Config::Rfc2136(config) if config.prerequisites())
+    }
+
+    /// Whether records other than A and AAAA can be set with the provider.
+    pub fn can_set_records(&self) -> bool {
+        matches!(self, Config::Rfc2136(_))
+    }
+
+    /// Whether [`Self::get_records`] is supported by the provider.
+    pub fn can_get_records(&self) -> bool {
+        matches!(self, Config::Rfc2136(_))
+    }
+
+    /// Files the credentials are read from, instead of being part of the config.
+    pub fn secret_files(&self) -> Vec<&Path> {
+        match self {
+            Config::Rfc2136(config) => config.secret_files(),
+            Config::GoogleCloudDns(config) => vec![config.credentials_file()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// File with the DNSSEC trust anchor answers are validated against.
+    pub fn trust_anchor(&self) -> Option<&Path> {
+        match self {
+            Config::Rfc2136(config) => config.trust_anchor(),
+            _ => None,
+        }
+    }
+
+    /// Whether updates are sent by running a command.
+    pub fn runs_commands(&self) -> bool {
+        matches!(self, Config::Rfc2136(config) if config.uses_nsupdate())
+    }
+
+    /// Check the parts of the config serde can't, like whether keys can be
+    /// loaded, without contacting the provider.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => config.validate(),
+            Config::Dynv6(config) => config.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the provider has credentials for `record`.
+    pub fn validate_record(&self, record: &Name) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => config.validate_record(record),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check whether the credentials may update the given record, without changing it.
+    pub async fn check_update_permission(
+        &self,
+        record_type: RecordType,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => {
+                config
+                    .check_update_permission(record_type, name, origin)
+                    .await
+            }
+            other => bail!(
+                "Permission checks are not supported for the {} provider",
+                other.name()
+            ),
+        }
+    }
+
+    /// Check that the clock is close enough to the provider's for it to accept
+    /// signed updates of `record`. Only RFC 2136 signatures are time bound.
+    pub async fn check_clock(&self, record: &Name, origin: Name) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => config.check_clock(record, origin).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Records of the given name and type, as currently published by the provider.
+    pub async fn get_records(
+        &self,
+        record_type: RecordType,
+        name: Name,
+    ) -> anyhow::Result<Vec<RData>> {
+        match self {
+            Config::Rfc2136(config) => config.get_records(record_type, name).await,
+            other => bail!(
+                "Reading records is not supported for the {} provider",
+                other.name()
+            ),
+        }
+    }
+
+    /// Replace all records of the given name and type with `rdata`. Every
+    /// provider supports A and AAAA records, other types only RFC 2136.
+    pub async fn set_record(&self, rdata: RData, name: Name, origin: Name) -> anyhow::Result<()> {
+        match (self, rdata) {
+            (Config::Rfc2136(config), rdata) => config.set_record(rdata, name, origin).await,
+            (config, RData::A(addr)) => config.set_ipv4(addr.0, name, origin).await,
+            (config, RData::AAAA(addr)) => config.set_ipv6(addr.0, name, origin).await,
+            (other, rdata) => bail!(
+                "Setting {} records is not supported for the {} provider",
+                rdata.record_type(),
+                other.name()
+            ),
+        }
+    }
+
+    /// Replace all records of the given name and type with `rdatas`. Only RFC
+    /// 2136 supports publishing more than one record.
+    pub async fn set_records(
+        &self,
+        mut rdatas: Vec<RData>,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        match (self, rdatas.len()) {
+            (Config::Rfc2136(config), _) => config.set_records(rdatas, name, origin).await,
+            (config, 1) => config.set_record(rdatas.remove(0), name, origin).await,
+            (other, _) => bail!(
+                "Publishing multiple records is not supported for the {} provider",
+                other.name()
+            ),
+        }
+    }
+
+    /// Replace all records of the given type at `name` and its `aliases` with
+    /// `rdatas`. RFC 2136 replaces them in a single UPDATE, only applied if
+    /// `name` holds the `expected` records with prerequisites enabled. The
+    /// other providers set one name after the other.
+    pub async fn set_records_with_aliases(
+        &self,
+        rdatas: Vec<RData>,
+        name: Name,
+        aliases: &[Name],
+        origin: Name,
+        expected: Option<&[RData]>,
+    ) -> anyhow::Result<()> {
+        if let Config::Rfc2136(config) = self {
+            let names: Vec<_> = std::iter::once(name)
+                .chain(aliases.iter().cloned())
+                .collect();
+            return config
+                .set_records_at(rdatas, &names, origin, expected)
+                .await;
+        }
+        for name in std::iter::once(name).chain(aliases.iter().cloned()) {
+            self.set_records(rdatas.clone(), name.clone(), origin.clone())
+                .await
+                .with_context(|| format!("Failed to set {name}"))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the changes of all `records` can be sent together with
+    /// [`Self::set_batch`].
+    pub fn can_batch(&self, records: &[&Name]) -> bool {
+        matches!(self, Config::Rfc2136(config) if config.can_batch(records))
+    }
+
+    /// Apply all `replacements` in a single UPDATE.
+    pub async fn set_batch(
+        &self,
+        replacements: Vec<dns::Replacement>,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => config.set_batch(replacements, origin).await,
+            other => bail!(
+                "Batched updates are not supported for the {} provider",
+                other.name()
+            ),
+        }
+    }
+
+    /// Delete all records of the given name and type.
+    pub async fn delete_records(
+        &self,
+        record_type: RecordType,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => config.delete_records(record_type, name, origin).await,
+            other => bail!(
+                "Deleting records is not supported for the {} provider",
+                other.name()
+            ),
+        }
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Porkbun(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Dynv6(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Namecheap(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Inwx(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Njalla(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Linode(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Vultr(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Scaleway(config) => config.set_ipv4(addr, name, origin).await,
+            Config::GoogleCloudDns(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Azure(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Freedns(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Dynu(config) => config.set_ipv4(addr, name, origin).await,
+            Config::Netcup(config) => config.set_ipv4(addr, name, origin).await,
+            Config::He(config) => config.set_ipv4(addr, name, origin).await,
+            Config::NsupdateInfo(config) => config.set_ipv4(addr, name, origin).await,
+        }
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        match self {
+            Config::Rfc2136(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Porkbun(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Dynv6(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Namecheap(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Inwx(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Njalla(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Linode(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Vultr(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Scaleway(config) => config.set_ipv6(addr, name, origin).await,
+            Config::GoogleCloudDns(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Azure(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Freedns(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Dynu(config) => config.set_ipv6(addr, name, origin).await,
+            Config::Netcup(config) => config.set_ipv6(addr, name, origin).await,
+            Config::He(config) => config.set_ipv6(addr, name, origin).await,
+            Config::NsupdateInfo(config) => config.set_ipv6(addr, name, origin).await,
+        }
+    }
+}
+
+/// Deserialize the provider configs, either a single table or an array of
+/// tables. Tables without a `provider` key fall back to RFC 2136, for configs
+/// written before the key was introduced.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Config>, D::Error> {
+    let tables = match toml::Value::deserialize(deserializer)? {
+        toml::Value::Table(table) => vec![table],
+        toml::Value::Array(values) => values
+            .into_iter()
+            .map(toml::Table::try_from)
+            .collect::<Result<_, _>>()
+            .map_err(serde::de::Error::custom)?,
+        other => {
+            return Err(serde::de::Error::custom(format!(
+                "expected a table or an array of tables, found {}",
+                other.type_str()
+            )));
+        }
+    };
+    if tables.is_empty() {
+        return Err(serde::de::Error::custom(
+            "at least one provider has to be configured",
+        ));
+    }
+    tables
+        .into_iter()
+        .map(|mut table| {
+            table.entry("provider").or_insert_with(|| "rfc2136".into());
+            Config::deserialize(toml::Value::Table(table)).map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
+
+/// Identifiers of the given providers, under which their state is tracked.
+/// Providers configured more than once are numbered in order of appearance.
+pub fn ids(configs: &[Config]) -> Vec<String> {
+    let mut seen = BTreeMap::<&str, usize>::new();
+    configs
+        .iter()
+        .map(|config| {
+            let count = seen.entry(config.name()).or_default();
+            *count += 1;
+            match count {
+                1 => config.name().to_string(),
+                count => format!("{}-{}", config.name(), count),
+            }
+        })
+        .collect()
+}
+
+/// Name of the record relative to the zone, as expected by most HTTP APIs.
+/// The zone apex is represented by an empty string.
+fn relative_name(name: &Name, origin: &Name) -> anyhow::Result<String> {
+    if !origin.zone_of(name) {
+        bail!("{} is not part of the zone {}", name, origin);
+    }
+    let labels = name.iter().count() - origin.iter().count();
+    let relative = name
+        .iter()
+        .take(labels)
+        .map(|label| String::from_utf8_lossy(label).to_lowercase())
+        .collect::<Vec<_>>()
+        .join(".");
+    Ok(relative)
+}
+
+/// Name without the trailing dot, as expected by most HTTP APIs.
+fn unqualified(name: &Name) -> String {
+    name.to_lowercase()
+        .to_utf8()
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Whether a record value returned by a provider matches the value about to be
+/// written. Addresses are compared parsed, as providers don't necessarily
+/// return them in the same notation they were written in.
+fn same_content(existing: &str, content: &str) -> bool {
+    match (existing.parse::<IpAddr>(), content.parse::<IpAddr>()) {
+        (Ok(existing), Ok(content)) => existing == content,
+        _ => existing == content,
+    }
+}
+
+/// Check the response of a dyndns2 compatible update endpoint, which reports
+/// the result as a return code at the start of the body.
+async fn dyndns2_result(provider: &str, response: reqwest::Response) -> anyhow::Result<()> {
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .with_context(|| format!("Failed to read {provider} response"))?;
+    dyndns2_check(provider, status, &body)
+}
+
+fn dyndns2_check(provider: &str, status: reqwest::StatusCode, body: &str) -> anyhow::Result<()> {
+    match body.split_whitespace().next() {
+        Some("good" | "nochg") if status.is_success() => {
+            tracing::debug!("{} response: {}", provider, body.trim());
+            Ok(())
+        }
+        _ => bail!(
+            "{} returned an error ({}): {}",
+            provider,
+            status,
+            body.trim()
+        ),
+    }
+}
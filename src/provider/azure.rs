@@ -0,0 +1,224 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const MANAGEMENT_BASE: &str = "https://management.azure.com";
+const API_VERSION: &str = "2018-05-01";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    tenant_id: String,
+    /// Application (client) ID of a service principal with the DNS Zone Contributor role
+    client_id: String,
+    client_secret: Secret<String>,
+    subscription_id: String,
+    resource_group: String,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: Secret<String>,
+}
+
+#[derive(Deserialize)]
+struct RecordSet {
+    etag: Option<String>,
+    properties: Value,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorReason,
+}
+
+#[derive(Deserialize)]
+struct ErrorReason {
+    code: String,
+    message: String,
+}
+
+impl Config {
+    async fn token(&self) -> anyhow::Result<Secret<String>> {
+        let response = proxy::client()
+            .post(format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                self.tenant_id
+            ))
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("scope", "https://management.azure.com/.default"),
+            ])
+            .send()
+            .await
+            .context("Failed to send token request to Azure")?;
+        if !response.status().is_success() {
+            bail!(
+                "Azure refused to issue an access token ({})",
+                response.status()
+            );
+        }
+        let token: Token = response
+            .json()
+            .await
+            .context("Failed to parse token response")?;
+        Ok(token.access_token)
+    }
+
+    fn request(
+        &self,
+        token: &str,
+        method: Method,
+        record_type: &str,
+        name: &str,
+        origin: &Name,
+    ) -> RequestBuilder {
+        proxy::client()
+            .request(
+                method,
+                format!(
+                    "{MANAGEMENT_BASE}/subscriptions/{}/resourceGroups/{}/providers/Microsoft.Network/dnsZones/{}/{record_type}/{name}",
+                    self.subscription_id,
+                    self.resource_group,
+                    unqualified(origin),
+                ),
+            )
+            .query(&[("api-version", API_VERSION)])
+            .bearer_auth(token)
+    }
+
+    async fn send(request: RequestBuilder) -> anyhow::Result<Response> {
+        let response = request
+            .send()
+            .await
+            .context("Failed to send request to Azure")?;
+        Self::check_status(response).await
+    }
+
+    /// Turn error responses other than 404 into errors.
+    async fn check_status(response: Response) -> anyhow::Result<Response> {
+        if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+            let status = response.status();
+            let error = response
+                .json::<ErrorResponse>()
+                .await
+                .map(|response| format!("{}: {}", response.error.code, response.error.message))
+                .unwrap_or_else(|_| "unknown error".to_string());
+            bail!("Azure returned an error ({}): {}", status, error);
+        }
+        Ok(response)
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let (records_key, address_key) = match record_type {
+            "A" => ("ARecords", "ipv4Address"),
+            _ => ("AAAARecords", "ipv6Address"),
+        };
+        let name = match relative_name(&name, &origin)? {
+            name if name.is_empty() => "@".to_string(),
+            name => name,
+        };
+        let token = self.token().await?;
+        let response = Self::send(self.request(&token, Method::GET, record_type, &name, &origin))
+            .await
+            .context("Failed to look up existing record set")?;
+        let existing = match response.status() {
+            StatusCode::NOT_FOUND => None,
+            _ => Some(
+                response
+                    .json::<RecordSet>()
+                    .await
+                    .context("Failed to parse existing record set")?,
+            ),
+        };
+        let mut request = self.request(&token, Method::PUT, record_type, &name, &origin);
+        // The ETag of the record set that was compared against makes sure the
+        // write fails instead of overwriting a concurrent change.
+        match &existing {
+            None => request = request.header("If-None-Match", "*"),
+            Some(existing) => {
+                let addresses = existing.properties[records_key]
+                    .as_array()
+                    .map(|records| {
+                        records
+                            .iter()
+                            .filter_map(|record| record[address_key].as_str())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                if let [address] = addresses.as_slice()
+                    && same_content(address, &content)
+                {
+                    tracing::debug!("{} record already up to date on Azure", record_type);
+                    return Ok(());
+                }
+                if let Some(etag) = &existing.etag {
+                    request = request.header("If-Match", etag);
+                }
+            }
+        }
+        let response = request
+            .json(&json!({
+                "properties": {
+                    "TTL": self.ttl,
+                    records_key: [{ address_key: content }],
+                },
+            }))
+            .send()
+            .await
+            .context("Failed to send request to Azure")?;
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            bail!("The record set was changed concurrently, retrying in the next cycle");
+        }
+        let response = Self::check_status(response)
+            .await
+            .context("Failed to update record set")?;
+        if response.status() == StatusCode::NOT_FOUND {
+            bail!("The zone {} does not exist in this resource group", origin);
+        }
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
@@ -0,0 +1,250 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use reqwest::{Method, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{dyndns2_result, relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const DYNDNS2_URL: &str = "https://api.dynu.com/nic/update";
+const API_BASE: &str = "https://api.dynu.com/v2";
+
+/// Dynu offers a dyndns2 compatible endpoint, which only needs the account
+/// credentials, and a REST API that can also manage records below hostnames.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "api", rename_all = "snake_case")]
+pub enum Config {
+    Dyndns2 {
+        username: String,
+        /// Account password, or its MD5 or SHA-256 hash
+        password: Secret<String>,
+    },
+    Rest {
+        api_key: Secret<String>,
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+    },
+}
+
+#[derive(Deserialize)]
+struct Domains {
+    domains: Vec<Domain>,
+}
+
+#[derive(Deserialize)]
+struct Domain {
+    id: u64,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Records {
+    dns_records: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    id: u64,
+    node_name: String,
+    record_type: String,
+    ipv4_address: Option<String>,
+    ipv6_address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+impl Config {
+    async fn update_dyndns2(
+        username: &str,
+        password: &str,
+        addr: IpAddr,
+        name: &Name,
+    ) -> anyhow::Result<()> {
+        let hostname = unqualified(name);
+        let content = addr.to_string();
+        // Dynu falls back to the address of the request for parameters that
+        // are omitted, so the other family has to be excluded explicitly.
+        let (myip, myipv6) = match addr {
+            IpAddr::V4(_) => (content.as_str(), "no"),
+            IpAddr::V6(_) => ("no", content.as_str()),
+        };
+        let response = proxy::client()
+            .get(DYNDNS2_URL)
+            .basic_auth(username, Some(password))
+            .query(&[
+                ("hostname", hostname.as_str()),
+                ("myip", myip),
+                ("myipv6", myipv6),
+            ])
+            .send()
+            .await
+            .context("Failed to send request to Dynu")?;
+        dyndns2_result("Dynu", response).await
+    }
+
+    fn request(api_key: &str, method: Method, path: &str) -> RequestBuilder {
+        proxy::client()
+            .request(method, format!("{API_BASE}/{path}"))
+            .header("API-Key", api_key)
+    }
+
+    async fn send(request: RequestBuilder) -> anyhow::Result<Response> {
+        let response = request
+            .send()
+            .await
+            .context("Failed to send request to Dynu")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response
+                .json::<ErrorResponse>()
+                .await
+                .map(|response| response.message)
+                .unwrap_or_else(|_| "unknown error".to_string());
+            bail!("Dynu returned an error ({}): {}", status, message);
+        }
+        Ok(response)
+    }
+
+    async fn update_rest(
+        api_key: &str,
+        ttl: u32,
+        addr: IpAddr,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let (record_type, address_key) = match addr {
+            IpAddr::V4(_) => ("A", "ipv4Address"),
+            IpAddr::V6(_) => ("AAAA", "ipv6Address"),
+        };
+        let content = addr.to_string();
+        let domain_name = unqualified(&origin);
+        let node_name = relative_name(&name, &origin)?;
+        let domains: Domains = Self::send(Self::request(api_key, Method::GET, "dns"))
+            .await
+            .context("Failed to list domains")?
+            .json()
+            .await
+            .context("Failed to parse domains")?;
+        let Some(domain) = domains
+            .domains
+            .into_iter()
+            .find(|domain| domain.name.eq_ignore_ascii_case(&domain_name))
+        else {
+            bail!(
+                "The domain {} does not exist in this Dynu account",
+                domain_name
+            );
+        };
+        if node_name.is_empty() {
+            // The addresses of the domain itself are part of the domain, which
+            // is updated as a whole, so the other settings are sent back as is.
+            let path = format!("dns/{}", domain.id);
+            let mut settings: Value = Self::send(Self::request(api_key, Method::GET, &path))
+                .await
+                .context("Failed to look up domain")?
+                .json()
+                .await
+                .context("Failed to parse domain")?;
+            if settings[address_key]
+                .as_str()
+                .is_some_and(|existing| same_content(existing, &content))
+            {
+                tracing::debug!("{} record already up to date on Dynu", record_type);
+                return Ok(());
+            }
+            settings[address_key] = content.into();
+            Self::send(Self::request(api_key, Method::POST, &path).json(&settings))
+                .await
+                .context("Failed to update domain")?;
+            return Ok(());
+        }
+        let path = format!("dns/{}/record", domain.id);
+        let records: Records = Self::send(Self::request(api_key, Method::GET, &path))
+            .await
+            .context("Failed to list existing records")?
+            .json()
+            .await
+            .context("Failed to parse existing records")?;
+        let existing = records.dns_records.into_iter().find(|record| {
+            record.node_name.eq_ignore_ascii_case(&node_name) && record.record_type == record_type
+        });
+        let body = json!({
+            "nodeName": node_name,
+            "recordType": record_type,
+            "ttl": ttl,
+            "state": true,
+            address_key: content,
+        });
+        match existing {
+            None => {
+                Self::send(Self::request(api_key, Method::POST, &path).json(&body))
+                    .await
+                    .context("Failed to create record")?;
+            }
+            Some(record)
+                if record
+                    .ipv4_address
+                    .as_ref()
+                    .or(record.ipv6_address.as_ref())
+                    .is_some_and(|existing| same_content(existing, &content)) =>
+            {
+                tracing::debug!("{} record already up to date on Dynu", record_type);
+            }
+            Some(record) => {
+                Self::send(
+                    Self::request(api_key, Method::POST, &format!("{path}/{}", record.id))
+                        .json(&body),
+                )
+                .await
+                .context("Failed to update record")?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn update(&self, addr: IpAddr, name: Name, origin: Name) -> anyhow::Result<()> {
+        match self {
+            Config::Dyndns2 { username, password } => {
+                Self::update_dyndns2(username, password, addr, &name).await
+            }
+            Config::Rest { api_key, ttl } => {
+                Self::update_rest(api_key, *ttl, addr, name, origin).await
+            }
+        }
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.update(addr.into(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.update(addr.into(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
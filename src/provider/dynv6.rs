@@ -0,0 +1,102 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+
+use super::unqualified;
+use crate::{proxy, secret::Secret};
+
+const UPDATE_URL: &str = "https://dynv6.com/api/update";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    token: Secret<String>,
+    /// When set, the IPv6 prefix of this length is sent instead of the
+    /// address, updating all records in the zone that are based on it.
+    prefix_length: Option<u8>,
+}
+
+impl Config {
+    async fn update(&self, origin: Name, param: &str, value: String) -> anyhow::Result<()> {
+        let hostname = unqualified(&origin);
+        // The token is part of the URL, so it's kept out of errors.
+        let response = proxy::client()
+            .get(UPDATE_URL)
+            .query(&[
+                ("hostname", hostname.as_str()),
+                ("token", self.token.as_str()),
+                (param, value.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .context("Failed to send request to dynv6")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read dynv6 response")?;
+        if !status.is_success() {
+            bail!("dynv6 returned an error ({}): {}", status, body.trim());
+        }
+        tracing::debug!("dynv6 response: {}", body.trim());
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(length) = self.prefix_length.filter(|length| *length > 128) {
+            bail!("Invalid IPv6 prefix length {}", length);
+        }
+        Ok(())
+    }
+
+    /// Fail for records other than the zone itself, as the update would set
+    /// the zone's address instead.
+    fn check_name(name: &Name, origin: &Name) -> anyhow::Result<()> {
+        if name != origin {
+            bail!(
+                "dynv6 only updates the zone {} itself, not {}; use the prefix update for other records",
+                origin,
+                name
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        Self::check_name(&name, &origin)?;
+        self.update(origin, "ipv4", addr.to_string())
+            .await
+            .context("Failed to update IPv4 address")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        match self.prefix_length {
+            Some(length) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(length)).unwrap_or(0);
+                let prefix = Ipv6Addr::from(u128::from(addr) & mask);
+                self.update(origin, "ipv6prefix", format!("{prefix}/{length}"))
+                    .await
+                    .context("Failed to update IPv6 prefix")
+            }
+            None => {
+                Self::check_name(&name, &origin)?;
+                self.update(origin, "ipv6", addr.to_string())
+                    .await
+                    .context("Failed to update IPv6 address")
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+
+use crate::{proxy, secret::Secret};
+
+const UPDATE_URL_V4: &str = "https://sync.afraid.org/u";
+const UPDATE_URL_V6: &str = "https://v6.sync.afraid.org/u";
+
+/// FreeDNS identifies the record to update by a randomized token, which is
+/// different for the A and AAAA records of a host.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Token of the A record, the last part of its v2 update URL
+    ipv4_token: Option<Secret<String>>,
+    /// Token of the AAAA record, the last part of its v2 update URL
+    ipv6_token: Option<Secret<String>>,
+}
+
+impl Config {
+    async fn update(
+        base_url: &str,
+        token: Option<&Secret<String>>,
+        addr: String,
+        name: &Name,
+    ) -> anyhow::Result<()> {
+        let Some(token) = token else {
+            bail!("No token is configured for this address family");
+        };
+        // The token is part of the URL, so it's kept out of errors.
+        let response = proxy::client()
+            .get(format!("{}/{}/", base_url, token.as_str()))
+            .query(&[("address", addr.as_str())])
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .context("Failed to send request to FreeDNS")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read FreeDNS response")?;
+        // Errors are reported in the body, sometimes with a successful status.
+        if !status.is_success() || body.trim_start().starts_with("ERROR") {
+            bail!("FreeDNS returned an error ({}): {}", status, body.trim());
+        }
+        tracing::debug!("FreeDNS response for {}: {}", name, body.trim());
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, _origin: Name) -> anyhow::Result<()> {
+        Self::update(
+            UPDATE_URL_V4,
+            self.ipv4_token.as_ref(),
+            addr.to_string(),
+            &name,
+        )
+        .await
+        .context("Failed to update A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, _origin: Name) -> anyhow::Result<()> {
+        Self::update(
+            UPDATE_URL_V6,
+            self.ipv6_token.as_ref(),
+            addr.to_string(),
+            &name,
+        )
+        .await
+        .context("Failed to update AAAA record")
+    }
+}
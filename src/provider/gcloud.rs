@@ -0,0 +1,321 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::{
+    fs::read_to_string,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, anyhow, bail};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use hickory_proto::rr::Name;
+use reqwest::{Method, RequestBuilder, Response};
+use ring::{
+    rand::SystemRandom,
+    signature::{RSA_PKCS1_SHA256, RsaKeyPair},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use zeroize::Zeroizing;
+
+use super::same_content;
+use crate::{proxy, secret::Secret};
+
+const API_BASE: &str = "https://dns.googleapis.com/dns/v1";
+const SCOPE: &str = "https://www.googleapis.com/auth/ndev.clouddns.readwrite";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// JSON key file of a service account with the DNS Administrator role
+    credentials_file: PathBuf,
+    /// Project of the managed zone, defaults to the project of the service account
+    project: Option<String>,
+    /// Name of the managed zone, looked up by the DNS name of the zone if unset
+    managed_zone: Option<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: Secret<String>,
+    project_id: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: Secret<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManagedZones {
+    #[serde(default)]
+    managed_zones: Vec<ManagedZone>,
+}
+
+#[derive(Deserialize)]
+struct ManagedZone {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ResourceRecordSets {
+    #[serde(default)]
+    rrsets: Vec<ResourceRecordSet>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResourceRecordSet {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    ttl: u32,
+    rrdatas: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorReason,
+}
+
+#[derive(Deserialize)]
+struct ErrorReason {
+    message: String,
+}
+
+/// An authenticated session with the Cloud DNS API.
+struct Session {
+    project: String,
+    token: Secret<String>,
+}
+
+impl Config {
+    pub fn credentials_file(&self) -> &Path {
+        &self.credentials_file
+    }
+
+    async fn login(&self) -> anyhow::Result<Session> {
+        let key: ServiceAccountKey = serde_json::from_str(&Zeroizing::new(
+            read_to_string(&self.credentials_file)
+                .context("Failed to read service account credentials")?,
+        ))
+        .context("Failed to parse service account credentials")?;
+        let assertion = key.assertion()?;
+        let response = proxy::client()
+            .post(&key.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ])
+            .send()
+            .await
+            .context("Failed to send token request to Google")?;
+        if !response.status().is_success() {
+            bail!(
+                "Google refused to issue an access token ({})",
+                response.status()
+            );
+        }
+        let token: Token = response
+            .json()
+            .await
+            .context("Failed to parse token response")?;
+        Ok(Session {
+            project: self.project.clone().unwrap_or(key.project_id),
+            token: token.access_token,
+        })
+    }
+
+    async fn managed_zone(&self, session: &Session, origin: &Name) -> anyhow::Result<String> {
+        if let Some(managed_zone) = &self.managed_zone {
+            return Ok(managed_zone.clone());
+        }
+        let dns_name = origin.to_lowercase().to_utf8();
+        let zones: ManagedZones = session
+            .send(
+                session
+                    .request(Method::GET, "managedZones")
+                    .query(&[("dnsName", dns_name.as_str())]),
+            )
+            .await
+            .context("Failed to look up managed zone")?
+            .json()
+            .await
+            .context("Failed to parse managed zones")?;
+        match zones.managed_zones.as_slice() {
+            [zone] => Ok(zone.name.clone()),
+            [] => bail!(
+                "No managed zone for {} exists in project {}",
+                dns_name,
+                session.project
+            ),
+            _ => bail!(
+                "Multiple managed zones for {} exist in project {}, set managed_zone",
+                dns_name,
+                session.project
+            ),
+        }
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let session = self.login().await?;
+        let managed_zone = self.managed_zone(&session, &origin).await?;
+        let name = name.to_lowercase().to_utf8();
+        let existing: ResourceRecordSets = session
+            .send(
+                session
+                    .request(Method::GET, &format!("managedZones/{managed_zone}/rrsets"))
+                    .query(&[("name", name.as_str()), ("type", record_type)]),
+            )
+            .await
+            .context("Failed to list existing records")?
+            .json()
+            .await
+            .context("Failed to parse existing records")?;
+        if let [rrset] = existing.rrsets.as_slice()
+            && let [rrdata] = rrset.rrdatas.as_slice()
+            && same_content(rrdata, &content)
+        {
+            tracing::debug!("{} record already up to date on Cloud DNS", record_type);
+            return Ok(());
+        }
+        // Deletions have to match the current record set exactly, and the
+        // change is applied atomically, so the record is never missing.
+        let addition = ResourceRecordSet {
+            name,
+            record_type: record_type.to_string(),
+            ttl: self.ttl,
+            rrdatas: vec![content],
+        };
+        session
+            .send(
+                session
+                    .request(
+                        Method::POST,
+                        &format!("managedZones/{managed_zone}/changes"),
+                    )
+                    .json(&json!({
+                        "additions": [addition],
+                        "deletions": existing.rrsets,
+                    })),
+            )
+            .await
+            .context("Failed to create change")?;
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+impl ServiceAccountKey {
+    /// Signed JWT, exchanged for an access token at the token URI.
+    fn assertion(&self) -> anyhow::Result<String> {
+        let now = crate::now();
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "RS256", "typ": "JWT" }).to_string());
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "iss": self.client_email,
+                "scope": SCOPE,
+                "aud": self.token_uri,
+                "iat": now,
+                "exp": now + 3600,
+            })
+            .to_string(),
+        );
+        let message = format!("{header}.{claims}");
+        let key_pair = RsaKeyPair::from_pkcs8(&self.private_key_der()?)
+            .map_err(|error| anyhow!("Invalid service account key: {}", error))?;
+        let mut signature = vec![0; key_pair.public().modulus_len()];
+        key_pair
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                message.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|error| anyhow!("Failed to sign token request: {}", error))?;
+        Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+    }
+
+    /// The PKCS#8 private key, decoded from its PEM form.
+    fn private_key_der(&self) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+        let base64 = Zeroizing::new(
+            self.private_key
+                .lines()
+                .filter(|line| !line.starts_with("-----"))
+                .collect::<String>(),
+        );
+        STANDARD
+            .decode(base64.as_bytes())
+            .map(Zeroizing::new)
+            .context("Failed to decode service account key")
+    }
+}
+
+impl Session {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        proxy::client()
+            .request(
+                method,
+                format!("{API_BASE}/projects/{}/{path}", self.project),
+            )
+            .bearer_auth(self.token.as_str())
+    }
+
+    async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let response = request
+            .send()
+            .await
+            .context("Failed to send request to Cloud DNS")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response
+                .json::<ErrorResponse>()
+                .await
+                .map(|response| response.error.message)
+                .unwrap_or_else(|_| "unknown error".to_string());
+            bail!("Cloud DNS returned an error ({}): {}", status, message);
+        }
+        Ok(response)
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
@@ -0,0 +1,60 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::Context;
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+
+use super::{dyndns2_result, unqualified};
+use crate::{proxy, secret::Secret};
+
+const UPDATE_URL: &str = "https://dyn.dns.he.net/nic/update";
+
+/// dns.he.net authenticates updates with a key generated for each record.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// DDNS key of the A record
+    key: Secret<String>,
+    /// DDNS key of the AAAA record, if it differs from the one of the A record
+    ipv6_key: Option<Secret<String>>,
+}
+
+impl Config {
+    async fn update(&self, key: &str, addr: String, name: Name) -> anyhow::Result<()> {
+        let hostname = unqualified(&name);
+        let response = proxy::client()
+            .post(UPDATE_URL)
+            .form(&[
+                ("hostname", hostname.as_str()),
+                ("password", key),
+                ("myip", addr.as_str()),
+            ])
+            .send()
+            .await
+            .context("Failed to send request to Hurricane Electric")?;
+        dyndns2_result("Hurricane Electric", response).await
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, _origin: Name) -> anyhow::Result<()> {
+        self.update(&self.key, addr.to_string(), name)
+            .await
+            .context("Failed to update A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, _origin: Name) -> anyhow::Result<()> {
+        let key = self.ipv6_key.as_ref().unwrap_or(&self.key);
+        self.update(key, addr.to_string(), name)
+            .await
+            .context("Failed to update AAAA record")
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const API_URL: &str = "https://api.domrobot.com/jsonrpc/";
+const OTE_API_URL: &str = "https://api.ote.domrobot.com/jsonrpc/";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Name of an API user, which must not have 2FA enabled
+    username: String,
+    password: Secret<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+    /// Use the OTE test environment instead of production
+    #[serde(default)]
+    sandbox: bool,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    code: u32,
+    msg: String,
+    #[serde(rename = "resData", default)]
+    res_data: Value,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: u64,
+    content: String,
+}
+
+struct Session {
+    client: reqwest::Client,
+    url: &'static str,
+}
+
+impl Session {
+    async fn call(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let response: Response = self
+            .client
+            .post(self.url)
+            .json(&json!({ "method": method, "params": params }))
+            .send()
+            .await
+            .with_context(|| format!("Failed to send {method} request to INWX"))?
+            .json()
+            .await
+            .with_context(|| format!("Failed to parse INWX {method} response"))?;
+        // Codes in the 1xxx range indicate success
+        if !(1000..2000).contains(&response.code) {
+            bail!(
+                "INWX {} failed with code {}: {}",
+                method,
+                response.code,
+                response.msg
+            );
+        }
+        Ok(response.res_data)
+    }
+}
+
+impl Config {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    async fn login(&self) -> anyhow::Result<Session> {
+        let session = Session {
+            client: proxy::builder()
+                .cookie_store(true)
+                .build()
+                .context("Failed to build HTTP client")?,
+            url: if self.sandbox { OTE_API_URL } else { API_URL },
+        };
+        let login = session
+            .call(
+                "account.login",
+                json!({ "user": self.username, "pass": self.password.as_str() }),
+            )
+            .await
+            .context("Failed to log in to INWX")?;
+        if let Some(tfa) = login.get("tfa").and_then(Value::as_str)
+            && tfa != "0"
+        {
+            bail!("The INWX account has 2FA enabled, create an API user without 2FA instead");
+        }
+        Ok(session)
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let session = self.login().await?;
+        let domain = unqualified(&origin);
+        let name = unqualified(&name);
+        let result = async {
+            let info = session
+                .call(
+                    "nameserver.info",
+                    json!({ "domain": domain, "name": name, "type": record_type }),
+                )
+                .await
+                .context("Failed to query existing records")?;
+            let records: Vec<Record> = match info.get("record") {
+                Some(records) => serde_json::from_value(records.clone())
+                    .context("Failed to parse existing records")?,
+                None => Vec::new(),
+            };
+            match records.first() {
+                None => {
+                    session
+                        .call(
+                            "nameserver.createRecord",
+                            json!({
+                                "domain": domain,
+                                "name": name,
+                                "type": record_type,
+                                "content": content,
+                                "ttl": self.ttl,
+                            }),
+                        )
+                        .await
+                        .context("Failed to create record")?;
+                }
+                Some(record) if same_content(&record.content, &content) => {
+                    tracing::debug!("{} record already up to date on INWX", record_type);
+                }
+                Some(record) => {
+                    session
+                        .call(
+                            "nameserver.updateRecord",
+                            json!({ "id": record.id, "content": content, "ttl": self.ttl }),
+                        )
+                        .await
+                        .context("Failed to update record")?;
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(error) = session.call("account.logout", json!({})).await {
+            tracing::warn!("Failed to log out of INWX: {:#}", error);
+        }
+        result
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
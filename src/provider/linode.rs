@@ -0,0 +1,171 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use reqwest::{Method, RequestBuilder};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+
+use super::{relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const API_BASE: &str = "https://api.linode.com/v4";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Personal access token with read/write access to domains
+    token: Secret<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+#[derive(Deserialize)]
+struct Page<T> {
+    data: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct Domain {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: u64,
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct Errors {
+    errors: Vec<ErrorReason>,
+}
+
+#[derive(Deserialize)]
+struct ErrorReason {
+    reason: String,
+}
+
+impl Config {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        proxy::client()
+            .request(method, format!("{API_BASE}/{path}"))
+            .bearer_auth(self.token.as_str())
+    }
+
+    async fn send<T: DeserializeOwned>(request: RequestBuilder) -> anyhow::Result<T> {
+        let response = request
+            .send()
+            .await
+            .context("Failed to send request to Linode")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let reasons = match response.json::<Errors>().await {
+                Ok(errors) => errors
+                    .errors
+                    .into_iter()
+                    .map(|error| error.reason)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                Err(_) => "unknown error".to_string(),
+            };
+            bail!("Linode returned an error ({}): {}", status, reasons);
+        }
+        response
+            .json()
+            .await
+            .context("Failed to parse Linode response")
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let domain_name = unqualified(&origin);
+        let name = relative_name(&name, &origin)?;
+        let domains: Page<Domain> = Self::send(
+            self.request(Method::GET, "domains")
+                .header("X-Filter", json!({ "domain": domain_name }).to_string()),
+        )
+        .await
+        .context("Failed to look up domain")?;
+        let Some(domain) = domains.data.first() else {
+            bail!(
+                "The domain {} does not exist in this Linode account",
+                domain_name
+            );
+        };
+        let records: Page<Record> = Self::send(
+            self.request(Method::GET, &format!("domains/{}/records", domain.id))
+                .query(&[("page_size", "500")]),
+        )
+        .await
+        .context("Failed to list existing records")?;
+        let existing = records
+            .data
+            .into_iter()
+            .find(|record| record.name == name && record.record_type == record_type);
+        match existing {
+            None => {
+                Self::send::<Value>(
+                    self.request(Method::POST, &format!("domains/{}/records", domain.id))
+                        .json(&json!({
+                            "type": record_type,
+                            "name": name,
+                            "target": content,
+                            "ttl_sec": self.ttl,
+                        })),
+                )
+                .await
+                .context("Failed to create record")?;
+            }
+            Some(record) if same_content(&record.target, &content) => {
+                tracing::debug!("{} record already up to date on Linode", record_type);
+            }
+            Some(record) => {
+                Self::send::<Value>(
+                    self.request(
+                        Method::PUT,
+                        &format!("domains/{}/records/{}", domain.id, record.id),
+                    )
+                    .json(&json!({ "target": content, "ttl_sec": self.ttl })),
+                )
+                .await
+                .context("Failed to update record")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
@@ -0,0 +1,82 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+
+use super::{relative_name, unqualified};
+use crate::{proxy, secret::Secret};
+
+const UPDATE_URL: &str = "https://dynamicdns.park-your-domain.com/update";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// The dynamic DNS password from the "Advanced DNS" page, not the account password
+    password: Secret<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Response {
+    err_count: u32,
+    #[serde(rename = "errors", default)]
+    errors: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        let host = match relative_name(&name, &origin)? {
+            host if host.is_empty() => "@".to_string(),
+            host => host,
+        };
+        // The password is part of the URL, so it's kept out of errors.
+        let body = proxy::client()
+            .get(UPDATE_URL)
+            .query(&[
+                ("host", host.as_str()),
+                ("domain", unqualified(&origin).as_str()),
+                ("password", self.password.as_str()),
+                ("ip", addr.to_string().as_str()),
+            ])
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)
+            .context("Failed to send request to Namecheap")?
+            .error_for_status()
+            .map_err(reqwest::Error::without_url)
+            .context("Namecheap returned an error status")?
+            .text()
+            .await
+            .context("Failed to read Namecheap response")?;
+        let response: Response =
+            quick_xml::de::from_str(&body).context("Failed to parse Namecheap response")?;
+        if response.err_count > 0 {
+            let errors: Vec<_> = response.errors.into_values().collect();
+            bail!("Namecheap returned an error: {}", errors.join(", "));
+        }
+        Ok(())
+    }
+
+    pub async fn set_ipv6(
+        &self,
+        _addr: Ipv6Addr,
+        _name: Name,
+        _origin: Name,
+    ) -> anyhow::Result<()> {
+        bail!("Namecheap's dynamic DNS API does not support AAAA records, disable ipv6")
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const API_URL: &str = "https://ccp.netcup.net/run/webservice/servers/endpoint.php?JSON";
+
+/// The TTL is configured for the whole zone in the CCP, so it can't be set here.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    customer_number: String,
+    api_key: Secret<String>,
+    api_password: Secret<String>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    status: String,
+    statuscode: u32,
+    shortmessage: String,
+    longmessage: Option<String>,
+    #[serde(default)]
+    responsedata: Value,
+}
+
+#[derive(Deserialize)]
+struct Records {
+    dnsrecords: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: String,
+    hostname: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    destination: String,
+}
+
+struct Session<'a> {
+    config: &'a Config,
+    id: Secret<String>,
+}
+
+impl Config {
+    async fn call(&self, action: &str, params: Value) -> anyhow::Result<Value> {
+        let response: Response = proxy::client()
+            .post(API_URL)
+            .json(&json!({ "action": action, "param": params }))
+            .send()
+            .await
+            .with_context(|| format!("Failed to send {action} request to netcup"))?
+            .json()
+            .await
+            .with_context(|| format!("Failed to parse netcup {action} response"))?;
+        if response.status != "success" {
+            bail!(
+                "netcup {} failed with code {}: {} {}",
+                action,
+                response.statuscode,
+                response.shortmessage,
+                response.longmessage.unwrap_or_default()
+            );
+        }
+        Ok(response.responsedata)
+    }
+
+    async fn login(&self) -> anyhow::Result<Session<'_>> {
+        let login = self
+            .call(
+                "login",
+                json!({
+                    "customernumber": self.customer_number,
+                    "apikey": self.api_key.as_str(),
+                    "apipassword": self.api_password.as_str(),
+                }),
+            )
+            .await
+            .context("Failed to log in to netcup")?;
+        let Some(id) = login.get("apisessionid").and_then(Value::as_str) else {
+            bail!("netcup did not return a session ID");
+        };
+        Ok(Session {
+            config: self,
+            id: Secret::new(id.to_string()),
+        })
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let session = self.login().await?;
+        let domain = unqualified(&origin);
+        let hostname = match relative_name(&name, &origin)? {
+            name if name.is_empty() => "@".to_string(),
+            name => name,
+        };
+        let result = async {
+            let records: Records = serde_json::from_value(
+                session
+                    .call("infoDnsRecords", json!({ "domainname": domain }))
+                    .await
+                    .context("Failed to list existing records")?,
+            )
+            .context("Failed to parse existing records")?;
+            let existing = records.dnsrecords.into_iter().find(|record| {
+                record.hostname.eq_ignore_ascii_case(&hostname) && record.record_type == record_type
+            });
+            let record = match existing {
+                Some(record) if same_content(&record.destination, &content) => {
+                    tracing::debug!("{} record already up to date on netcup", record_type);
+                    return Ok(());
+                }
+                Some(record) => json!({
+                    "id": record.id,
+                    "hostname": hostname,
+                    "type": record_type,
+                    "destination": content,
+                    "deleterecord": false,
+                }),
+                None => json!({
+                    "hostname": hostname,
+                    "type": record_type,
+                    "destination": content,
+                    "deleterecord": false,
+                }),
+            };
+            session
+                .call(
+                    "updateDnsRecords",
+                    json!({
+                        "domainname": domain,
+                        "dnsrecordset": { "dnsrecords": [record] },
+                    }),
+                )
+                .await
+                .context("Failed to update record")?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(error) = session.call("logout", json!({})).await {
+            tracing::warn!("Failed to log out of netcup: {:#}", error);
+        }
+        result
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+impl Session<'_> {
+    /// Call an action with the session credentials added to its parameters.
+    async fn call(&self, action: &str, mut params: Value) -> anyhow::Result<Value> {
+        params["customernumber"] = self.config.customer_number.clone().into();
+        params["apikey"] = self.config.api_key.as_str().into();
+        params["apisessionid"] = self.id.as_str().into();
+        self.config.call(action, params).await
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const API_URL: &str = "https://njal.la/api/1/";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    token: Secret<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    result: Option<Value>,
+    error: Option<ResponseError>,
+}
+
+#[derive(Deserialize)]
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct Records {
+    records: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: Value,
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    content: String,
+}
+
+impl Config {
+    async fn call(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let response: Response = proxy::client()
+            .post(API_URL)
+            .header("Authorization", format!("Njalla {}", self.token.as_str()))
+            .json(&json!({ "method": method, "params": params }))
+            .send()
+            .await
+            .with_context(|| format!("Failed to send {method} request to Njalla"))?
+            .json()
+            .await
+            .with_context(|| format!("Failed to parse Njalla {method} response"))?;
+        match response {
+            Response {
+                error: Some(error), ..
+            } => bail!(
+                "Njalla {} failed with code {}: {}",
+                method,
+                error.code,
+                error.message
+            ),
+            Response { result, .. } => Ok(result.unwrap_or_default()),
+        }
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let domain = unqualified(&origin);
+        let name = match relative_name(&name, &origin)? {
+            name if name.is_empty() => "@".to_string(),
+            name => name,
+        };
+        let records: Records = serde_json::from_value(
+            self.call("list-records", json!({ "domain": domain }))
+                .await
+                .context("Failed to list existing records")?,
+        )
+        .context("Failed to parse existing records")?;
+        let existing = records
+            .records
+            .into_iter()
+            .find(|record| record.name == name && record.record_type == record_type);
+        match existing {
+            None => {
+                self.call(
+                    "add-record",
+                    json!({
+                        "domain": domain,
+                        "name": name,
+                        "type": record_type,
+                        "content": content,
+                        "ttl": self.ttl,
+                    }),
+                )
+                .await
+                .context("Failed to add record")?;
+            }
+            Some(record) if same_content(&record.content, &content) => {
+                tracing::debug!("{} record already up to date on Njalla", record_type);
+            }
+            Some(record) => {
+                self.call(
+                    "edit-record",
+                    json!({
+                        "domain": domain,
+                        "id": record.id,
+                        "content": content,
+                        "ttl": self.ttl,
+                    }),
+                )
+                .await
+                .context("Failed to edit record")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
@@ -0,0 +1,108 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+
+use super::{dyndns2_check, unqualified};
+use crate::{proxy, secret::Secret};
+
+const UPDATE_URL_V4: &str = "https://ipv4.nsupdate.info/nic/update";
+const UPDATE_URL_V6: &str = "https://ipv6.nsupdate.info/nic/update";
+
+/// Time updates are suspended for after the first `abuse` response, doubled
+/// for every further one.
+const ABUSE_BACKOFF: Duration = Duration::from_secs(60 * 60);
+const MAX_ABUSE_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Update secret of the host, which is the configured `domain`
+    secret: Secret<String>,
+    #[serde(skip)]
+    lockout: Arc<Mutex<Option<Lockout>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Lockout {
+    until: Instant,
+    backoff: Duration,
+}
+
+impl Config {
+    async fn update(&self, url: &str, addr: String, name: Name) -> anyhow::Result<()> {
+        if let Some(lockout) = *self.lockout() {
+            let now = Instant::now();
+            if lockout.until > now {
+                bail!(
+                    "nsupdate.info blocked the host for abuse, not retrying for another {:?}",
+                    lockout.until - now
+                );
+            }
+        }
+        let hostname = unqualified(&name);
+        let response = proxy::client()
+            .get(url)
+            .basic_auth(&hostname, Some(self.secret.as_str()))
+            .query(&[("hostname", hostname.as_str()), ("myip", addr.as_str())])
+            .send()
+            .await
+            .context("Failed to send request to nsupdate.info")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read nsupdate.info response")?;
+        let mut lockout = self.lockout();
+        if body.trim() == "abuse" {
+            // Further updates would only extend the block, so they're
+            // suspended, until the host was unblocked in the web interface.
+            let backoff = lockout
+                .map(|lockout| (lockout.backoff * 2).min(MAX_ABUSE_BACKOFF))
+                .unwrap_or(ABUSE_BACKOFF);
+            *lockout = Some(Lockout {
+                until: Instant::now() + backoff,
+                backoff,
+            });
+            bail!(
+                "nsupdate.info blocked the host for abuse, unblock it in the web interface; retrying in {:?}",
+                backoff
+            );
+        }
+        *lockout = None;
+        dyndns2_check("nsupdate.info", status, &body)
+    }
+
+    fn lockout(&self) -> std::sync::MutexGuard<'_, Option<Lockout>> {
+        self.lockout
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, _origin: Name) -> anyhow::Result<()> {
+        self.update(UPDATE_URL_V4, addr.to_string(), name)
+            .await
+            .context("Failed to update A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, _origin: Name) -> anyhow::Result<()> {
+        self.update(UPDATE_URL_V6, addr.to_string(), name)
+            .await
+            .context("Failed to update AAAA record")
+    }
+}
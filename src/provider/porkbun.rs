@@ -0,0 +1,130 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const API_BASE: &str = "https://api.porkbun.com/api/json/v3";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    api_key: Secret<String>,
+    secret_api_key: Secret<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    status: String,
+    message: Option<String>,
+    #[serde(default)]
+    records: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    content: String,
+}
+
+impl Config {
+    async fn call(&self, path: &str, mut body: serde_json::Value) -> anyhow::Result<Response> {
+        body["apikey"] = self.api_key.as_str().into();
+        body["secretapikey"] = self.secret_api_key.as_str().into();
+        let response: Response = proxy::client()
+            .post(format!("{API_BASE}/{path}"))
+            .json(&body)
+            .send()
+            .await
+            .context("Failed to send request to Porkbun")?
+            .json()
+            .await
+            .context("Failed to parse Porkbun response")?;
+        if response.status != "SUCCESS" {
+            bail!(
+                "Porkbun returned an error: {}",
+                response.message.as_deref().unwrap_or("unknown error")
+            );
+        }
+        Ok(response)
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let domain = unqualified(&origin);
+        let subdomain = relative_name(&name, &origin)?;
+        let existing = self
+            .call(
+                &format!("dns/retrieveByNameType/{domain}/{record_type}/{subdomain}"),
+                json!({}),
+            )
+            .await
+            .context("Failed to retrieve existing records")?
+            .records;
+        if existing.is_empty() {
+            self.call(
+                &format!("dns/create/{domain}"),
+                json!({
+                    "name": subdomain,
+                    "type": record_type,
+                    "content": content,
+                    "ttl": self.ttl.to_string(),
+                }),
+            )
+            .await
+            .context("Failed to create record")?;
+        } else if existing
+            .iter()
+            .all(|record| same_content(&record.content, &content))
+        {
+            tracing::debug!("{} record already up to date on Porkbun", record_type);
+        } else {
+            self.call(
+                &format!("dns/editByNameType/{domain}/{record_type}/{subdomain}"),
+                json!({
+                    "content": content,
+                    "ttl": self.ttl.to_string(),
+                }),
+            )
+            .await
+            .context("Failed to edit record")?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    600
+}
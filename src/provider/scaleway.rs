@@ -0,0 +1,144 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use reqwest::{Method, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const API_BASE: &str = "https://api.scaleway.com/domain/v2beta1";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Secret key of an API key with the DomainsDNSFullAccess permission
+    secret_key: Secret<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+#[derive(Deserialize)]
+struct Records {
+    records: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+impl Config {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        proxy::client()
+            .request(method, format!("{API_BASE}/{path}"))
+            .header("X-Auth-Token", self.secret_key.as_str())
+    }
+
+    async fn send(request: RequestBuilder) -> anyhow::Result<Response> {
+        let response = request
+            .send()
+            .await
+            .context("Failed to send request to Scaleway")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response
+                .json::<ErrorResponse>()
+                .await
+                .map(|response| response.message)
+                .unwrap_or_else(|_| "unknown error".to_string());
+            bail!("Scaleway returned an error ({}): {}", status, message);
+        }
+        Ok(response)
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let zone = unqualified(&origin);
+        let name = relative_name(&name, &origin)?;
+        let records: Records = Self::send(
+            self.request(Method::GET, &format!("dns-zones/{zone}/records"))
+                .query(&[("name", name.as_str()), ("type", record_type)]),
+        )
+        .await
+        .context("Failed to list existing records")?
+        .json()
+        .await
+        .context("Failed to parse existing records")?;
+        let existing = records
+            .records
+            .into_iter()
+            .filter(|record| record.name == name && record.record_type == record_type)
+            .map(|record| record.data)
+            .collect::<Vec<_>>();
+        if let [existing] = existing.as_slice()
+            && same_content(existing, &content)
+        {
+            tracing::debug!("{} record already up to date on Scaleway", record_type);
+            return Ok(());
+        }
+        // A `set` change replaces all records with the given name and type at
+        // once, so there's no window in which the record is missing.
+        Self::send(
+            self.request(Method::PATCH, &format!("dns-zones/{zone}/records"))
+                .json(&json!({
+                    "changes": [{
+                        "set": {
+                            "id_fields": { "name": name, "type": record_type },
+                            "records": [{
+                                "name": name,
+                                "type": record_type,
+                                "data": content,
+                                "ttl": self.ttl,
+                            }],
+                        },
+                    }],
+                    "return_all_records": false,
+                })),
+        )
+        .await
+        .context("Failed to apply change set")?;
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
@@ -0,0 +1,144 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, bail};
+use hickory_proto::rr::Name;
+use reqwest::{Method, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{relative_name, same_content, unqualified};
+use crate::{proxy, secret::Secret};
+
+const API_BASE: &str = "https://api.vultr.com/v2";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    api_key: Secret<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+}
+
+#[derive(Deserialize)]
+struct Records {
+    records: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    id: String,
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl Config {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        proxy::client()
+            .request(method, format!("{API_BASE}/{path}"))
+            .bearer_auth(self.api_key.as_str())
+    }
+
+    async fn send(request: RequestBuilder) -> anyhow::Result<Response> {
+        let response = request
+            .send()
+            .await
+            .context("Failed to send request to Vultr")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error = response
+                .json::<ErrorResponse>()
+                .await
+                .map(|response| response.error)
+                .unwrap_or_else(|_| "unknown error".to_string());
+            bail!("Vultr returned an error ({}): {}", status, error);
+        }
+        Ok(response)
+    }
+
+    async fn replace(
+        &self,
+        record_type: &str,
+        content: String,
+        name: Name,
+        origin: Name,
+    ) -> anyhow::Result<()> {
+        let domain = unqualified(&origin);
+        let name = relative_name(&name, &origin)?;
+        let records: Records = Self::send(
+            self.request(Method::GET, &format!("domains/{domain}/records"))
+                .query(&[("per_page", "500")]),
+        )
+        .await
+        .context("Failed to list existing records")?
+        .json()
+        .await
+        .context("Failed to parse existing records")?;
+        let existing = records
+            .records
+            .into_iter()
+            .find(|record| record.name == name && record.record_type == record_type);
+        match existing {
+            None => {
+                Self::send(
+                    self.request(Method::POST, &format!("domains/{domain}/records"))
+                        .json(&json!({
+                            "name": name,
+                            "type": record_type,
+                            "data": content,
+                            "ttl": self.ttl,
+                        })),
+                )
+                .await
+                .context("Failed to create record")?;
+            }
+            Some(record) if same_content(&record.data, &content) => {
+                tracing::debug!("{} record already up to date on Vultr", record_type);
+            }
+            Some(record) => {
+                Self::send(
+                    self.request(
+                        Method::PATCH,
+                        &format!("domains/{domain}/records/{}", record.id),
+                    )
+                    .json(&json!({ "data": content, "ttl": self.ttl })),
+                )
+                .await
+                .context("Failed to update record")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn set_ipv4(&self, addr: Ipv4Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("A", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace A record")
+    }
+
+    pub async fn set_ipv6(&self, addr: Ipv6Addr, name: Name, origin: Name) -> anyhow::Result<()> {
+        self.replace("AAAA", addr.to_string(), name, origin)
+            .await
+            .context("Failed to replace AAAA record")
+    }
+}
+
+fn default_ttl() -> u32 {
+    300
+}
@@ -0,0 +1,142 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! The proxy the HTTP clients of the sources, providers and notification
+//! channels connect through.
+
+use std::{future::Future, sync::RwLock};
+
+use anyhow::{Result, anyhow, bail};
+use reqwest::NoProxy;
+use serde::{Deserialize, Serialize};
+
+use crate::secret::Secret;
+
+/// The configured proxy, shared by all clients, as they're created deep
+/// within the providers and notification channels.
+static PROXY: RwLock<Shared> = RwLock::new(Shared {
+    proxy: None,
+    client: None,
+});
+
+tokio::task_local! {
+    /// The proxy of a config that is still being validated, overriding the
+    /// configured one for the clients created by the validation.
+    static SCOPED: Option<Proxy>;
+}
+
+struct Shared {
+    proxy: Option<Proxy>,
+    /// Client with the proxy, built on first use, so its connections are
+    /// reused until the proxy changes.
+    client: Option<reqwest::Client>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Proxy {
+    /// `http://`, `https://`, `socks5://` or `socks5h://` URL of the proxy,
+    /// optionally with credentials
+    url: Secret<String>,
+    /// Hosts connected to directly, in the format of `NO_PROXY`
+    #[serde(default)]
+    no_proxy: Vec<String>,
+}
+
+impl Proxy {
+    /// Check that the URL is usable as a proxy.
+    pub fn validate(&self) -> Result<()> {
+        let scheme = self.url.split_once("://").map(|(scheme, _)| scheme);
+        if !matches!(scheme, Some("http" | "https" | "socks5" | "socks5h")) {
+            bail!("The proxy URL has to start with http://, https://, socks5:// or socks5h://");
+        }
+        self.to_reqwest().map(drop)
+    }
+
+    fn to_reqwest(&self) -> Result<reqwest::Proxy> {
+        // The URL may contain credentials, so it's kept out of errors.
+        let proxy =
+            reqwest::Proxy::all(self.url.as_str()).map_err(|_| anyhow!("Invalid proxy URL"))?;
+        Ok(proxy.no_proxy(NoProxy::from_string(&self.no_proxy.join(","))))
+    }
+}
+
+/// Use `proxy` for all clients created afterwards, or the proxy set in the
+/// environment if it's `None`.
+pub fn set(proxy: Option<Proxy>) {
+    let mut shared = PROXY
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if shared.proxy != proxy {
+        shared.proxy = proxy;
+        shared.client = None;
+    }
+}
+
+/// Run `future` with `proxy` instead of the configured one, without changing
+/// the proxy used elsewhere.
+pub async fn scope<F: Future>(proxy: Option<Proxy>, future: F) -> F::Output {
+    SCOPED.scope(proxy, future).await
+}
+
+/// A client builder using the configured proxy, or the one set in the
+/// `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY` environment
+/// variables if none is configured.
+pub fn builder() -> reqwest::ClientBuilder {
+    SCOPED
+        .try_with(|proxy| with(proxy.as_ref()))
+        .unwrap_or_else(|_| {
+            with(
+                PROXY
+                    .read()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .proxy
+                    .as_ref(),
+            )
+        })
+}
+
+/// A client builder using `proxy`, or the proxy set in the environment.
+pub(crate) fn with(proxy: Option<&Proxy>) -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder();
+    // Invalid proxies are rejected with the config, so this never falls back.
+    match proxy.map(Proxy::to_reqwest) {
+        Some(Ok(proxy)) => builder.proxy(proxy),
+        _ => builder,
+    }
+}
+
+/// A client using the configured proxy.
+pub fn client() -> reqwest::Client {
+    if SCOPED.try_with(|_| ()).is_ok() {
+        return build(builder());
+    }
+    if let Some(client) = &PROXY
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .client
+    {
+        return client.clone();
+    }
+    let mut shared = PROXY
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let proxy = shared.proxy.clone();
+    shared
+        .client
+        .get_or_insert_with(|| build(with(proxy.as_ref())))
+        .clone()
+}
+
+fn build(builder: reqwest::ClientBuilder) -> reqwest::Client {
+    builder
+        .build()
+        .expect("clients can be built with the validated proxy")
+}
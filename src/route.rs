@@ -0,0 +1,81 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Default routes of the host, as found in the kernel's main routing table.
+
+use std::{fs::read_to_string, net::Ipv4Addr};
+
+use anyhow::{Context, Result, anyhow};
+use public_ip::Version;
+
+const ROUTES_V4: &str = "/proc/net/route";
+const ROUTES_V6: &str = "/proc/net/ipv6_route";
+/// `RTF_UP`
+const FLAG_UP: u32 = 0x0001;
+/// `RTF_REJECT`, set on the unreachable default route the kernel adds
+/// without IPv6 connectivity
+const FLAG_REJECT: u32 = 0x0200;
+
+/// Fields of the IPv4 default routes, skipping the header.
+fn defaults_v4(routes: &str) -> impl Iterator<Item = Vec<&str>> {
+    routes
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.get(1) == Some(&"00000000") && fields.get(7) == Some(&"00000000"))
+}
+
+/// The gateway of the IPv4 default route.
+pub fn default_gateway() -> Result<Ipv4Addr> {
+    let routes =
+        read_to_string(ROUTES_V4).with_context(|| format!("Failed to read {ROUTES_V4}"))?;
+    defaults_v4(&routes)
+        .find_map(|fields| {
+            // The kernel prints the address in host byte order.
+            let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            Some(Ipv4Addr::from(gateway.to_le_bytes()))
+        })
+        .ok_or_else(|| anyhow!("No IPv4 default route found"))
+}
+
+/// Whether there's a usable default route for the given family. If the
+/// routing table can't be read, the host is assumed to be online.
+pub fn has_default(version: Version) -> bool {
+    let path = match version {
+        Version::V6 => ROUTES_V6,
+        _ => ROUTES_V4,
+    };
+    let routes = match read_to_string(path) {
+        Ok(routes) => routes,
+        Err(error) => {
+            tracing::debug!("Failed to read {}, assuming to be online: {}", path, error);
+            return true;
+        }
+    };
+    let flags = |field: Option<&&str>| field.and_then(|flags| u32::from_str_radix(flags, 16).ok());
+    match version {
+        Version::V6 => routes.lines().any(|line| {
+            // Destination, prefix length, source, source prefix length, next
+            // hop, metric, reference count, use count, flags and device
+            let fields: Vec<_> = line.split_whitespace().collect();
+            fields
+                .first()
+                .is_some_and(|dest| dest.bytes().all(|b| b == b'0'))
+                && fields.get(1) == Some(&"00")
+                && flags(fields.get(8))
+                    .is_some_and(|flags| flags & FLAG_UP != 0 && flags & FLAG_REJECT == 0)
+        }),
+        _ => defaults_v4(&routes).any(|fields| {
+            flags(fields.get(3))
+                .is_some_and(|flags| flags & FLAG_UP != 0 && flags & FLAG_REJECT == 0)
+        }),
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Landlock and seccomp restrictions of the daemon, limiting what can be done
+//! with the DNS credentials it holds if it's ever compromised.
+
+use std::{
+    collections::BTreeMap,
+    env::{self, consts::ARCH},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use landlock::{
+    ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    path_beneath_rules,
+};
+use nix::libc;
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+
+use crate::{Config, cache};
+
+/// Directories the system libraries read from, like the resolver config, the
+/// NSS modules and the routing tables. The resolver config may be a symlink
+/// out of these, see [`RESOLV_CONF`].
+const SYSTEM: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/proc", "/sys", "/dev"];
+
+/// The resolver config, a symlink into `/run/systemd/resolve` with
+/// systemd-resolved. Landlock checks the target, so its directory is
+/// readable as well.
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// The system calls of the network loop, the SQLite database and the threads
+/// of the runtime.
+const SYSCALLS: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_close,
+    libc::SYS_openat,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_getcwd,
+    libc::SYS_fcntl,
+    libc::SYS_flock,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat2,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Threads and signals
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_membarrier,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_restart_syscall,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    // Event loop
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    // Network
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept4,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    // Time and randomness
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    libc::SYS_uname,
+];
+
+/// The older variants of the system calls above, which x86_64 still has.
+#[cfg(target_arch = "x86_64")]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_getdents,
+    libc::SYS_mkdir,
+    libc::SYS_unlink,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_chmod,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_epoll_wait,
+    libc::SYS_arch_prctl,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+/// The `ioctl`s used to make sockets non-blocking and check for terminals.
+const IOCTLS: &[libc::c_ulong] = &[libc::FIONBIO, libc::FIONREAD, libc::FIOCLEX, libc::TCGETS];
+
+/// Restrict the filesystem access of the process to reading the system
+/// directories, the resolver config, the files the config refers to and the
+/// systemd credentials, and writing the state, the audit log, the metrics and
+/// the control socket.
+///
+/// Landlock only restricts the calling thread and the threads it starts
+/// afterwards, so this has to be called before the runtime is started.
+pub fn restrict_filesystem(config: &Config, cache_dir: &Path, config_path: &Path) -> Result<()> {
+    let mut writable = Vec::new();
+    match (config.cache, &config.state_db) {
+        (cache::Mode::Memory, _) => {}
+        (cache::Mode::File, Some(path)) => writable.push(parent(path)),
+        (cache::Mode::File, None) => {
+            // Rules can only be added for paths that exist already.
+            cache::create_dir(cache_dir)?;
+            writable.push(cache_dir.to_owned());
+        }
+    }
+    writable.extend(config.audit_log.as_deref().map(parent));
+    writable.extend(config.prometheus_textfile.as_deref().map(parent));
+    writable.extend(config.control_socket.as_deref().map(parent));
+    let readable = SYSTEM
+        .iter()
+        .map(PathBuf::from)
+        .chain(std::iter::once(config_path.to_owned()))
+        .chain(fs::canonicalize(RESOLV_CONF).ok().map(|path| parent(&path)))
+        // Credentials are read again when the config is reloaded.
+        .chain(env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from))
+        .chain(
+            config
+                .dns_provider_config
+                .iter()
+                .flat_map(|provider| provider.secret_files())
+                .map(Path::to_owned),
+        )
+        .chain(
+            config
+                .dns_provider_config
+                .iter()
+                .filter_map(|provider| provider.trust_anchor())
+                .map(Path::to_owned),
+        );
+
+    let abi = ABI::V6;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(readable, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules(&writable, AccessFs::from_all(abi)))?
+        .restrict_self()
+        .context("Failed to restrict filesystem access")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => tracing::debug!("Restricted filesystem access"),
+        RulesetStatus::PartiallyEnforced => {
+            tracing::info!("Restricted filesystem access, as far as the kernel supports it");
+        }
+        RulesetStatus::NotEnforced => {
+            tracing::warn!(
+                "Landlock isn't supported by the kernel, not restricting filesystem access"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Refuse all system calls the daemon doesn't need once it's initialized,
+/// most importantly starting other programs, in all threads of the process.
+/// Refused system calls fail with `EPERM`.
+pub fn restrict_syscalls() -> Result<()> {
+    let arch = ARCH
+        .try_into()
+        .map_err(|_| anyhow!("System calls can't be restricted on {ARCH}"))?;
+    let ioctl = IOCTLS
+        .iter()
+        .map(|request| {
+            SeccompRule::new(vec![SeccompCondition::new(
+                1,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::Eq,
+                *request,
+            )?])
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let rules: BTreeMap<_, _> = SYSCALLS
+        .iter()
+        .chain(LEGACY_SYSCALLS)
+        .map(|syscall| (*syscall, Vec::new()))
+        .chain(std::iter::once((libc::SYS_ioctl, ioctl)))
+        .collect();
+    let filter: BpfProgram = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        arch,
+    )?
+    .try_into()?;
+    seccompiler::apply_filter_all_threads(&filter).context("Failed to restrict system calls")?;
+    tracing::debug!("Restricted system calls");
+    Ok(())
+}
+
+/// The directory containing `path`, which is where files next to it, like
+/// SQLite's journal or a replaced socket, are created.
+fn parent(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+        _ => PathBuf::from("."),
+    }
+}
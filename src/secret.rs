@@ -0,0 +1,159 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Secrets like keys and tokens, kept out of logs, errors and serialized
+//! configs.
+//!
+//! In the config, a secret is either given directly, or as the name of a
+//! systemd credential, e.g. `token = { credential = "dynv6-token" }`, which is
+//! read from `$CREDENTIALS_DIRECTORY`. Those are passed to the service with
+//! `LoadCredential=` or `SetCredentialEncrypted=`, so the secrets don't have
+//! to be in the config at all. With a `[vault]` section, secrets can be read
+//! from Vault as well, see [`crate::vault`].
+
+use std::{
+    env,
+    fmt::{self, Debug},
+    fs::read_to_string,
+    ops::Deref,
+    path::Path,
+};
+
+use anyhow::{Context, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, MapAccess, Visitor, value::MapAccessDeserializer},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::vault;
+
+/// What's shown instead of a secret
+const REDACTED: &str = "[redacted]";
+
+/// A secret, wiped from memory when dropped, and redacted when it's formatted
+/// with `Debug` or serialized. The value itself is only available through
+/// [`Deref`].
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret<T: Zeroize>(Zeroizing<T>);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(Zeroizing::new(value))
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Zeroize> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T: Zeroize> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret<String> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(SecretVisitor)
+    }
+}
+
+struct SecretVisitor;
+
+impl<'de> Visitor<'de> for SecretVisitor {
+    type Value = Secret<String>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, or a table referring to a systemd credential or a Vault secret")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(Secret::new(value.to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Self::Value, E> {
+        Ok(Secret::new(value))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Reference {
+            credential: Option<String>,
+            vault: Option<String>,
+            field: Option<String>,
+        }
+        let reference = Reference::deserialize(MapAccessDeserializer::new(map))?;
+        let secret = match reference {
+            Reference {
+                credential: Some(name),
+                vault: None,
+                field: None,
+            } => credential(&name),
+            Reference {
+                credential: None,
+                vault: Some(path),
+                field: Some(field),
+            } => vault::secret(&path, &field),
+            _ => {
+                return Err(de::Error::custom(
+                    "expected either `credential`, or `vault` and `field`",
+                ));
+            }
+        };
+        secret.map_err(|error| de::Error::custom(format!("{error:#}")))
+    }
+}
+
+/// Read the systemd credential `name`, without the trailing newline files
+/// usually end with.
+fn credential(name: &str) -> anyhow::Result<Secret<String>> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("Invalid credential name {name:?}");
+    }
+    let dir = env::var_os("CREDENTIALS_DIRECTORY").with_context(|| {
+        format!("Can't read the credential {name}, as $CREDENTIALS_DIRECTORY isn't set")
+    })?;
+    let mut value = Zeroizing::new(
+        read_to_string(Path::new(&dir).join(name))
+            .with_context(|| format!("Failed to read the credential {name}"))?,
+    );
+    let len = value.trim_end_matches(['\n', '\r']).len();
+    value.truncate(len);
+    Ok(Secret::new(std::mem::take(&mut *value)))
+}
+
+/// Deserialize a base64 encoded secret, like a TSIG key.
+pub fn base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Secret<Vec<u8>>, D::Error> {
+    let encoded = Secret::<String>::deserialize(deserializer)?;
+    STANDARD
+        .decode(encoded.trim())
+        .map(Secret::new)
+        .map_err(de::Error::custom)
+}
@@ -0,0 +1,972 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! dyndns2 endpoint, the protocol of DynDNS that most routers speak, so they
+//! can push their addresses to dyndnsd, which publishes them through its
+//! providers. Clients send `GET /nic/update?hostname=...&myip=...` with HTTP
+//! basic auth, and get a line with a return code like `good` or `nochg` back
+//! for each hostname.
+//!
+//! The listener parses and authenticates the requests, off the daemon's loop
+//! as the password hashes take a while to check. They're handled by the
+//! daemon, so a reloaded config applies to them right away, except for the
+//! passwords, which are kept from when the endpoint started listening.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{self, Display},
+    hash::Hash,
+    net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener as StdListener},
+    num::NonZeroU32,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+};
+use hickory_proto::rr::Name;
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, RETRY_AFTER, WWW_AUTHENTICATE},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use ring::{
+    digest,
+    pbkdf2::{self, PBKDF2_HMAC_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, serde_as};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+};
+use zeroize::Zeroizing;
+
+use crate::{duration::HumanDuration, secret::Secret};
+
+/// Most hostnames a single request may update, as with DynDNS
+const MAX_HOSTNAMES: usize = 20;
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// Address and port the endpoint listens on
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// Networks clients may connect from, all of them if empty
+    #[serde(default)]
+    pub allowed_networks: Vec<IpNet>,
+    /// Requests each client address may make, counted before they're
+    /// authenticated
+    #[serde(default = "default_client_limit")]
+    pub client_limit: RateLimit,
+    /// Failed authentications each client address may make
+    #[serde(default = "default_auth_failure_limit")]
+    pub auth_failure_limit: RateLimit,
+    /// Updates each hostname may get
+    #[serde(default = "default_hostname_limit")]
+    pub hostname_limit: RateLimit,
+    /// Clients allowed to push, each with its own credentials and names
+    pub users: Vec<User>,
+}
+
+/// A token bucket, allowing `burst` requests at once, and one more every
+/// `interval` after that.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub burst: u32,
+    #[serde_as(as = "HumanDuration")]
+    pub interval: Duration,
+}
+
+impl RateLimit {
+    fn validate(&self) -> Result<()> {
+        if self.burst == 0 {
+            bail!("The burst has to allow at least one request");
+        }
+        if self.interval.is_zero() {
+            bail!("The interval can't be zero");
+        }
+        Ok(())
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct User {
+    pub username: String,
+    /// Hash of the password, as printed by `dyndnsd hash-password`
+    #[serde_as(as = "DisplayFromStr")]
+    pub password_hash: PasswordHash,
+    /// Names the user may update, relative to the zone unless they end with
+    /// a dot
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub hostnames: Vec<Name>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        if self.users.is_empty() {
+            bail!("No users are configured");
+        }
+        self.client_limit
+            .validate()
+            .context("Invalid client_limit")?;
+        self.auth_failure_limit
+            .validate()
+            .context("Invalid auth_failure_limit")?;
+        self.hostname_limit
+            .validate()
+            .context("Invalid hostname_limit")?;
+        for (i, user) in self.users.iter().enumerate() {
+            if user.username.contains(':') {
+                bail!(
+                    "The username {} contains a colon, which basic auth separates it from the password with",
+                    user.username
+                );
+            }
+            if self.users[..i]
+                .iter()
+                .any(|other| other.username == user.username)
+            {
+                bail!("The username {} is used more than once", user.username);
+            }
+            if user.hostnames.is_empty() {
+                bail!("No hostnames are configured for {}", user.username);
+            }
+        }
+        Ok(())
+    }
+
+    /// The names any user may update.
+    pub fn hostnames(&self) -> impl Iterator<Item = &Name> {
+        self.users.iter().flat_map(|user| &user.hostnames)
+    }
+}
+
+/// PBKDF2-HMAC-SHA256 hash of a password, in the PHC string format
+/// `$pbkdf2-sha256$i=<iterations>$<salt>$<hash>`, with the salt and the hash
+/// in unpadded base64.
+#[derive(Debug, Clone)]
+pub struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Iterations of new hashes, as recommended by OWASP
+    const ITERATIONS: NonZeroU32 = NonZeroU32::new(600_000).expect("not zero");
+    const PREFIX: &str = "$pbkdf2-sha256$i=";
+
+    /// Hash `password` with a random salt.
+    pub fn new(password: &str) -> Result<Self> {
+        let mut salt = vec![0; 16];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| anyhow!("Failed to generate a salt"))?;
+        let mut hash = vec![0; digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(
+            PBKDF2_HMAC_SHA256,
+            Self::ITERATIONS,
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+        Ok(Self {
+            iterations: Self::ITERATIONS,
+            salt,
+            hash,
+        })
+    }
+
+    /// A hash no password matches, taking as long to check as a real one.
+    fn placeholder() -> Self {
+        Self {
+            iterations: Self::ITERATIONS,
+            salt: vec![0; 16],
+            hash: vec![0; digest::SHA256_OUTPUT_LEN],
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        let parse = || {
+            let (iterations, rest) = string.strip_prefix(Self::PREFIX)?.split_once('$')?;
+            let (salt, hash) = rest.split_once('$')?;
+            Some(Self {
+                iterations: iterations.parse().ok()?,
+                salt: STANDARD_NO_PAD.decode(salt).ok()?,
+                hash: STANDARD_NO_PAD.decode(hash).ok()?,
+            })
+        };
+        parse()
+            .filter(|parsed| !parsed.hash.is_empty())
+            .ok_or_else(|| anyhow!("Not a password hash printed by dyndnsd hash-password"))
+    }
+}
+
+impl Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}${}${}",
+            Self::PREFIX,
+            self.iterations,
+            STANDARD_NO_PAD.encode(&self.salt),
+            STANDARD_NO_PAD.encode(&self.hash)
+        )
+    }
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 8245)
+}
+
+fn default_client_limit() -> RateLimit {
+    RateLimit {
+        burst: 20,
+        interval: Duration::from_secs(30),
+    }
+}
+
+fn default_auth_failure_limit() -> RateLimit {
+    RateLimit {
+        burst: 5,
+        interval: Duration::from_secs(60),
+    }
+}
+
+fn default_hostname_limit() -> RateLimit {
+    RateLimit {
+        burst: 5,
+        interval: Duration::from_secs(5 * 60),
+    }
+}
+
+/// Token buckets of the requests of each client or hostname.
+pub struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K> Default for Buckets<K> {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The tokens in the bucket at `now`.
+    fn tokens_at(&self, limit: RateLimit, now: Instant) -> f64 {
+        let refilled =
+            now.duration_since(self.updated).as_secs_f64() / limit.interval.as_secs_f64();
+        (self.tokens + refilled).min(limit.burst.into())
+    }
+
+    /// How long it takes from `now` until the bucket has a token again.
+    fn wait_at(&self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        let tokens = self.tokens_at(limit, now);
+        if tokens < 1.0 {
+            return Err(limit.interval.mul_f64(1.0 - tokens));
+        }
+        Ok(())
+    }
+}
+
+impl<K: Hash + Eq + Clone> Buckets<K> {
+    /// Most buckets kept, the full ones are dropped beyond that, and the
+    /// least recently used ones if none are full
+    const MAX_LEN: usize = 1024;
+
+    /// Take a token from the bucket of `key`, or return how long it takes
+    /// until the next one is available.
+    pub fn take(&mut self, key: K, limit: RateLimit) -> Result<(), Duration> {
+        self.take_at(key, limit, Instant::now())
+    }
+
+    /// Return how long it takes until the bucket of `key` has a token,
+    /// without taking it.
+    pub fn peek(&self, key: &K, limit: RateLimit) -> Result<(), Duration> {
+        self.peek_at(key, limit, Instant::now())
+    }
+
+    fn peek_at(&self, key: &K, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        match self.buckets.get(key) {
+            Some(bucket) => bucket.wait_at(limit, now),
+            None => Ok(()),
+        }
+    }
+
+    fn take_at(&mut self, key: K, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        if self.buckets.len() >= Self::MAX_LEN && !self.buckets.contains_key(&key) {
+            // Full buckets are the same as missing ones.
+            self.buckets
+                .retain(|_, bucket| bucket.tokens_at(limit, now) < limit.burst.into());
+            if self.buckets.len() >= Self::MAX_LEN
+                && let Some(oldest) = self
+                    .buckets
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.updated)
+                    .map(|(key, _)| key.clone())
+            {
+                self.buckets.remove(&oldest);
+            }
+        }
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst.into(),
+            updated: now,
+        });
+        bucket.tokens = bucket.tokens_at(limit, now);
+        bucket.updated = now;
+        bucket.wait_at(limit, now)?;
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// An authenticated update request, waiting for the daemon to handle it.
+pub struct Push {
+    pub username: String,
+    /// Hostnames as given by the client, fully qualified or not
+    pub hostnames: Vec<String>,
+    /// Addresses to publish, of one or both families
+    pub addrs: Vec<IpAddr>,
+    reply: oneshot::Sender<Vec<Answer>>,
+}
+
+impl Push {
+    /// Look up the user in `config`, returning the configured name for each
+    /// hostname the user may update, or the answer for it if they may not.
+    pub fn authorize(&self, config: Option<&Config>) -> Vec<Result<Name, Answer>> {
+        let user = config.map(|config| {
+            config
+                .users
+                .iter()
+                .find(|user| user.username == self.username)
+        });
+        let denied = match user {
+            None => Answer::ServerError,
+            Some(None) => {
+                tracing::warn!(
+                    "Rejected update by {:?}, who was removed from the config",
+                    self.username
+                );
+                Answer::BadAuth
+            }
+            Some(Some(_)) if self.hostnames.len() > MAX_HOSTNAMES => Answer::NumHost,
+            Some(Some(user)) => return self.names(user),
+        };
+        vec![Err(denied); self.hostnames.len()]
+    }
+
+    /// The names of `user` the hostnames refer to.
+    fn names(&self, user: &User) -> Vec<Result<Name, Answer>> {
+        self.hostnames
+            .iter()
+            .map(|hostname| {
+                let mut name = Name::from_str(hostname)
+                    .ok()
+                    .filter(|name| name.num_labels() > 1)
+                    .ok_or(Answer::NotFqdn)?;
+                // Clients don't end the names with a dot, but they're fully
+                // qualified all the same.
+                name.set_fqdn(true);
+                user.hostnames
+                    .iter()
+                    .find(|allowed| **allowed == name)
+                    .cloned()
+                    .ok_or(Answer::NoHost)
+            })
+            .collect()
+    }
+
+    /// Answer the client, with one answer per hostname.
+    pub fn reply(self, answers: Vec<Answer>) {
+        // The client may have disconnected in the meantime.
+        let _ = self.reply.send(answers);
+    }
+}
+
+/// The dyndns2 return code for a hostname.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Answer {
+    /// The records were set to the addresses
+    Good(Vec<IpAddr>),
+    /// The records were already set to the addresses
+    NoChange(Vec<IpAddr>),
+    BadAuth,
+    /// The hostname isn't a fully qualified domain name
+    NotFqdn,
+    /// The client may not update the hostname
+    NoHost,
+    /// The hostname was updated too often
+    Abuse,
+    /// Too many hostnames were given at once
+    NumHost,
+    /// The request was malformed
+    BadAgent,
+    /// Setting the records failed
+    DnsErr,
+    ServerError,
+}
+
+impl Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |addrs: &[IpAddr]| {
+            addrs
+                .iter()
+                .map(IpAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        match self {
+            Answer::Good(addrs) => write!(f, "good {}", join(addrs)),
+            Answer::NoChange(addrs) => write!(f, "nochg {}", join(addrs)),
+            Answer::BadAuth => f.write_str("badauth"),
+            Answer::NotFqdn => f.write_str("notfqdn"),
+            Answer::NoHost => f.write_str("nohost"),
+            Answer::Abuse => f.write_str("abuse"),
+            Answer::NumHost => f.write_str("numhost"),
+            Answer::BadAgent => f.write_str("badagent"),
+            Answer::DnsErr => f.write_str("dnserr"),
+            Answer::ServerError => f.write_str("911"),
+        }
+    }
+}
+
+/// What's checked of every request before it's passed to the daemon, as set
+/// when the endpoint started listening.
+struct Guard {
+    allowed_networks: Vec<IpNet>,
+    client_limit: RateLimit,
+    clients: Mutex<Buckets<IpAddr>>,
+    auth_failure_limit: RateLimit,
+    failures: Mutex<Buckets<IpAddr>>,
+    /// Password hash of each user
+    users: HashMap<String, PasswordHash>,
+}
+
+impl Guard {
+    /// Whether `password` is the one of the user with `username`. Takes
+    /// long on purpose, so it's best run on a blocking thread.
+    fn authenticate(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(hash) => hash.verify(password),
+            None => {
+                // Hashing anyway keeps the time taken from revealing which
+                // users exist.
+                PasswordHash::placeholder().verify(password);
+                false
+            }
+        }
+    }
+
+    /// The response to a request from `peer`, unless it's let through.
+    fn check(&self, peer: IpAddr) -> Option<Response<Full<Bytes>>> {
+        if !self.allowed_networks.is_empty()
+            && !self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(&peer))
+        {
+            tracing::warn!("Refused update from {}, which isn't allowed", peer);
+            return Some(response(
+                StatusCode::FORBIDDEN,
+                &Answer::BadAuth.to_string(),
+            ));
+        }
+        let mut clients = self
+            .clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Err(wait) = clients.take(client(peer), self.client_limit) else {
+            return None;
+        };
+        tracing::warn!("Refused update from {}, which sends too many", peer);
+        Some(too_many(wait))
+    }
+
+    /// The response to a request from `peer`, if it failed to authenticate
+    /// too often to have its password checked.
+    fn check_failures(&self, peer: IpAddr) -> Option<Response<Full<Bytes>>> {
+        let failures = self
+            .failures
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let wait = failures
+            .peek(&client(peer), self.auth_failure_limit)
+            .err()?;
+        tracing::warn!(
+            "Refused update from {}, which failed to authenticate too often",
+            peer
+        );
+        Some(too_many(wait))
+    }
+
+    /// Count a failed authentication of `peer`.
+    fn failed(&self, peer: IpAddr) {
+        let mut failures = self
+            .failures
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Already beyond the limit otherwise, which the next request is
+        // refused for.
+        let _ = failures.take(client(peer), self.auth_failure_limit);
+    }
+}
+
+/// The address the requests of `peer` are counted for. IPv6 clients usually
+/// have a whole /64 to pick addresses from, so that's counted as one.
+fn client(peer: IpAddr) -> IpAddr {
+    match peer.to_canonical() {
+        IpAddr::V6(addr) => Ipv6Addr::from(u128::from(addr) & (u128::MAX << 64)).into(),
+        addr => addr,
+    }
+}
+
+/// Answer with `911`, asking the client to retry after `wait`.
+fn too_many(wait: Duration) -> Response<Full<Bytes>> {
+    let mut response = response(
+        StatusCode::TOO_MANY_REQUESTS,
+        &Answer::ServerError.to_string(),
+    );
+    response.headers_mut().insert(
+        RETRY_AFTER,
+        (wait.as_secs() + 1)
+            .to_string()
+            .parse()
+            .expect("valid header"),
+    );
+    response
+}
+
+/// Listen on the configured address, passing the authenticated update
+/// requests to the returned channel. The allowed networks, the client limit
+/// and the passwords are kept from `config`, the rest is up to the daemon.
+pub fn listen(config: &Config) -> Result<mpsc::Receiver<Push>> {
+    let addr = config.listen;
+    let listener = StdListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .with_context(|| format!("Failed to listen on {addr}"))?;
+    tracing::info!("Listening for dyndns2 updates on {}", addr);
+    let guard = Arc::new(Guard {
+        allowed_networks: config.allowed_networks.clone(),
+        client_limit: config.client_limit,
+        clients: Mutex::default(),
+        auth_failure_limit: config.auth_failure_limit,
+        failures: Mutex::default(),
+        users: config
+            .users
+            .iter()
+            .map(|user| (user.username.clone(), user.password_hash.clone()))
+            .collect(),
+    });
+    let (pushes, receiver) = mpsc::channel(8);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let pushes = pushes.clone();
+                    let guard = guard.clone();
+                    let service = service_fn(move |request| {
+                        let peer = peer.ip().to_canonical();
+                        let refused = guard.check(peer);
+                        let pushes = pushes.clone();
+                        let guard = guard.clone();
+                        async move {
+                            match refused {
+                                Some(response) => Ok(response),
+                                None => handle(request, peer, &guard, pushes).await,
+                            }
+                        }
+                    });
+                    tokio::spawn(async move {
+                        let connection =
+                            http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                        if let Err(error) = connection.await {
+                            tracing::debug!("Connection from {} failed: {}", peer, error);
+                        }
+                    });
+                }
+                Err(error) => tracing::error!("Failed to accept dyndns2 connection: {}", error),
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    peer: IpAddr,
+    guard: &Arc<Guard>,
+    pushes: mpsc::Sender<Push>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != "/nic/update" {
+        return Ok(response(StatusCode::NOT_FOUND, "Not found"));
+    }
+    if request.method() != Method::GET {
+        return Ok(response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+        ));
+    }
+    let Some((username, password)) = credentials(request.headers()) else {
+        let mut response = response(StatusCode::UNAUTHORIZED, &Answer::BadAuth.to_string());
+        response.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            "Basic realm=\"dyndnsd\"".parse().expect("valid header"),
+        );
+        return Ok(response);
+    };
+    let query = request.uri().query().unwrap_or_default();
+    let Ok((hostnames, addrs)) = parse(query, peer) else {
+        return Ok(response(StatusCode::OK, &Answer::BadAgent.to_string()));
+    };
+    if hostnames.is_empty() {
+        return Ok(response(StatusCode::OK, &Answer::NotFqdn.to_string()));
+    }
+    if let Some(response) = guard.check_failures(peer) {
+        return Ok(response);
+    }
+    let authenticated = {
+        let guard = guard.clone();
+        let username = username.clone();
+        tokio::task::spawn_blocking(move || guard.authenticate(&username, &password))
+    };
+    match authenticated.await {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::warn!("Rejected update by {:?} with wrong credentials", username);
+            guard.failed(peer);
+            let answers = vec![Answer::BadAuth.to_string(); hostnames.len()];
+            return Ok(response(StatusCode::OK, &answers.join("\n")));
+        }
+        Err(_) => return Ok(response(StatusCode::OK, &Answer::ServerError.to_string())),
+    }
+    let (reply, answers) = oneshot::channel();
+    let push = Push {
+        username,
+        hostnames,
+        addrs,
+        reply,
+    };
+    if pushes.send(push).await.is_err() {
+        // The daemon is shutting down.
+        return Ok(response(StatusCode::OK, &Answer::ServerError.to_string()));
+    }
+    let body = match answers.await {
+        Ok(answers) => answers
+            .iter()
+            .map(Answer::to_string)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Err(_) => Answer::ServerError.to_string(),
+    };
+    Ok(response(StatusCode::OK, &body))
+}
+
+/// The username and password of the basic auth of a request.
+fn credentials(headers: &HeaderMap) -> Option<(String, Secret<String>)> {
+    let header = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = Zeroizing::new(STANDARD.decode(encoded.trim()).ok()?);
+    let decoded = std::str::from_utf8(&decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), Secret::new(password.to_owned())))
+}
+
+/// The hostnames and addresses of an update request. Without `myip` or
+/// `myipv6`, the address of the client is published.
+fn parse(query: &str, peer: IpAddr) -> Result<(Vec<String>, Vec<IpAddr>)> {
+    let mut hostnames = Vec::new();
+    let mut addrs = Vec::new();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let values = value
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty());
+        match key.as_ref() {
+            "hostname" => hostnames.extend(values.map(str::to_owned)),
+            "myip" | "myipv6" => {
+                for value in values {
+                    addrs.push(value.parse().context("Invalid address")?);
+                }
+            }
+            _ => {}
+        }
+    }
+    if addrs.is_empty() {
+        addrs.push(peer);
+    }
+    addrs.sort();
+    addrs.dedup();
+    Ok((hostnames, addrs))
+}
+
+fn response(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(format!("{body}\n"))));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        "text/plain; charset=utf-8".parse().expect("valid header"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hash of `password` with few iterations, as debug builds take seconds
+    /// for the real number.
+    fn quick_hash(password: &str) -> PasswordHash {
+        let iterations = NonZeroU32::new(1000).unwrap();
+        let salt = b"0123456789abcdef".to_vec();
+        let mut hash = vec![0; digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(
+            PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+        PasswordHash {
+            iterations,
+            salt,
+            hash,
+        }
+    }
+
+    #[test]
+    fn password_hash_round_trip() {
+        let printed = quick_hash("hunter2").to_string();
+        assert!(printed.starts_with("$pbkdf2-sha256$i=1000$MDEyMzQ1Njc4OWFiY2RlZg$"));
+        let parsed: PasswordHash = printed.parse().unwrap();
+        assert_eq!(parsed.to_string(), printed);
+        assert!(parsed.verify("hunter2"));
+        assert!(!parsed.verify("hunter3"));
+        assert!(!parsed.verify(""));
+    }
+
+    #[test]
+    fn password_hash_rejects_malformed() {
+        for malformed in [
+            "",
+            "hunter2",
+            "$pbkdf2-sha256$i=1000$c2FsdA",
+            "$pbkdf2-sha256$i=1000$c2FsdA$",
+            "$pbkdf2-sha256$i=0$c2FsdA$aGFzaA",
+            "$pbkdf2-sha256$i=many$c2FsdA$aGFzaA",
+            "$pbkdf2-sha256$i=1000$c2FsdA$not base64!",
+            "$pbkdf2-sha512$i=1000$c2FsdA$aGFzaA",
+            "$argon2id$v=19$m=65536,t=3,p=4$c2FsdA$aGFzaA",
+        ] {
+            assert!(
+                malformed.parse::<PasswordHash>().is_err(),
+                "{malformed:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_addresses() {
+        let peer: IpAddr = "198.51.100.1".parse().unwrap();
+        let (hostnames, addrs) = parse(
+            "hostname=a.example.com,b.example.com&myip=192.0.2.1&myipv6=2001:db8::1",
+            peer,
+        )
+        .unwrap();
+        assert_eq!(hostnames, ["a.example.com", "b.example.com"]);
+        assert_eq!(
+            addrs,
+            [
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ]
+        );
+        let (_, addrs) = parse("hostname=a&myip=192.0.2.2,%202001:db8::2,192.0.2.2", peer).unwrap();
+        assert_eq!(
+            addrs,
+            [
+                "192.0.2.2".parse::<IpAddr>().unwrap(),
+                "2001:db8::2".parse().unwrap()
+            ]
+        );
+        assert!(parse("hostname=a&myip=192.0.2.256", peer).is_err());
+    }
+
+    #[test]
+    fn parse_falls_back_to_peer() {
+        let peer: IpAddr = "2001:db8::3".parse().unwrap();
+        let (hostnames, addrs) = parse("hostname=a.example.com&myip=", peer).unwrap();
+        assert_eq!(hostnames, ["a.example.com"]);
+        assert_eq!(addrs, [peer]);
+    }
+
+    #[test]
+    fn basic_auth() {
+        let mut headers = HeaderMap::new();
+        assert!(credentials(&headers).is_none());
+        let encoded = STANDARD.encode("router:pass:word");
+        headers.insert(AUTHORIZATION, format!("Basic {encoded}").parse().unwrap());
+        let (username, password) = credentials(&headers).unwrap();
+        assert_eq!(username, "router");
+        assert_eq!(password.as_str(), "pass:word");
+        headers.insert(AUTHORIZATION, format!("Bearer {encoded}").parse().unwrap());
+        assert!(credentials(&headers).is_none());
+        let encoded = STANDARD.encode("no colon");
+        headers.insert(AUTHORIZATION, format!("basic {encoded}").parse().unwrap());
+        assert!(credentials(&headers).is_none());
+    }
+
+    #[test]
+    fn guard_authenticates() {
+        let guard = Guard {
+            allowed_networks: Vec::new(),
+            client_limit: default_client_limit(),
+            clients: Mutex::default(),
+            auth_failure_limit: default_auth_failure_limit(),
+            failures: Mutex::default(),
+            users: HashMap::from([("router".to_string(), quick_hash("hunter2"))]),
+        };
+        assert!(guard.authenticate("router", "hunter2"));
+        assert!(!guard.authenticate("router", "hunter3"));
+        assert!(!guard.authenticate("nas", "hunter2"));
+    }
+
+    #[test]
+    fn buckets_refill() {
+        let limit = RateLimit {
+            burst: 2,
+            interval: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut buckets = Buckets::default();
+        assert_eq!(buckets.take_at("a", limit, start), Ok(()));
+        assert_eq!(buckets.take_at("a", limit, start), Ok(()));
+        assert_eq!(
+            buckets.take_at("a", limit, start),
+            Err(Duration::from_secs(10))
+        );
+        // Other keys have their own bucket.
+        assert_eq!(buckets.take_at("b", limit, start), Ok(()));
+        let later = start + Duration::from_secs(4);
+        assert_eq!(
+            buckets.take_at("a", limit, later),
+            Err(Duration::from_secs(6))
+        );
+        let later = start + Duration::from_secs(10);
+        assert_eq!(buckets.take_at("a", limit, later), Ok(()));
+        assert!(buckets.take_at("a", limit, later).is_err());
+        // The bucket never holds more than the burst.
+        let later = start + Duration::from_secs(100);
+        assert_eq!(buckets.take_at("a", limit, later), Ok(()));
+        assert_eq!(buckets.take_at("a", limit, later), Ok(()));
+        assert!(buckets.take_at("a", limit, later).is_err());
+    }
+
+    #[test]
+    fn buckets_peek() {
+        let limit = RateLimit {
+            burst: 1,
+            interval: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut buckets = Buckets::default();
+        assert_eq!(buckets.peek_at(&"a", limit, start), Ok(()));
+        assert_eq!(buckets.peek_at(&"a", limit, start), Ok(()));
+        assert_eq!(buckets.take_at("a", limit, start), Ok(()));
+        assert_eq!(
+            buckets.peek_at(&"a", limit, start),
+            Err(Duration::from_secs(10))
+        );
+        let later = start + Duration::from_secs(10);
+        assert_eq!(buckets.peek_at(&"a", limit, later), Ok(()));
+    }
+
+    #[test]
+    fn buckets_capped() {
+        let limit = RateLimit {
+            burst: 1,
+            interval: Duration::from_secs(3600),
+        };
+        let start = Instant::now();
+        let mut buckets = Buckets::default();
+        for key in 0..Buckets::<usize>::MAX_LEN {
+            let now = start + Duration::from_secs(key as u64);
+            assert_eq!(buckets.take_at(key, limit, now), Ok(()));
+        }
+        // None of the buckets is full again, so the least recently used one
+        // makes room.
+        let now = start + Duration::from_secs(3000);
+        assert_eq!(buckets.take_at(usize::MAX, limit, now), Ok(()));
+        assert_eq!(buckets.buckets.len(), Buckets::<usize>::MAX_LEN);
+        assert!(!buckets.buckets.contains_key(&0));
+        assert!(buckets.buckets.contains_key(&1));
+        // Full ones are dropped instead, here the first few.
+        let now = start + Duration::from_secs(3603);
+        assert_eq!(buckets.take_at(usize::MAX - 1, limit, now), Ok(()));
+        assert_eq!(buckets.buckets.len(), Buckets::<usize>::MAX_LEN - 2);
+    }
+
+    #[test]
+    fn client_keys() {
+        let key = |addr: &str| client(addr.parse().unwrap());
+        assert_eq!(key("192.0.2.1"), "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(key("::ffff:192.0.2.1"), key("192.0.2.1"));
+        assert_ne!(key("::ffff:192.0.2.1"), key("::ffff:192.0.2.2"));
+        assert_eq!(key("2001:db8:1:2:3:4:5:6"), key("2001:db8:1:2::1"));
+        assert_eq!(
+            key("2001:db8:1:2::1"),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Interactive first-run setup, writing the config file and a systemd unit.
+
+use std::{
+    fs::{DirBuilder, OpenOptions},
+    io::{BufRead, Write, stderr, stdin},
+    net::IpAddr,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result, bail};
+use dyndnsd::{
+    cache::Cache, outbound::Outbound, provider, provider::Config as ProviderConfig, source,
+    source::Sources,
+};
+use hickory_proto::rr::{Name, RData, RecordType};
+use nix::sys::termios::{LocalFlags, SetArg, tcgetattr, tcsetattr};
+use public_ip::Version;
+
+const UNIT_PATH: &str = "/etc/systemd/system/dyndnsd.service";
+
+struct Field {
+    key: &'static str,
+    prompt: &'static str,
+    secret: bool,
+}
+
+const fn field(key: &'static str, prompt: &'static str) -> Field {
+    Field {
+        key,
+        prompt,
+        secret: false,
+    }
+}
+
+const fn secret(key: &'static str, prompt: &'static str) -> Field {
+    Field {
+        key,
+        prompt,
+        secret: true,
+    }
+}
+
+/// A provider as offered by the setup, with the settings it requires.
+/// Optional settings are left at their defaults and can be added later.
+struct Choice {
+    label: &'static str,
+    provider: &'static str,
+    preset: &'static [(&'static str, &'static str)],
+    fields: &'static [Field],
+}
+
+const CHOICES: &[Choice] = &[
+    Choice {
+        label: "RFC 2136 (nsupdate with TSIG)",
+        provider: "rfc2136",
+        preset: &[],
+        fields: &[
+            field("url", "Server, e.g. udp://1.2.3.4:53"),
+            field("key_name", "TSIG key name"),
+            secret("key", "TSIG key (base64)"),
+            field("algorithm", "TSIG algorithm, e.g. hmac-sha256"),
+        ],
+    },
+    Choice {
+        label: "Azure DNS",
+        provider: "azure",
+        preset: &[],
+        fields: &[
+            field("tenant_id", "Tenant ID"),
+            field("client_id", "Client ID"),
+            secret("client_secret", "Client secret"),
+            field("subscription_id", "Subscription ID"),
+            field("resource_group", "Resource group"),
+        ],
+    },
+    Choice {
+        label: "Dynu (dyndns2 endpoint)",
+        provider: "dynu",
+        preset: &[("api", "dyndns2")],
+        fields: &[
+            field("username", "Username"),
+            secret("password", "Password"),
+        ],
+    },
+    Choice {
+        label: "Dynu (REST API)",
+        provider: "dynu",
+        preset: &[("api", "rest")],
+        fields: &[secret("api_key", "API key")],
+    },
+    Choice {
+        label: "dynv6",
+        provider: "dynv6",
+        preset: &[],
+        fields: &[secret("token", "HTTP token")],
+    },
+    Choice {
+        label: "FreeDNS (freedns.afraid.org)",
+        provider: "freedns",
+        preset: &[],
+        fields: &[
+            secret("ipv4_token", "Update token of the A record"),
+            secret("ipv6_token", "Update token of the AAAA record"),
+        ],
+    },
+    Choice {
+        label: "Google Cloud DNS",
+        provider: "google_cloud_dns",
+        preset: &[],
+        fields: &[field(
+            "credentials_file",
+            "Path of the service account JSON key",
+        )],
+    },
+    Choice {
+        label: "Hurricane Electric (dns.he.net)",
+        provider: "he",
+        preset: &[],
+        fields: &[
+            secret("key", "DDNS key of the A record"),
+            secret("ipv6_key", "DDNS key of the AAAA record, if different"),
+        ],
+    },
+    Choice {
+        label: "INWX",
+        provider: "inwx",
+        preset: &[],
+        fields: &[
+            field("username", "Username"),
+            secret("password", "Password"),
+        ],
+    },
+    Choice {
+        label: "Linode",
+        provider: "linode",
+        preset: &[],
+        fields: &[secret("token", "Personal access token")],
+    },
+    Choice {
+        label: "Namecheap",
+        provider: "namecheap",
+        preset: &[],
+        fields: &[secret("password", "Dynamic DNS password")],
+    },
+    Choice {
+        label: "netcup",
+        provider: "netcup",
+        preset: &[],
+        fields: &[
+            field("customer_number", "Customer number"),
+            secret("api_key", "API key"),
+            secret("api_password", "API password"),
+        ],
+    },
+    Choice {
+        label: "Njalla",
+        provider: "njalla",
+        preset: &[],
+        fields: &[secret("token", "API token")],
+    },
+    Choice {
+        label: "nsupdate.info",
+        provider: "nsupdate_info",
+        preset: &[],
+        fields: &[secret("secret", "Update secret of the host")],
+    },
+    Choice {
+        label: "Porkbun",
+        provider: "porkbun",
+        preset: &[],
+        fields: &[
+            secret("api_key", "API key"),
+            secret("secret_api_key", "Secret API key"),
+        ],
+    },
+    Choice {
+        label: "Scaleway",
+        provider: "scaleway",
+        preset: &[],
+        fields: &[secret("secret_key", "Secret key")],
+    },
+    Choice {
+        label: "Vultr",
+        provider: "vultr",
+        preset: &[],
+        fields: &[secret("api_key", "API key")],
+    },
+];
+
+/// Walk the user through writing the config file at `config_path`.
+pub async fn run(config_path: &Path) -> Result<()> {
+    println!("This sets up dyndnsd, writing {}.", config_path.display());
+    println!("Defaults are shown in brackets, press enter to accept them.");
+    println!();
+
+    let zone = loop {
+        match prompt("Zone, e.g. example.com", None)?.parse::<Name>() {
+            Ok(zone) => break zone.append_domain(&Name::root())?,
+            Err(error) => println!("Invalid name: {error}"),
+        }
+    };
+    let domain = loop {
+        let answer = prompt("Record to keep updated", Some(&format!("home.{zone}")))?;
+        match answer.parse::<Name>() {
+            Ok(name) => {
+                let name = name.append_domain(&Name::root())?;
+                if zone.zone_of(&name) {
+                    break name;
+                }
+                println!("{name} is not part of the zone {zone}");
+            }
+            Err(error) => println!("Invalid name: {error}"),
+        }
+    };
+    let ipv4 = confirm("Update the A record with the public IPv4 address?", true)?;
+    let ipv6 = confirm(
+        "Update the AAAA record with the public IPv6 address?",
+        false,
+    )?;
+    if !ipv4 && !ipv6 {
+        bail!("At least one address family has to be updated");
+    }
+
+    println!();
+    println!("Detecting the current addresses...");
+    let cache = Mutex::new(Cache::default());
+    let outbound = Outbound::default();
+    let sources = Sources::default();
+    let mut detected = Vec::new();
+    for (enabled, version, family) in [(ipv4, Version::V4, "IPv4"), (ipv6, Version::V6, "IPv6")] {
+        if !enabled {
+            continue;
+        }
+        let detected_addr = source::detect(version, &cache, &sources, &[], &outbound)
+            .await
+            .and_then(|addrs| addrs.first().copied());
+        match detected_addr {
+            Some(addr) => {
+                println!("  {family}: {addr}");
+                detected.push(addr);
+            }
+            None => println!("  {family}: no address could be detected"),
+        }
+    }
+
+    let table = loop {
+        let (table, provider) = choose_provider()?;
+        println!();
+        match validate(&provider, &domain, &zone, &detected).await {
+            Ok(()) => break table,
+            Err(error) => {
+                println!("Validation failed: {error:#}");
+                if !confirm("Enter the provider settings again?", true)? {
+                    bail!("Setup aborted");
+                }
+            }
+        }
+    };
+
+    let mut config = toml::Table::new();
+    config.insert("zone".into(), zone.to_string().into());
+    config.insert("domain".into(), domain.to_string().into());
+    config.insert("ipv4".into(), ipv4.into());
+    config.insert("ipv6".into(), ipv6.into());
+    config.insert("dns_provider_config".into(), table.into());
+    let config = toml::to_string(&config).context("Failed to serialize config")?;
+
+    println!();
+    if config_path.exists()
+        && !confirm(
+            &format!("{} already exists, overwrite it?", config_path.display()),
+            false,
+        )?
+    {
+        bail!("Setup aborted");
+    }
+    write_file(config_path, &config, 0o600)?;
+    println!("Wrote {}", config_path.display());
+
+    if confirm("Install a systemd unit for the daemon?", true)? {
+        let executable = std::env::current_exe().context("Failed to locate the dyndnsd binary")?;
+        let unit = format!(
+            "[Unit]\n\
+             Description=Dynamic DNS updater\n\
+             Wants=network-online.target\n\
+             After=network-online.target\n\
+             \n\
+             [Service]\n\
+             ExecStart={}\n\
+             Restart=on-failure\n\
+             CacheDirectory=dyndnsd\n\
+             CacheDirectoryMode=0700\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            executable.display()
+        );
+        write_file(Path::new(UNIT_PATH), &unit, 0o644)?;
+        println!("Wrote {UNIT_PATH}, start the daemon with:");
+        println!("  systemctl daemon-reload && systemctl enable --now dyndnsd");
+    }
+    Ok(())
+}
+
+fn choose_provider() -> Result<(toml::Table, ProviderConfig)> {
+    println!();
+    for (index, choice) in CHOICES.iter().enumerate() {
+        println!("{:>3}) {}", index + 1, choice.label);
+    }
+    let choice = loop {
+        match prompt("DNS provider", Some("1"))?.parse::<usize>() {
+            Ok(number) if (1..=CHOICES.len()).contains(&number) => break &CHOICES[number - 1],
+            _ => println!("Please enter a number between 1 and {}", CHOICES.len()),
+        }
+    };
+    let mut table = toml::Table::new();
+    table.insert("provider".into(), choice.provider.into());
+    for (key, value) in choice.preset {
+        table.insert(key.to_string(), value.to_string().into());
+    }
+    for field in choice.fields {
+        let value = if field.secret {
+            prompt_secret(field.prompt)?
+        } else {
+            prompt(field.prompt, None)?
+        };
+        if !value.is_empty() {
+            table.insert(field.key.into(), value.into());
+        }
+    }
+    let provider = provider::deserialize(toml::Value::Table(table.clone()))
+        .context("Invalid provider settings")?
+        .remove(0);
+    Ok((table, provider))
+}
+
+/// Check the credentials against the provider, without changing records if
+/// the provider allows it, or else with the consent of the user.
+async fn validate(
+    provider: &ProviderConfig,
+    domain: &Name,
+    zone: &Name,
+    detected: &[IpAddr],
+) -> Result<()> {
+    for addr in detected {
+        let (record_type, rdata) = match *addr {
+            IpAddr::V4(addr) => (RecordType::A, RData::A(addr.into())),
+            IpAddr::V6(addr) => (RecordType::AAAA, RData::AAAA(addr.into())),
+        };
+        println!(
+            "Dry run: would set {} {} to {} on {}",
+            domain,
+            record_type,
+            addr,
+            provider.name()
+        );
+        if provider.can_check_update_permission() {
+            provider
+                .check_update_permission(record_type, domain.clone(), zone.clone())
+                .await?;
+            println!("The {} record can be updated", record_type);
+        } else {
+            if !confirm(
+                &format!(
+                    "{} can't be checked without changing the record, publish {} now as a test?",
+                    provider.name(),
+                    addr
+                ),
+                false,
+            )? {
+                println!("Skipping validation, the credentials are tried on the first update");
+                continue;
+            }
+            provider
+                .set_record(rdata, domain.clone(), zone.clone())
+                .await?;
+            println!("The {} record was updated", record_type);
+        }
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Ask a question on stderr, keeping stdout free for output.
+pub fn prompt(question: &str, default: Option<&str>) -> Result<String> {
+    match default {
+        Some(default) => eprint!("{question} [{default}]: "),
+        None => eprint!("{question}: "),
+    }
+    stderr().flush()?;
+    let mut answer = String::new();
+    if stdin().lock().read_line(&mut answer)? == 0 {
+        bail!("Setup aborted");
+    }
+    let answer = answer.trim();
+    match default {
+        Some(default) if answer.is_empty() => Ok(default.to_string()),
+        _ => Ok(answer.to_string()),
+    }
+}
+
+/// Prompt without echoing the input, if stdin is a terminal.
+pub fn prompt_secret(question: &str) -> Result<String> {
+    let original = tcgetattr(stdin()).ok();
+    if let Some(original) = &original {
+        let mut silent = original.clone();
+        silent.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(stdin(), SetArg::TCSANOW, &silent)?;
+    }
+    let answer = prompt(question, None);
+    if let Some(original) = &original {
+        tcsetattr(stdin(), SetArg::TCSANOW, original)?;
+        eprintln!();
+    }
+    answer
+}
+
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    loop {
+        let answer = prompt(&format!("{question} [{hint}]"), None)?;
+        match answer.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer yes or no"),
+        }
+    }
+}
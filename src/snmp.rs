@@ -0,0 +1,137 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Helper for net-snmp's `pass_persist` protocol, exposing the daemon status
+//! stored in the cache as a small read-only MIB subtree.
+
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result};
+use dyndnsd::cache::{Cache, Store};
+
+/// Default subtree, located in NET-SNMP-MIB::netSnmpPlaypen.
+pub const DEFAULT_BASE_OID: &str = ".1.3.6.1.4.1.8072.9999.9999.1";
+
+type Oid = Vec<u32>;
+
+struct Object {
+    oid: Oid,
+    kind: &'static str,
+    value: String,
+}
+
+fn parse_oid(oid: &str) -> Result<Oid> {
+    oid.trim()
+        .trim_start_matches('.')
+        .split('.')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().context("Invalid OID component"))
+        .collect()
+}
+
+fn format_oid(oid: &[u32]) -> String {
+    oid.iter().map(|part| format!(".{part}")).collect()
+}
+
+fn objects(base: &[u32], cache: &Cache) -> Vec<Object> {
+    let object = |index: u32, kind, value| {
+        let mut oid = base.to_vec();
+        oid.extend([index, 0]);
+        Object { oid, kind, value }
+    };
+    let mut objects = Vec::new();
+    if let Some(v4) = cache.v4 {
+        objects.push(object(1, "ipaddress", v4.to_string()));
+    }
+    if let Some(v6) = cache.v6 {
+        objects.push(object(2, "string", v6.to_string()));
+    }
+    if let Some(last_update) = cache.last_update {
+        objects.push(object(3, "gauge", last_update.to_string()));
+    }
+    objects.push(object(4, "counter", (cache.failures as u32).to_string()));
+    objects.push(object(5, "gauge", cache.consecutive_failures.to_string()));
+    objects
+}
+
+/// The answer to a `get` of `oid`, or to a `getnext` unless `get` is set.
+fn lookup(get: bool, base: &[u32], oid: &[u32], store: &Store) -> String {
+    let cache = match store.load() {
+        Ok(cache) => cache.unwrap_or_default(),
+        Err(error) => {
+            tracing::warn!("Failed to read the state: {:#}", error);
+            Cache::default()
+        }
+    };
+    let objects = objects(base, &cache);
+    let found = if get {
+        objects.into_iter().find(|object| object.oid == oid)
+    } else {
+        objects
+            .into_iter()
+            .find(|object| object.oid.as_slice() > oid)
+    };
+    match found {
+        Some(object) => format!(
+            "{}\n{}\n{}\n",
+            format_oid(&object.oid),
+            object.kind,
+            object.value
+        ),
+        None => "NONE\n".to_string(),
+    }
+}
+
+/// Answer `pass_persist` requests from snmpd on stdin/stdout until snmpd closes the pipe.
+///
+/// The cache is re-read for every request, so the values always reflect the
+/// latest state written by the running daemon.
+pub fn pass_persist(base_oid: &str, store: &Store) -> Result<()> {
+    let base = parse_oid(base_oid).context("Failed to parse base OID")?;
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    let mut lines = stdin.lock().lines();
+    while let Some(command) = lines.next() {
+        let command = command.context("Failed to read from snmpd")?;
+        let response = match command.trim() {
+            "" => break,
+            "PING" => "PONG\n".to_string(),
+            "get" | "getnext" => {
+                let oid = lines
+                    .next()
+                    .context("snmpd closed the pipe mid-request")?
+                    .context("Failed to read from snmpd")?;
+                match parse_oid(&oid) {
+                    Ok(oid) => lookup(command.trim() == "get", &base, &oid, store),
+                    Err(error) => {
+                        tracing::warn!("Invalid OID {:?} from snmpd: {:#}", oid.trim(), error);
+                        "NONE\n".to_string()
+                    }
+                }
+            }
+            "set" => {
+                // Skip the OID and the value lines, the subtree is read-only.
+                lines.next();
+                lines.next();
+                "not-writable\n".to_string()
+            }
+            other => {
+                tracing::warn!("Unknown pass_persist command: {}", other);
+                "NONE\n".to_string()
+            }
+        };
+        stdout
+            .write_all(response.as_bytes())
+            .and_then(|_| stdout.flush())
+            .context("Failed to write to snmpd")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,280 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Detection of the public IP addresses, keeping track of which source
+//! produced which result.
+
+use std::{net::IpAddr, sync::Mutex, time::Instant};
+
+use anyhow::{Result, anyhow, bail};
+use futures_util::StreamExt;
+use public_ip::{Resolver, Version};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{
+    cache::{self, Cache, Sample},
+    filter::{self, Filter},
+    outbound::{Outbound, Overrides},
+};
+
+mod exec;
+mod http;
+mod interface;
+mod nat_pmp;
+mod stun;
+mod upnp;
+mod whoami;
+
+/// The sources queried for each address family, in order until one succeeds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Sources {
+    #[serde(default = "builtin")]
+    pub ipv4: Vec<Source>,
+    #[serde(default = "builtin")]
+    pub ipv6: Vec<Source>,
+    /// Number of sources that have to agree on an address before it's used
+    #[serde(default = "default_consensus")]
+    pub consensus: usize,
+    /// Timeouts and retries of the detection of each family, instead of the
+    /// outbound settings
+    #[serde(default)]
+    pub detection: Detection,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Detection {
+    #[serde(default)]
+    pub ipv4: Overrides,
+    #[serde(default)]
+    pub ipv6: Overrides,
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Self {
+            ipv4: builtin(),
+            ipv6: builtin(),
+            consensus: default_consensus(),
+            detection: Detection::default(),
+        }
+    }
+}
+
+fn default_consensus() -> usize {
+    1
+}
+
+impl Sources {
+    /// Check that the consensus can be reached with the configured sources.
+    pub fn validate(&self) -> Result<()> {
+        if self.consensus == 0 {
+            bail!("The consensus has to be at least 1");
+        }
+        for (family, sources) in [("ipv4", &self.ipv4), ("ipv6", &self.ipv6)] {
+            if sources.len() < self.consensus {
+                bail!(
+                    "A consensus of {} needs at least as many {} sources, but only {} are configured",
+                    self.consensus,
+                    family,
+                    sources.len()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any of the sources runs a command.
+    pub fn runs_commands(&self) -> bool {
+        self.ipv4
+            .iter()
+            .chain(&self.ipv6)
+            .any(|source| matches!(source, Source::Exec(_)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum Source {
+    /// The builtin sources of the `public_ip` crate
+    Opendns,
+    Google,
+    Ipify,
+    Whatismyipaddress,
+    /// Binding requests to STUN servers
+    Stun(stun::Config),
+    /// User supplied HTTP endpoints
+    Http(http::Config),
+    /// DNS servers reflecting the address of the query
+    Whoami(whoami::Config),
+    /// The router, through UPnP IGD
+    Upnp(upnp::Config),
+    /// The default gateway, through NAT-PMP or PCP
+    NatPmp(nat_pmp::Config),
+    /// A user supplied command
+    Exec(exec::Config),
+    /// The addresses of a local interface
+    Interface(interface::Config),
+}
+
+impl Source {
+    /// Name the results of the source are recorded under.
+    fn name(&self) -> &'static str {
+        match self {
+            Source::Opendns => "opendns",
+            Source::Google => "google",
+            Source::Ipify => "ipify",
+            Source::Whatismyipaddress => "whatismyipaddress",
+            Source::Stun(_) => "stun",
+            Source::Http(_) => "http",
+            Source::Whoami(_) => "whoami",
+            Source::Upnp(_) => "upnp",
+            Source::NatPmp(_) => "nat_pmp",
+            Source::Exec(_) => "exec",
+            Source::Interface(_) => "interface",
+        }
+    }
+
+    /// All addresses of the given version the source returns.
+    async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        let resolver: &dyn Resolver<'static> = match self {
+            Source::Opendns => public_ip::dns::OPENDNS,
+            Source::Google => public_ip::dns::GOOGLE,
+            Source::Ipify => public_ip::http::HTTP_IPIFY_ORG,
+            Source::Whatismyipaddress => public_ip::http::HTTP_WHATISMYIPADDRESS_COM,
+            Source::Stun(config) => return config.resolve(version).await,
+            Source::Http(config) => return config.resolve(version).await,
+            Source::Whoami(config) => return config.resolve(version).await,
+            Source::Upnp(config) => return config.resolve(version).await,
+            Source::NatPmp(config) => return config.resolve(version).await,
+            Source::Exec(config) => return config.resolve(version).await,
+            Source::Interface(config) => return config.resolve(version).await,
+        };
+        let mut resolutions = public_ip::resolve(resolver, version);
+        let mut error = None;
+        let mut addrs = Vec::new();
+        while let Some(resolution) = resolutions.next().await {
+            match resolution {
+                Ok((resolved, _)) => addrs.push(resolved),
+                Err(resolution_error) => error = Some(resolution_error.to_string()),
+            }
+        }
+        if addrs.is_empty() {
+            return Err(anyhow!(
+                error.unwrap_or_else(|| "no address returned".to_string())
+            ));
+        }
+        Ok(addrs)
+    }
+}
+
+fn builtin() -> Vec<Source> {
+    vec![
+        Source::Opendns,
+        Source::Google,
+        Source::Ipify,
+        Source::Whatismyipaddress,
+    ]
+}
+
+fn family(version: Version) -> &'static str {
+    match version {
+        Version::V4 => "ipv4",
+        Version::V6 => "ipv6",
+        _ => "any",
+    }
+}
+
+/// Detect the current public address of the given IP version, recording the
+/// result of every source queried in the history kept in the cache.
+///
+/// The addresses returned by a source are run through the filters, and the
+/// first address left over is the source's vote. If the filters drop all of
+/// them, the source doesn't vote. Sources are queried until `consensus` of
+/// them voted for the same address, which is then returned first, followed by
+/// the other addresses left over from the source that cast the deciding vote.
+#[tracing::instrument(skip_all, fields(family = family(version)))]
+pub async fn detect(
+    version: Version,
+    cache: &Mutex<Cache>,
+    config: &Sources,
+    filters: &[Filter],
+    outbound: &Outbound,
+) -> Option<Vec<IpAddr>> {
+    let (sources, overrides) = match version {
+        Version::V6 => (&config.ipv6, &config.detection.ipv6),
+        _ => (&config.ipv4, &config.detection.ipv4),
+    };
+    let consensus = config.consensus;
+    let mut votes: Vec<(IpAddr, Vec<String>)> = Vec::new();
+    for source_config in sources {
+        let source = format!("{}/{}", source_config.name(), family(version));
+        let start = Instant::now();
+        let result = outbound
+            .call_with(&source, overrides, || source_config.resolve(version))
+            .instrument(tracing::info_span!("source", source = %source))
+            .await;
+        let latency = start.elapsed();
+        match result {
+            Ok(addrs) => {
+                tracing::debug!("{} detected {:?} in {:?}", source, addrs, latency);
+                cache::lock(cache)
+                    .record_source(source.clone(), Sample::new(Ok(addrs[0]), latency));
+                let addrs = filter::apply(filters, addrs);
+                let Some(addr) = addrs.first().copied() else {
+                    tracing::debug!("all addresses from {} were filtered out", source);
+                    continue;
+                };
+                let voters = match votes.iter_mut().find(|(voted, _)| *voted == addr) {
+                    Some((_, voters)) => voters,
+                    None => {
+                        votes.push((addr, Vec::new()));
+                        &mut votes.last_mut().expect("just pushed").1
+                    }
+                };
+                voters.push(source);
+                if voters.len() >= consensus {
+                    if votes.len() > 1 {
+                        tracing::warn!(
+                            "sources disagree on the {} address: {}",
+                            family(version),
+                            describe(&votes)
+                        );
+                    }
+                    return Some(addrs);
+                }
+            }
+            Err(error) => {
+                tracing::debug!("{} failed after {:?}: {:#}", source, latency, error);
+                cache::lock(cache)
+                    .record_source(source, Sample::new(Err(format!("{error:#}")), latency));
+            }
+        }
+    }
+    if !votes.is_empty() {
+        tracing::warn!(
+            "no {} sources agreed on the {} address: {}",
+            consensus,
+            family(version),
+            describe(&votes)
+        );
+    }
+    None
+}
+
+/// List the addresses voted for, with the sources that voted for them.
+fn describe(votes: &[(IpAddr, Vec<String>)]) -> String {
+    votes
+        .iter()
+        .map(|(addr, voters)| format!("{} from {}", addr, voters.join(", ")))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
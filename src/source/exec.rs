@@ -0,0 +1,84 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Address detection through a user supplied command.
+
+use std::{net::IpAddr, path::PathBuf, process::Stdio, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use public_ip::Version;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tokio::{process::Command, time::timeout};
+
+use crate::duration::HumanDuration;
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Time after which the command is killed
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_timeout")]
+    timeout: Duration,
+}
+
+impl Config {
+    /// Run the command and use every line of its output that is an address of
+    /// the requested family. The family is also passed to the command in
+    /// `DYNDNSD_FAMILY`, as `ipv4` or `ipv6`.
+    pub async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        let family = match version {
+            Version::V6 => "ipv6",
+            _ => "ipv4",
+        };
+        let child = Command::new(&self.command)
+            .args(&self.args)
+            .env("DYNDNSD_FAMILY", family)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.command.display()))?;
+        let output = timeout(self.timeout, child.wait_with_output())
+            .await
+            .with_context(|| format!("{} timed out", self.command.display()))?
+            .with_context(|| format!("Failed to run {}", self.command.display()))?;
+        if !output.status.success() {
+            bail!(
+                "{} failed ({}): {}",
+                self.command.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let addrs: Vec<IpAddr> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .filter(|addr: &IpAddr| match version {
+                Version::V4 => addr.is_ipv4(),
+                Version::V6 => addr.is_ipv6(),
+                _ => true,
+            })
+            .collect();
+        if addrs.is_empty() {
+            bail!("{} printed no {} address", self.command.display(), family);
+        }
+        Ok(addrs)
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
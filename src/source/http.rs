@@ -0,0 +1,128 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Address detection through user supplied HTTP endpoints, like self-hosted
+//! icanhazip clones.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use public_ip::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::serde_as;
+
+use crate::{duration::HumanDuration, proxy};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    strategy: Strategy,
+    /// Endpoint the next round-robin query starts with
+    #[serde(skip)]
+    next: AtomicUsize,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+struct Endpoint {
+    url: String,
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_timeout")]
+    timeout: Duration,
+    /// JSON pointer to the address in a JSON response. Without it, the
+    /// response is expected to be the plain address.
+    json_pointer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Strategy {
+    /// Always start with the first endpoint, using the others if it fails
+    #[default]
+    Fallback,
+    /// Start with the next endpoint on every query, spreading the load
+    RoundRobin,
+}
+
+impl Config {
+    pub async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        let start = match self.strategy {
+            Strategy::Fallback => 0,
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        let mut last_error = None;
+        for index in 0..self.endpoints.len() {
+            let endpoint = &self.endpoints[(start + index) % self.endpoints.len()];
+            match endpoint.resolve(version).await {
+                Ok(addr) => return Ok(vec![addr]),
+                Err(error) => {
+                    tracing::debug!("{} failed: {:#}", endpoint.url, error);
+                    last_error = Some(error.context(format!("{} failed", endpoint.url)));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No HTTP endpoint configured")))
+    }
+}
+
+impl Endpoint {
+    async fn resolve(&self, version: Version) -> Result<IpAddr> {
+        // Binding to the unspecified address of the family forces the
+        // connection, and with it the address seen by the endpoint, to use it.
+        let local_address = match version {
+            Version::V4 => Some(Ipv4Addr::UNSPECIFIED.into()),
+            Version::V6 => Some(Ipv6Addr::UNSPECIFIED.into()),
+            _ => None,
+        };
+        let body = proxy::builder()
+            .local_address(local_address)
+            .timeout(self.timeout)
+            .build()
+            .context("Failed to build HTTP client")?
+            .get(&self.url)
+            .send()
+            .await
+            .context("Failed to send request")?
+            .error_for_status()?
+            .text()
+            .await
+            .context("Failed to read response")?;
+        let addr = match &self.json_pointer {
+            Some(pointer) => {
+                let json: Value =
+                    serde_json::from_str(&body).context("Failed to parse JSON response")?;
+                json.pointer(pointer)
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("The response has no string at {}", pointer))?
+                    .trim()
+                    .parse::<IpAddr>()
+            }
+            None => body.trim().parse::<IpAddr>(),
+        }
+        .context("The response is not an IP address")?;
+        match (version, addr) {
+            (Version::V4, IpAddr::V6(_)) | (Version::V6, IpAddr::V4(_)) => {
+                bail!("Returned {}, which is of the wrong family", addr)
+            }
+            _ => Ok(addr),
+        }
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
@@ -0,0 +1,77 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Address detection by reading the global IPv6 addresses of a local
+//! interface, like the LAN interface of a router holding an address out of the
+//! delegated prefix.
+
+use std::{
+    fs::read_to_string,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use anyhow::{Context, Result, bail};
+use public_ip::Version;
+use serde::{Deserialize, Serialize};
+
+const ADDRESSES: &str = "/proc/net/if_inet6";
+/// `RT_SCOPE_UNIVERSE`
+const SCOPE_GLOBAL: u8 = 0x00;
+/// `IFA_F_TEMPORARY`
+const FLAG_TEMPORARY: u8 = 0x01;
+/// `IFA_F_DADFAILED`, `IFA_F_DEPRECATED` and `IFA_F_TENTATIVE`
+const FLAGS_UNUSABLE: u8 = 0x08 | 0x20 | 0x40;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    interface: String,
+    /// Whether temporary privacy addresses are used as well
+    #[serde(default)]
+    temporary: bool,
+}
+
+impl Config {
+    pub async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        if let Version::V4 = version {
+            bail!("Only the IPv6 addresses of an interface can be read");
+        }
+        let addresses =
+            read_to_string(ADDRESSES).with_context(|| format!("Failed to read {ADDRESSES}"))?;
+        let mut addrs: Vec<Ipv6Addr> = addresses
+            .lines()
+            .filter_map(|line| {
+                // Address, index, prefix length, scope, flags and name
+                let fields: Vec<_> = line.split_whitespace().collect();
+                if fields.len() != 6 || fields[5] != self.interface {
+                    return None;
+                }
+                let scope = u8::from_str_radix(fields[3], 16).ok()?;
+                let flags = u8::from_str_radix(fields[4], 16).ok()?;
+                if scope != SCOPE_GLOBAL
+                    || flags & FLAGS_UNUSABLE != 0
+                    || (!self.temporary && flags & FLAG_TEMPORARY != 0)
+                {
+                    return None;
+                }
+                let addr = Ipv6Addr::from(u128::from_str_radix(fields[0], 16).ok()?);
+                // Unique local addresses have global scope too, but aren't
+                // reachable from the internet.
+                (!addr.is_unique_local()).then_some(addr)
+            })
+            .collect();
+        if addrs.is_empty() {
+            bail!("{} has no global IPv6 address", self.interface);
+        }
+        // The kernel lists the addresses in no particular order.
+        addrs.sort();
+        Ok(addrs.into_iter().map(IpAddr::V6).collect())
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Address detection by asking the default gateway for its external address
+//! through NAT-PMP (RFC 6886) or PCP (RFC 6887).
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use public_ip::Version;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    time::{Instant, timeout_at},
+};
+
+use crate::route;
+
+const PORT: u16 = 5351;
+/// Number of requests sent, with the timeout doubling after each, starting
+/// at 250ms as recommended by both RFCs
+const ATTEMPTS: u32 = 5;
+/// Lifetime of the short-lived mapping PCP needs to report the address
+const PCP_LIFETIME: u32 = 120;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    #[serde(default)]
+    protocol: Protocol,
+    /// Address of the gateway instead of the default route's
+    gateway: Option<Ipv4Addr>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Protocol {
+    #[default]
+    NatPmp,
+    Pcp,
+}
+
+impl Config {
+    pub async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        if let Version::V6 = version {
+            bail!("Only the external IPv4 address can be asked for");
+        }
+        let gateway = match self.gateway {
+            Some(gateway) => gateway,
+            None => route::default_gateway()?,
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .await
+            .context("Failed to bind socket")?;
+        socket
+            .connect((gateway, PORT))
+            .await
+            .context("Failed to connect socket")?;
+        let addr = match self.protocol {
+            Protocol::NatPmp => nat_pmp(&socket).await?,
+            Protocol::Pcp => pcp(&socket).await?,
+        };
+        Ok(vec![addr.into()])
+    }
+}
+
+/// Send `request` until a response passing `accept` arrives, retransmitting
+/// with exponential backoff.
+async fn exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    accept: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>> {
+    let mut wait = Duration::from_millis(250);
+    let mut buffer = [0; 1100];
+    for _ in 0..ATTEMPTS {
+        socket
+            .send(request)
+            .await
+            .context("Failed to send request")?;
+        let deadline = Instant::now() + wait;
+        while let Ok(received) = timeout_at(deadline, socket.recv(&mut buffer)).await {
+            let len = received.context("Failed to receive response")?;
+            if accept(&buffer[..len]) {
+                return Ok(buffer[..len].to_vec());
+            }
+        }
+        wait *= 2;
+    }
+    bail!("The gateway didn't answer")
+}
+
+async fn nat_pmp(socket: &UdpSocket) -> Result<Ipv4Addr> {
+    // Version 0, opcode 0: external address request
+    let response = exchange(socket, &[0, 0], nat_pmp_matches).await?;
+    nat_pmp_external(&response)
+}
+
+/// Whether `response` answers the external address request.
+fn nat_pmp_matches(response: &[u8]) -> bool {
+    response.len() >= 12 && response[0] == 0 && response[1] == 128
+}
+
+fn nat_pmp_external(response: &[u8]) -> Result<Ipv4Addr> {
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        bail!(
+            "The gateway refused the request with result code {}",
+            result
+        );
+    }
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+async fn pcp(socket: &UdpSocket) -> Result<Ipv4Addr> {
+    // PCP has no request for just the external address, so a short-lived
+    // mapping for the socket's own port is requested, which reports it.
+    let local = socket.local_addr().context("Failed to get local address")?;
+    let IpAddr::V4(client) = local.ip() else {
+        bail!("Not bound to an IPv4 address");
+    };
+    let mut nonce = [0; 12];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("Failed to generate nonce"))?;
+    let request = pcp_request(PCP_LIFETIME, client, local.port(), &nonce);
+    let response = exchange(socket, &request, |response| pcp_matches(response, &nonce)).await?;
+    let external = pcp_external(&response)?;
+    // The mapping isn't needed anymore, so it's deleted right away instead of
+    // waiting for it to expire. It does expire if that fails.
+    let delete = pcp_request(0, client, local.port(), &nonce);
+    if let Err(error) = exchange(socket, &delete, |response| pcp_matches(response, &nonce)).await {
+        tracing::debug!("Failed to delete the PCP mapping: {:#}", error);
+    }
+    Ok(external)
+}
+
+/// A MAP request for the UDP `port` of `client`, deleting the mapping
+/// created with the same `nonce` if `lifetime` is 0.
+fn pcp_request(lifetime: u32, client: Ipv4Addr, port: u16, nonce: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(60);
+    // Version 2, MAP request, reserved
+    request.extend_from_slice(&[2, 1, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&client.to_ipv6_mapped().octets());
+    request.extend_from_slice(nonce);
+    // UDP, reserved
+    request.extend_from_slice(&[17, 0, 0, 0]);
+    request.extend_from_slice(&port.to_be_bytes());
+    // No suggested external port and address
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+    request
+}
+
+/// Whether `response` answers the MAP request with `nonce`.
+fn pcp_matches(response: &[u8], nonce: &[u8; 12]) -> bool {
+    // Version 2, MAP response, for this request
+    response.len() >= 60 && response[0] == 2 && response[1] == 0x81 && response[24..36] == *nonce
+}
+
+fn pcp_external(response: &[u8]) -> Result<Ipv4Addr> {
+    if response[3] != 0 {
+        bail!(
+            "The gateway refused the request with result code {}",
+            response[3]
+        );
+    }
+    let mut external = [0; 16];
+    external.copy_from_slice(&response[44..60]);
+    Ipv6Addr::from(external)
+        .to_ipv4_mapped()
+        .ok_or_else(|| anyhow!("The gateway returned a non-IPv4 external address"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    /// A MAP response to a request with `nonce`, mapping to `external`.
+    fn pcp_response(result: u8, nonce: &[u8; 12], external: Ipv6Addr) -> Vec<u8> {
+        let mut response = vec![2, 0x81, 0, result];
+        // Lifetime, epoch and reserved
+        response.extend(120u32.to_be_bytes());
+        response.extend([0; 16]);
+        response.extend(nonce);
+        response.extend([17, 0, 0, 0, 0x30, 0x39, 0x30, 0x39]);
+        response.extend(external.octets());
+        response
+    }
+
+    #[test]
+    fn nat_pmp_response() {
+        let response = [0, 128, 0, 0, 0, 0, 0x12, 0x34, 203, 0, 113, 9];
+        assert!(nat_pmp_matches(&response));
+        assert_eq!(
+            nat_pmp_external(&response).unwrap(),
+            Ipv4Addr::new(203, 0, 113, 9)
+        );
+        assert!(!nat_pmp_matches(&response[..11]));
+        // The response to a mapping request, or from a PCP gateway
+        assert!(!nat_pmp_matches(&[0, 129, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        assert!(!nat_pmp_matches(&[2, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        // Not authorized
+        let refused = [0, 128, 0, 2, 0, 0, 0x12, 0x34, 0, 0, 0, 0];
+        assert!(nat_pmp_matches(&refused));
+        assert!(nat_pmp_external(&refused).is_err());
+    }
+
+    #[test]
+    fn pcp_map_request() {
+        let request = pcp_request(120, Ipv4Addr::new(192, 168, 1, 2), 12345, &NONCE);
+        assert_eq!(request.len(), 60);
+        assert_eq!(request[..4], [2, 1, 0, 0]);
+        assert_eq!(request[4..8], 120u32.to_be_bytes());
+        assert_eq!(
+            request[8..24],
+            Ipv4Addr::new(192, 168, 1, 2).to_ipv6_mapped().octets()
+        );
+        assert_eq!(request[24..36], NONCE);
+        assert_eq!(request[36], 17);
+        assert_eq!(request[40..42], 12345u16.to_be_bytes());
+        assert!(request[42..].iter().all(|byte| *byte == 0));
+        let delete = pcp_request(0, Ipv4Addr::new(192, 168, 1, 2), 12345, &NONCE);
+        assert_eq!(delete[4..8], [0; 4]);
+        assert_eq!(delete[8..], request[8..]);
+    }
+
+    #[test]
+    fn pcp_map_response() {
+        let external = Ipv4Addr::new(203, 0, 113, 9);
+        let response = pcp_response(0, &NONCE, external.to_ipv6_mapped());
+        assert!(pcp_matches(&response, &NONCE));
+        assert_eq!(pcp_external(&response).unwrap(), external);
+        let mut other = NONCE;
+        other[0] ^= 1;
+        assert!(!pcp_matches(&response, &other));
+        assert!(!pcp_matches(&response[..59], &NONCE));
+        // A request echoed back
+        let mut echoed = response.clone();
+        echoed[1] = 1;
+        assert!(!pcp_matches(&echoed, &NONCE));
+        // NOT_AUTHORIZED
+        let refused = pcp_response(2, &NONCE, external.to_ipv6_mapped());
+        assert!(pcp_matches(&refused, &NONCE));
+        assert!(pcp_external(&refused).is_err());
+        let ipv6 = pcp_response(0, &NONCE, "2001:db8::1".parse().unwrap());
+        assert!(pcp_external(&ipv6).is_err());
+    }
+}
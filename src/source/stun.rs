@@ -0,0 +1,290 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Address detection through STUN (RFC 5389) binding requests.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use public_ip::Version;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{UdpSocket, lookup_host},
+    time::timeout,
+};
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// STUN servers as `host:port`, queried in order until one answers
+    #[serde(default = "default_servers")]
+    servers: Vec<String>,
+}
+
+impl Config {
+    pub async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        let mut last_error = None;
+        for server in &self.servers {
+            match binding(server, version).await {
+                Ok(addr) => return Ok(vec![addr]),
+                Err(error) => {
+                    tracing::debug!("STUN server {} failed: {:#}", server, error);
+                    last_error = Some(error.context(format!("STUN server {server} failed")));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No STUN server configured")))
+    }
+}
+
+/// Send a binding request to `server` and return the mapped address.
+async fn binding(server: &str, version: Version) -> Result<IpAddr> {
+    let server = lookup_host(server)
+        .await
+        .context("Failed to resolve server")?
+        .find(|addr| match version {
+            Version::V4 => addr.is_ipv4(),
+            Version::V6 => addr.is_ipv6(),
+            _ => true,
+        })
+        .ok_or_else(|| anyhow!("The server has no address of this family"))?;
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .context("Failed to bind socket")?;
+    socket
+        .connect(server)
+        .await
+        .context("Failed to connect socket")?;
+
+    let mut transaction_id = [0; 12];
+    SystemRandom::new()
+        .fill(&mut transaction_id)
+        .map_err(|_| anyhow!("Failed to generate transaction ID"))?;
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    socket
+        .send(&request)
+        .await
+        .context("Failed to send binding request")?;
+
+    let mut response = [0; 576];
+    let len = timeout(RESPONSE_TIMEOUT, socket.recv(&mut response))
+        .await
+        .context("Timed out waiting for a response")?
+        .context("Failed to receive response")?;
+    parse(&response[..len], &transaction_id)
+}
+
+/// Extract the mapped address from a binding response, preferring the
+/// XOR-MAPPED-ADDRESS over the legacy MAPPED-ADDRESS attribute.
+fn parse(response: &[u8], transaction_id: &[u8; 12]) -> Result<IpAddr> {
+    if response.len() < 20 {
+        bail!("Response too short");
+    }
+    let message_type = u16::from_be_bytes([response[0], response[1]]);
+    let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+    if response[4..8] != MAGIC_COOKIE.to_be_bytes() || &response[8..20] != transaction_id {
+        bail!("Response doesn't match the request");
+    }
+    if message_type != BINDING_SUCCESS {
+        bail!("Unexpected response type {:#06x}", message_type);
+    }
+    let attributes = response
+        .get(20..20 + length)
+        .ok_or_else(|| anyhow!("Response truncated"))?;
+
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let attribute_type = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let attribute_length =
+            u16::from_be_bytes([attributes[offset + 2], attributes[offset + 3]]) as usize;
+        let value = attributes
+            .get(offset + 4..offset + 4 + attribute_length)
+            .ok_or_else(|| anyhow!("Attribute truncated"))?;
+        match attribute_type {
+            XOR_MAPPED_ADDRESS => return address(value, Some(transaction_id)),
+            MAPPED_ADDRESS => mapped = Some(address(value, None)?),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of four bytes.
+        offset += 4 + attribute_length.div_ceil(4) * 4;
+    }
+    mapped.ok_or_else(|| anyhow!("Response contains no mapped address"))
+}
+
+/// Decode the address of a (XOR-)MAPPED-ADDRESS attribute, which is XORed
+/// with the magic cookie and transaction ID if one is given.
+fn address(value: &[u8], xor_transaction_id: Option<&[u8; 12]>) -> Result<IpAddr> {
+    let mut mask = [0; 16];
+    if let Some(transaction_id) = xor_transaction_id {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction_id);
+    }
+    match (value.get(1), value.get(4..)) {
+        (Some(0x01), Some(addr)) if addr.len() >= 4 => {
+            let mut octets = [0; 4];
+            for (index, octet) in octets.iter_mut().enumerate() {
+                *octet = addr[index] ^ mask[index];
+            }
+            Ok(Ipv4Addr::from(octets).into())
+        }
+        (Some(0x02), Some(addr)) if addr.len() >= 16 => {
+            let mut octets = [0; 16];
+            for (index, octet) in octets.iter_mut().enumerate() {
+                *octet = addr[index] ^ mask[index];
+            }
+            Ok(Ipv6Addr::from(octets).into())
+        }
+        _ => bail!("Malformed address attribute"),
+    }
+}
+
+fn default_servers() -> Vec<String> {
+    vec![
+        "stun.l.google.com:19302".to_string(),
+        "stun.cloudflare.com:3478".to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transaction ID of the sample responses of RFC 5769
+    const TRANSACTION_ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    /// A binding response with the given attributes, padded to four bytes.
+    fn binding_response(attributes: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for (attribute_type, value) in attributes {
+            encoded.extend(attribute_type.to_be_bytes());
+            encoded.extend((value.len() as u16).to_be_bytes());
+            encoded.extend(*value);
+            encoded.resize(encoded.len().next_multiple_of(4), 0);
+        }
+        let mut response = BINDING_SUCCESS.to_be_bytes().to_vec();
+        response.extend((encoded.len() as u16).to_be_bytes());
+        response.extend(MAGIC_COOKIE.to_be_bytes());
+        response.extend(TRANSACTION_ID);
+        response.extend(encoded);
+        response
+    }
+
+    #[test]
+    fn xor_mapped_ipv4() {
+        // RFC 5769 2.2, up to the XOR-MAPPED-ADDRESS, after the SOFTWARE
+        // attribute with its one byte of padding
+        let response = binding_response(&[
+            (0x8022, b"test vector"),
+            (
+                XOR_MAPPED_ADDRESS,
+                &[0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43],
+            ),
+        ]);
+        assert_eq!(&response[20..36], b"\x80\x22\x00\x0btest vector\x00");
+        assert_eq!(
+            parse(&response, &TRANSACTION_ID).unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn xor_mapped_ipv6() {
+        // RFC 5769 2.3, up to the XOR-MAPPED-ADDRESS
+        let response = binding_response(&[
+            (0x8022, b"test vector"),
+            (
+                XOR_MAPPED_ADDRESS,
+                &[
+                    0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc,
+                    0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+                ],
+            ),
+        ]);
+        assert_eq!(
+            parse(&response, &TRANSACTION_ID).unwrap(),
+            "2001:db8:1234:5678:11:2233:4455:6677"
+                .parse::<IpAddr>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn mapped_fallback() {
+        let mapped: &[u8] = &[0x00, 0x01, 0x12, 0x34, 198, 51, 100, 7];
+        let response = binding_response(&[(0x8022, b"odd"), (MAPPED_ADDRESS, mapped)]);
+        assert_eq!(
+            parse(&response, &TRANSACTION_ID).unwrap(),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        // The XOR-MAPPED-ADDRESS is preferred, wherever it is.
+        let xor_mapped: &[u8] = &[0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        let response =
+            binding_response(&[(MAPPED_ADDRESS, mapped), (XOR_MAPPED_ADDRESS, xor_mapped)]);
+        assert_eq!(
+            parse(&response, &TRANSACTION_ID).unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse(&binding_response(&[(0x8022, b"odd")]), &TRANSACTION_ID).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut response = binding_response(&[(
+            XOR_MAPPED_ADDRESS,
+            &[0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43],
+        )]);
+        // The attribute claims to be longer than the message.
+        response[23] = 12;
+        assert!(parse(&response, &TRANSACTION_ID).is_err());
+        // The message claims to be longer than what was received.
+        response[23] = 8;
+        assert!(parse(&response[..response.len() - 1], &TRANSACTION_ID).is_err());
+        assert!(parse(&response[..19], &TRANSACTION_ID).is_err());
+        // An address attribute too short for its family
+        let response = binding_response(&[(XOR_MAPPED_ADDRESS, &[0x00, 0x02, 0xa1, 0x47, 0x01])]);
+        assert!(parse(&response, &TRANSACTION_ID).is_err());
+    }
+
+    #[test]
+    fn mismatched() {
+        let response = binding_response(&[(
+            XOR_MAPPED_ADDRESS,
+            &[0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43],
+        )]);
+        let mut other = TRANSACTION_ID;
+        other[11] ^= 1;
+        assert!(parse(&response, &other).is_err());
+        let mut error_response = response.clone();
+        error_response[..2].copy_from_slice(&0x0111u16.to_be_bytes());
+        assert!(parse(&error_response, &TRANSACTION_ID).is_err());
+    }
+}
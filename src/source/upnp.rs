@@ -0,0 +1,203 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Address detection by asking the router for its external address through
+//! UPnP IGD.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use public_ip::Version;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    time::{Instant, timeout_at},
+};
+
+const SSDP_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// Services offering `GetExternalIPAddress`, in order of preference
+const SERVICE_TYPES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// URL of the router's device description, skipping the SSDP discovery
+    location: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Description {
+    #[serde(rename = "URLBase")]
+    url_base: Option<String>,
+    device: Device,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Device {
+    #[serde(default)]
+    service_list: ServiceList,
+    #[serde(default)]
+    device_list: DeviceList,
+}
+
+#[derive(Deserialize, Default)]
+struct ServiceList {
+    #[serde(default)]
+    service: Vec<Service>,
+}
+
+#[derive(Deserialize, Default)]
+struct DeviceList {
+    #[serde(default)]
+    device: Vec<Device>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Service {
+    service_type: String,
+    #[serde(rename = "controlURL")]
+    control_url: String,
+}
+
+impl Device {
+    /// All services of this device and its embedded devices.
+    fn services(&self) -> Vec<&Service> {
+        let mut services: Vec<_> = self.service_list.service.iter().collect();
+        for device in &self.device_list.device {
+            services.extend(device.services());
+        }
+        services
+    }
+}
+
+impl Config {
+    pub async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        if let Version::V6 = version {
+            bail!("UPnP IGD only reports the external IPv4 address");
+        }
+        let location = match &self.location {
+            Some(location) => location.clone(),
+            None => discover().await?,
+        };
+        let location = Url::parse(&location).context("Invalid device description URL")?;
+        // The router is in the LAN, so it's never reached through a proxy.
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .context("Failed to build HTTP client")?;
+        let body = client
+            .get(location.clone())
+            .send()
+            .await
+            .context("Failed to fetch the device description")?
+            .error_for_status()?
+            .text()
+            .await
+            .context("Failed to read the device description")?;
+        let description: Description =
+            quick_xml::de::from_str(&body).context("Failed to parse the device description")?;
+        let services = description.device.services();
+        let (service_type, control_url) = SERVICE_TYPES
+            .iter()
+            .find_map(|service_type| {
+                services
+                    .iter()
+                    .find(|service| service.service_type == *service_type)
+                    .map(|service| (*service_type, &service.control_url))
+            })
+            .ok_or_else(|| anyhow!("The router offers no WAN connection service"))?;
+        let base = match &description.url_base {
+            Some(url_base) => Url::parse(url_base).context("Invalid URLBase")?,
+            None => location,
+        };
+        let control_url = base.join(control_url).context("Invalid control URL")?;
+
+        let body = client
+            .post(control_url)
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header(
+                "SOAPAction",
+                format!("\"{service_type}#GetExternalIPAddress\""),
+            )
+            .body(format!(
+                "<?xml version=\"1.0\"?>\
+                 <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                 s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+                 <s:Body><u:GetExternalIPAddress xmlns:u=\"{service_type}\"/></s:Body>\
+                 </s:Envelope>"
+            ))
+            .send()
+            .await
+            .context("Failed to send GetExternalIPAddress")?
+            .error_for_status()?
+            .text()
+            .await
+            .context("Failed to read the GetExternalIPAddress response")?;
+        let addr = body
+            .split_once("<NewExternalIPAddress>")
+            .and_then(|(_, rest)| rest.split_once("</NewExternalIPAddress>"))
+            .map(|(addr, _)| addr.trim())
+            .ok_or_else(|| anyhow!("The response contains no external address"))?;
+        let addr: Ipv4Addr = addr
+            .parse()
+            .with_context(|| format!("The router returned an invalid address: {addr}"))?;
+        Ok(vec![addr.into()])
+    }
+}
+
+/// Find the location of the router's device description through SSDP.
+async fn discover() -> Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("Failed to bind socket")?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: 239.255.255.250:1900\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\
+         ST: {SEARCH_TARGET}\r\n\r\n"
+    );
+    socket
+        .send_to(request.as_bytes(), SSDP_ADDR)
+        .await
+        .context("Failed to send SSDP search")?;
+    let deadline = Instant::now() + SEARCH_TIMEOUT;
+    let mut buffer = [0; 2048];
+    loop {
+        let (len, _) = timeout_at(deadline, socket.recv_from(&mut buffer))
+            .await
+            .context("No UPnP gateway answered")?
+            .context("Failed to receive SSDP response")?;
+        let response = String::from_utf8_lossy(&buffer[..len]);
+        let location = response.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header
+                .trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+        if let Some(location) = location {
+            return Ok(location);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Address detection through DNS servers reflecting the address a query came
+//! from, like `myip.opendns.com`.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result, anyhow, bail};
+use hickory_client::client::{Client, ClientHandle};
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{DNSClass, Name, RData, RecordType},
+    runtime::TokioRuntimeProvider,
+    udp::UdpClientStream,
+};
+use public_ip::Version;
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    service: Service,
+    /// Address of the server to query instead of the service's default
+    server: Option<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Service {
+    /// `myip.opendns.com` against OpenDNS's resolvers
+    Opendns,
+    /// `whoami.akamai.net` against Akamai's nameservers
+    Akamai,
+    /// `whoami.cloudflare` in the CHAOS class against Cloudflare's resolvers
+    Cloudflare,
+    /// `o-o.myaddr.l.google.com` TXT against Google's nameservers
+    Google,
+}
+
+impl Service {
+    fn server(self) -> &'static str {
+        match self {
+            Service::Opendns => "resolver1.opendns.com:53",
+            Service::Akamai => "ns1-1.akamaitech.net:53",
+            Service::Cloudflare => "one.one.one.one:53",
+            Service::Google => "ns1.google.com:53",
+        }
+    }
+
+    fn query(self, version: Version) -> (&'static str, DNSClass, RecordType) {
+        let address_type = match version {
+            Version::V6 => RecordType::AAAA,
+            _ => RecordType::A,
+        };
+        match self {
+            Service::Opendns => ("myip.opendns.com.", DNSClass::IN, address_type),
+            Service::Akamai => ("whoami.akamai.net.", DNSClass::IN, address_type),
+            Service::Cloudflare => ("whoami.cloudflare.", DNSClass::CH, RecordType::TXT),
+            Service::Google => ("o-o.myaddr.l.google.com.", DNSClass::IN, RecordType::TXT),
+        }
+    }
+}
+
+impl Config {
+    pub async fn resolve(&self, version: Version) -> Result<Vec<IpAddr>> {
+        let server = match self.server {
+            Some(server) => SocketAddr::new(server, 53),
+            None => lookup_host(self.service.server())
+                .await
+                .context("Failed to resolve the server")?
+                .find(|addr| match version {
+                    Version::V4 => addr.is_ipv4(),
+                    Version::V6 => addr.is_ipv6(),
+                    _ => true,
+                })
+                .ok_or_else(|| anyhow!("The server has no address of this family"))?,
+        };
+        let (name, class, record_type) = self.service.query(version);
+        let conn = UdpClientStream::builder(server, TokioRuntimeProvider::default()).build();
+        let (mut client, bg) = Client::connect(conn).await?;
+        tokio::spawn(bg);
+        let response = client
+            .query(Name::from_ascii(name)?, class, record_type)
+            .await
+            .context("Failed to query the server")?;
+        if response.response_code() != ResponseCode::NoError {
+            bail!("The server refused the query: {}", response.response_code());
+        }
+        let addrs: Vec<IpAddr> = response
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                RData::A(addr) => Some(addr.0.into()),
+                RData::AAAA(addr) => Some(addr.0.into()),
+                // Google's nameservers add an `edns0-client-subnet` string
+                // when the query went through a resolver, so only strings
+                // that are addresses are used.
+                RData::TXT(txt) => txt
+                    .txt_data()
+                    .iter()
+                    .find_map(|data| String::from_utf8_lossy(data).parse().ok()),
+                _ => None,
+            })
+            .collect();
+        if addrs.is_empty() {
+            bail!("The answer contains no address");
+        }
+        Ok(addrs)
+    }
+}
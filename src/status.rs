@@ -0,0 +1,204 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use dyndnsd::{
+    cache::Store,
+    control::{self, Command, Status},
+};
+use serde_json::json;
+
+fn timestamp(timestamp: u64) -> impl Display {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(timestamp))
+}
+
+fn or_none(value: Option<impl Display>) -> String {
+    value.map_or_else(|| "none".to_string(), |value| value.to_string())
+}
+
+/// Print the state of the daemon, asking the running daemon through its
+/// control socket if there is one, and reading the cache otherwise.
+pub async fn print(
+    store: &Store,
+    config_path: &Path,
+    socket: Option<PathBuf>,
+    sources: bool,
+    json: bool,
+) -> Result<()> {
+    let live = match control::socket(socket, config_path) {
+        Some(socket) => match control::send(&socket, Command::Status)
+            .await
+            .and_then(|status| serde_json::from_value(status).context("Invalid status"))
+        {
+            Ok(status) => Some(status),
+            Err(error) => {
+                tracing::warn!("{:#}, reading the cache instead", error);
+                None
+            }
+        },
+        None => None,
+    };
+    let cache = match store.is_memory() {
+        true => Err(anyhow!(
+            "The daemon keeps its cache in memory, set a control_socket to ask it"
+        )),
+        false => store
+            .load()
+            .and_then(|cache| cache.context("No state available, has the daemon run yet?")),
+    };
+    let (status, cache) = match live {
+        Some(status) => (status, cache.ok()),
+        None => {
+            let cache = cache?;
+            (Status::new(&cache, None), Some(cache))
+        }
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+    println!("IPv4:                 {}", or_none(status.ipv4));
+    println!("IPv6:                 {}", or_none(status.ipv6));
+    println!(
+        "Last update:          {}",
+        or_none(status.last_update.map(timestamp))
+    );
+    println!(
+        "Failures:             {} ({} consecutive)",
+        status.failures, status.consecutive_failures
+    );
+    if let Some(error) = &status.last_error {
+        println!("Last error:           {error}");
+    }
+    for (record, providers) in &status.records {
+        println!();
+        println!("{record}:");
+        for (provider, published) in providers {
+            let state = match (published.failing_since, published.last_success) {
+                (Some(since), _) => format!(" (failing since {})", timestamp(since)),
+                (None, Some(success)) => format!(" (set {})", timestamp(success)),
+                (None, None) => String::new(),
+            };
+            let additional: String = published
+                .additional
+                .iter()
+                .map(|addr| format!(", {addr}"))
+                .collect();
+            println!(
+                "  {:<20}{}{}{}",
+                format!("{provider}:"),
+                or_none(published.addr),
+                additional,
+                state
+            );
+        }
+    }
+    // The history of the sources and calls is only kept in the cache.
+    let Some(cache) = cache else {
+        return Ok(());
+    };
+    if !cache.outbound.is_empty() {
+        println!();
+        println!("Outbound calls:");
+        for (target, stats) in &cache.outbound {
+            println!(
+                "  {:<28}{} calls, {} failed, {} retried, {} timed out, last took {} ms",
+                target,
+                stats.calls,
+                stats.failures,
+                stats.retries,
+                stats.timeouts,
+                stats.last_latency_ms
+            );
+        }
+    }
+    if sources {
+        for (source, history) in &cache.sources {
+            println!();
+            println!("{source}:");
+            for sample in history.iter().rev() {
+                let result = match (&sample.addr, &sample.error) {
+                    (Some(addr), _) => addr.to_string(),
+                    (None, Some(error)) => format!("error: {error}"),
+                    (None, None) => "no result".to_string(),
+                };
+                println!(
+                    "  {}  {:>6} ms  {}",
+                    timestamp(sample.timestamp),
+                    sample.latency_ms,
+                    result
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the last `limit` address changes, record updates and failed cycles
+/// kept in the state database, newest first.
+pub fn history(store: &Store, limit: usize, json: bool) -> Result<()> {
+    let Some(db) = store.history() else {
+        bail!("The history is only kept with a state_db set in the config");
+    };
+    let changes = db.changes(limit)?;
+    let updates = db.updates(limit)?;
+    let failures = db.failures(limit)?;
+    if json {
+        let history = json!({
+            "changes": changes,
+            "updates": updates,
+            "failures": failures,
+        });
+        println!("{}", serde_json::to_string_pretty(&history)?);
+        return Ok(());
+    }
+    println!("Address changes:");
+    for change in &changes {
+        println!(
+            "  {}  {}  {} -> {}",
+            timestamp(change.timestamp),
+            change.family,
+            or_none(change.old.as_ref()),
+            change.new
+        );
+    }
+    println!();
+    println!("Record updates:");
+    for update in &updates {
+        let result = match &update.error {
+            Some(error) => format!("failed: {error}"),
+            None => "ok".to_string(),
+        };
+        println!(
+            "  {}  {} {} on {}: {} -> {}, {} ms, {}",
+            timestamp(update.timestamp),
+            update.record,
+            update.record_type,
+            update.provider,
+            or_none(update.old.as_ref()),
+            update.new,
+            update.latency_ms,
+            result
+        );
+    }
+    println!();
+    println!("Failed cycles:");
+    for failure in &failures {
+        println!("  {}  {}", timestamp(failure.timestamp), failure.error);
+    }
+    Ok(())
+}
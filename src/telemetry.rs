@@ -0,0 +1,146 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Export of the spans and metrics of every update cycle to an OpenTelemetry
+//! collector over OTLP/HTTP.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use opentelemetry::{
+    KeyValue,
+    metrics::{Counter, Histogram, MeterProvider},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    metrics::{PeriodicReader, SdkMeterProvider},
+    trace::{SdkTracer, SdkTracerProvider},
+};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{Registry, reload};
+
+use crate::duration::HumanDuration;
+
+/// The layer passing spans to the exporter, only set once the config is
+/// loaded.
+pub type Layer = reload::Layer<Option<OpenTelemetryLayer<Registry, SdkTracer>>, Registry>;
+pub type Handle = reload::Handle<Option<OpenTelemetryLayer<Registry, SdkTracer>>, Registry>;
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Telemetry {
+    /// Base URL of the collector, like `http://localhost:4318`
+    endpoint: String,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    /// Interval in which the metrics are exported
+    #[serde_as(as = "HumanDuration")]
+    #[serde(default = "default_metrics_interval")]
+    metrics_interval: Duration,
+}
+
+/// An exporter that doesn't export anything yet.
+pub fn layer() -> (Layer, Handle) {
+    reload::Layer::new(None)
+}
+
+/// The running exporter, along with the metrics of the update cycles.
+pub struct Exporter {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+    cycles: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+impl Telemetry {
+    /// Start exporting spans and metrics.
+    pub fn start(&self, handle: &Handle) -> Result<Exporter> {
+        let endpoint = self.endpoint.trim_end_matches('/');
+        let resource = Resource::builder()
+            .with_service_name(self.service_name.clone())
+            .build();
+
+        let span_exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{endpoint}/v1/traces"))
+            .build()
+            .context("Failed to create span exporter")?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(span_exporter)
+            .with_resource(resource.clone())
+            .build();
+        let tracer = tracer_provider.tracer(env!("CARGO_PKG_NAME"));
+        handle
+            .reload(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+            .context("Failed to install span exporter")?;
+
+        let metric_exporter = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{endpoint}/v1/metrics"))
+            .build()
+            .context("Failed to create metric exporter")?;
+        let reader = PeriodicReader::builder(metric_exporter)
+            .with_interval(self.metrics_interval)
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+        let meter = meter_provider.meter(env!("CARGO_PKG_NAME"));
+        Ok(Exporter {
+            cycles: meter
+                .u64_counter("dyndnsd.update.cycles")
+                .with_description("Number of update cycles")
+                .build(),
+            duration: meter
+                .f64_histogram("dyndnsd.update.duration")
+                .with_description("Duration of the update cycles")
+                .with_unit("s")
+                .build(),
+            tracer_provider,
+            meter_provider,
+        })
+    }
+}
+
+impl Exporter {
+    /// Record an update cycle, labeled with whether it succeeded.
+    pub fn record(&self, duration: Duration, success: bool) {
+        let result = [KeyValue::new(
+            "result",
+            if success { "success" } else { "failure" },
+        )];
+        self.cycles.add(1, &result);
+        self.duration.record(duration.as_secs_f64(), &result);
+    }
+
+    /// Export what's left and stop.
+    pub fn shutdown(self) {
+        if let Err(error) = self.tracer_provider.shutdown() {
+            tracing::error!("Failed to export remaining spans: {}", error);
+        }
+        if let Err(error) = self.meter_provider.shutdown() {
+            tracing::error!("Failed to export remaining metrics: {}", error);
+        }
+    }
+}
+
+fn default_service_name() -> String {
+    String::from(env!("CARGO_PKG_NAME"))
+}
+
+fn default_metrics_interval() -> Duration {
+    Duration::from_secs(60)
+}
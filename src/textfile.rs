@@ -0,0 +1,222 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Metrics in the Prometheus text format, written to a file after every cycle
+//! for node_exporter's textfile collector.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::Write as _,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::cache::Cache;
+
+/// Check that node_exporter picks up the file at `path`.
+pub fn validate(path: &Path) -> Result<()> {
+    if path.extension().is_none_or(|extension| extension != "prom") {
+        bail!(
+            "{} doesn't end in .prom, so node_exporter ignores it",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Write the metrics of `cache` to `path`. They're written to a temporary
+/// file next to it first, which node_exporter ignores, and then moved over
+/// it, so node_exporter never reads a partially written file.
+pub fn write(path: &Path, cache: &Cache) -> Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&temporary)
+        .with_context(|| format!("Failed to open {}", Path::new(&temporary).display()))?;
+    file.write_all(render(cache).as_bytes())
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {}", Path::new(&temporary).display()))?;
+    fs::rename(&temporary, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    tracing::debug!("Wrote metrics to {}", path.display());
+    Ok(())
+}
+
+/// The metrics of `cache` in the Prometheus text format.
+fn render(cache: &Cache) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        if samples.is_empty() {
+            return;
+        }
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+    metric(
+        "dyndnsd_info",
+        "gauge",
+        "Version of dyndnsd",
+        vec![(
+            labels(&[("version", env!("CARGO_PKG_VERSION"))]),
+            "1".to_string(),
+        )],
+    );
+    let detected = [
+        ("ipv4", cache.v4.map(|addr| addr.to_string())),
+        ("ipv6", cache.v6.map(|addr| addr.to_string())),
+    ];
+    metric(
+        "dyndnsd_detected_address_info",
+        "gauge",
+        "Last detected address of each family",
+        detected
+            .iter()
+            .filter_map(|(family, addr)| {
+                let addr = addr.as_deref()?;
+                Some((
+                    labels(&[("family", family), ("address", addr)]),
+                    "1".to_string(),
+                ))
+            })
+            .collect(),
+    );
+    metric(
+        "dyndnsd_last_update_timestamp_seconds",
+        "gauge",
+        "Time a record was last set",
+        cache
+            .last_update
+            .map(|last_update| (String::new(), last_update.to_string()))
+            .into_iter()
+            .collect(),
+    );
+    metric(
+        "dyndnsd_failed_cycles_total",
+        "counter",
+        "Update cycles that failed",
+        vec![(String::new(), cache.failures.to_string())],
+    );
+    metric(
+        "dyndnsd_consecutive_failed_cycles",
+        "gauge",
+        "Update cycles that failed since the last successful one",
+        vec![(String::new(), cache.consecutive_failures.to_string())],
+    );
+    let records: Vec<_> = cache
+        .records
+        .iter()
+        .flat_map(|(key, providers)| {
+            providers.iter().map(move |(provider, published)| {
+                let labels = labels(&[
+                    ("record", &key.name.to_string()),
+                    ("type", &key.record_type.to_string()),
+                    ("provider", provider),
+                ]);
+                (labels, published)
+            })
+        })
+        .collect();
+    metric(
+        "dyndnsd_record_last_success_timestamp_seconds",
+        "gauge",
+        "Time each record was last set successfully on each provider",
+        records
+            .iter()
+            .filter_map(|(labels, published)| {
+                Some((labels.clone(), published.last_success?.to_string()))
+            })
+            .collect(),
+    );
+    metric(
+        "dyndnsd_record_failing",
+        "gauge",
+        "Whether each record failed to be set on each provider since its last change",
+        records
+            .iter()
+            .map(|(labels, published)| {
+                let failing = u8::from(published.failing_since.is_some());
+                (labels.clone(), failing.to_string())
+            })
+            .collect(),
+    );
+    let outbound = |value: fn(&crate::outbound::Stats) -> u64| {
+        cache
+            .outbound
+            .iter()
+            .map(|(target, stats)| (labels(&[("target", target)]), value(stats).to_string()))
+            .collect()
+    };
+    metric(
+        "dyndnsd_outbound_calls_total",
+        "counter",
+        "Calls to each provider and source since startup",
+        outbound(|stats| stats.calls),
+    );
+    metric(
+        "dyndnsd_outbound_failures_total",
+        "counter",
+        "Calls to each provider and source that failed since startup",
+        outbound(|stats| stats.failures),
+    );
+    metric(
+        "dyndnsd_outbound_retries_total",
+        "counter",
+        "Retries of calls to each provider and source since startup",
+        outbound(|stats| stats.retries),
+    );
+    metric(
+        "dyndnsd_outbound_timeouts_total",
+        "counter",
+        "Calls to each provider and source that timed out since startup",
+        outbound(|stats| stats.timeouts),
+    );
+    metric(
+        "dyndnsd_outbound_last_latency_seconds",
+        "gauge",
+        "Latency of the last call to each provider and source",
+        cache
+            .outbound
+            .iter()
+            .map(|(target, stats)| {
+                (
+                    labels(&[("target", target)]),
+                    (stats.last_latency_ms as f64 / 1000.0).to_string(),
+                )
+            })
+            .collect(),
+    );
+    out
+}
+
+/// Label set of a sample, with the values escaped.
+fn labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', r#"\""#)
+                .replace('\n', r"\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
@@ -0,0 +1,1263 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! The update loop, detecting the current addresses and publishing them to
+//! every provider, each address family on its own schedule.
+
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Result, anyhow, bail};
+use hickory_proto::rr::{
+    Name, RData, RecordType,
+    rdata::{PTR, TXT},
+};
+use public_ip::Version;
+use tokio::time::{Instant, sleep_until};
+use tracing::Instrument;
+
+use crate::{
+    Config, DnsProvider,
+    bogon::{self, Policy as BogonPolicy},
+    cache::{self, Cache, Lock, Published, RecordKey, Store},
+    config::metadata_name,
+    control::Status,
+    dns, filter,
+    history::{self, Db},
+    notify::Event,
+    now, proxy, route,
+    server::{Answer, Buckets, Push},
+    source, textfile,
+};
+
+/// Keeps the records of a [`Config`] up to date, along with the cache of what
+/// was published.
+pub struct Updater {
+    config: Config,
+    cache: Cache,
+    store: Store,
+    /// Keeps other instances from using the same store
+    _lock: Lock,
+    /// Error of the last failed cycle
+    last_error: Option<String>,
+    /// When the next update of each enabled family is due
+    next_v4: Option<Instant>,
+    next_v6: Option<Instant>,
+    /// Whether each family was found to be offline in its last update
+    offline_v4: bool,
+    offline_v6: bool,
+    /// Updates of each name pushed through the dyndns2 endpoint
+    pushed: Buckets<Name>,
+}
+
+impl Updater {
+    /// An updater keeping its cache in `cache_dir`, or in the state database
+    /// if one is configured, with the updates of both families due right away.
+    /// Fails if another updater is using the same cache. The proxy of the
+    /// config is used for all HTTP clients created afterwards.
+    pub fn new(config: Config, cache_dir: &Path) -> Result<Self> {
+        proxy::set(config.proxy.clone());
+        let store = Store::new(cache_dir, config.cache, config.state_db.as_deref())?;
+        let lock = store.lock()?;
+        let mut cache = store.load_checked(config.strict_state)?;
+        cache.migrate(&config.domain);
+        let start = Instant::now();
+        Ok(Self {
+            next_v4: config.ipv4.then_some(start),
+            next_v6: config.ipv6.then_some(start),
+            config,
+            cache,
+            store,
+            _lock: lock,
+            last_error: None,
+            offline_v4: false,
+            offline_v6: false,
+            pushed: Buckets::default(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// The detected addresses and the state of the records.
+    pub fn status(&self) -> Status {
+        Status::new(&self.cache, self.last_error.clone())
+    }
+
+    /// Replace the config, updating the records of the new one right away.
+    /// The cache stays where it is until the updater is created again.
+    pub fn set_config(&mut self, config: Config) {
+        proxy::set(config.proxy.clone());
+        self.config = config;
+        self.update_now();
+    }
+
+    /// Make the updates of all enabled families due right away.
+    pub fn update_now(&mut self) {
+        let now = Instant::now();
+        self.next_v4 = self.config.ipv4.then_some(now);
+        self.next_v6 = self.config.ipv6.then_some(now);
+    }
+
+    /// Forget the published addresses, so the next cycle updates every record.
+    pub fn flush(&mut self) -> Result<()> {
+        self.cache.flush();
+        tracing::info!("Flushed the cache, every record is updated in the next cycle");
+        self.store.write(&self.cache)
+    }
+
+    /// When the next update is due.
+    pub fn next_due(&self) -> Instant {
+        self.next_v4
+            .into_iter()
+            .chain(self.next_v6)
+            .min()
+            .expect("at least one family is enabled")
+    }
+
+    /// Update the families that are due, unless they're offline, returning
+    /// the result of the cycle, or `None` if no family was updated.
+    pub async fn tick(&mut self) -> Option<Result<()>> {
+        let config = &self.config;
+        let now = Instant::now();
+        let mut ipv4 = self.next_v4.is_some_and(|next| next <= now);
+        let mut ipv6 = self.next_v6.is_some_and(|next| next <= now);
+        if ipv4 {
+            self.next_v4 = Some(now + config.interval_v4.unwrap_or(config.interval));
+            ipv4 = online(config, Version::V4, &mut self.offline_v4);
+        }
+        if ipv6 {
+            self.next_v6 = Some(now + config.interval_v6.unwrap_or(config.interval));
+            ipv6 = online(config, Version::V6, &mut self.offline_v6);
+        }
+        if !ipv4 && !ipv6 {
+            return None;
+        }
+        Some(self.cycle(ipv4, ipv6, None).await)
+    }
+
+    /// Publish `addrs` once, instead of detecting the addresses, as an update
+    /// of the enabled families among them, e.g. when the network tells what
+    /// the new address is. The filters still apply.
+    pub async fn update_with(&mut self, addrs: &[IpAddr]) -> Result<()> {
+        let ipv4 = self.config.ipv4 && addrs.iter().any(IpAddr::is_ipv4);
+        let ipv6 = self.config.ipv6 && addrs.iter().any(IpAddr::is_ipv6);
+        if !ipv4 && !ipv6 {
+            bail!("None of {:?} is of an enabled address family", addrs);
+        }
+        self.cycle(ipv4, ipv6, Some(addrs)).await
+    }
+
+    /// Update the given families, with the `given` addresses if there are
+    /// any, and record the result.
+    async fn cycle(&mut self, ipv4: bool, ipv6: bool, given: Option<&[IpAddr]>) -> Result<()> {
+        let config = &self.config;
+        let result = update(config, &mut self.cache, &self.store, ipv4, ipv6, given).await;
+        match &result {
+            Ok(()) => self.cache.consecutive_failures = 0,
+            Err(error) => {
+                tracing::error!("Failed to update record: {:#?}", error);
+                let error = format!("{error:#}");
+                record_history(&self.store, |db| db.record_failure(&error));
+                self.last_error = Some(error);
+                self.cache.failures += 1;
+                self.cache.consecutive_failures += 1;
+            }
+        }
+        self.cache.outbound = config.outbound.stats();
+        // Written after every cycle, to keep the source history current.
+        if let Err(error) = self.store.write(&self.cache) {
+            tracing::error!("Failed to write cache: {:#?}", error);
+        }
+        if let Some(path) = &config.prometheus_textfile
+            && let Err(error) = textfile::write(path, &self.cache)
+        {
+            tracing::error!("Failed to write metrics: {:#?}", error);
+        }
+        if let Some(mqtt) = &config.mqtt {
+            mqtt.update(&self.cache, &config.outbound).await;
+        }
+        if let Some(healthchecks) = &config.healthchecks {
+            healthchecks.ping(&result, &config.outbound).await;
+        }
+        result
+    }
+
+    /// Check that the clock is close enough to the clocks of the providers
+    /// for them to accept signed updates, logging an error for the ones that
+    /// reject the signature because of it, or fail to be checked.
+    pub async fn check_clocks(&self) {
+        let config = &self.config;
+        for (id, provider) in config.providers() {
+            let result = config
+                .outbound
+                .call(&id, || {
+                    provider.check_clock(&config.domain, config.zone().clone())
+                })
+                .await;
+            if let Err(error) = result {
+                tracing::error!("Failed to check the clock against {}: {:#}", id, error);
+            }
+        }
+    }
+
+    /// With `seed_from_dns`, or with the cache kept in memory, replace the
+    /// cached state of the records with what the providers currently serve,
+    /// for the providers that can be queried. The cached state is kept for
+    /// the records that fail to be queried.
+    pub async fn seed(&mut self) {
+        let config = &self.config;
+        if !config.seed_from_dns && !self.store.is_memory() {
+            return;
+        }
+        let mut records = Vec::new();
+        if config.ipv4 {
+            records.push((config.domain.clone(), RecordType::A));
+        }
+        if config.ipv6 {
+            records.push((config.domain.clone(), RecordType::AAAA));
+            records.extend(
+                config
+                    .lan
+                    .hosts
+                    .iter()
+                    .map(|host| (host.domain.clone(), RecordType::AAAA)),
+            );
+        }
+        for (id, provider) in config.providers() {
+            if !provider.can_get_records() {
+                tracing::debug!("{} can't be queried, keeping its cached state", id);
+                continue;
+            }
+            for (name, record_type) in &records {
+                let key = RecordKey {
+                    name: name.clone(),
+                    record_type: *record_type,
+                };
+                let result = config
+                    .outbound
+                    .call(&id, || provider.get_records(*record_type, name.clone()))
+                    .await;
+                let rdatas = match result {
+                    Ok(rdatas) => rdatas,
+                    Err(error) => {
+                        tracing::warn!(
+                            "Failed to query {} on {}, keeping its cached state: {:#}",
+                            key,
+                            id,
+                            error
+                        );
+                        continue;
+                    }
+                };
+                let mut addrs: Vec<IpAddr> = rdatas.iter().filter_map(addr).collect();
+                addrs.sort();
+                tracing::info!(
+                    "{} is {} on {}",
+                    key,
+                    match addrs.is_empty() {
+                        true => "not set".to_string(),
+                        false => addrs
+                            .iter()
+                            .map(IpAddr::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    },
+                    id
+                );
+                let published = self
+                    .cache
+                    .records
+                    .entry(key)
+                    .or_default()
+                    .entry(id.clone())
+                    .or_default();
+                published.addr = addrs.first().copied();
+                published.additional = addrs.iter().skip(1).copied().collect();
+                published.failing_since = None;
+                published.last_verified = Some(now());
+            }
+        }
+    }
+
+    /// With `delete_on_shutdown`, delete the records on every provider, or
+    /// set them to the fallback addresses of their family, so they no longer
+    /// point at a host that is going away. Meant to be called once the
+    /// updates have stopped. Failures are only logged.
+    pub async fn withdraw(&mut self) {
+        let config = &self.config;
+        if !config.delete_on_shutdown {
+            return;
+        }
+        let mut records = Vec::new();
+        if config.ipv4 {
+            records.push((&config.domain, &config.aliases, RecordType::A));
+        }
+        if config.ipv6 {
+            records.push((&config.domain, &config.aliases, RecordType::AAAA));
+            records.extend(
+                config
+                    .lan
+                    .hosts
+                    .iter()
+                    .map(|host| (&host.domain, &host.aliases, RecordType::AAAA)),
+            );
+        }
+        for (id, provider) in config.providers() {
+            for (record, aliases, record_type) in &records {
+                let key = RecordKey {
+                    name: (*record).clone(),
+                    record_type: *record_type,
+                };
+                let fallback: Vec<IpAddr> = config
+                    .shutdown_fallback
+                    .iter()
+                    .copied()
+                    .filter(|addr| addr.is_ipv4() == (*record_type == RecordType::A))
+                    .collect();
+                let published = self
+                    .cache
+                    .records
+                    .entry(key.clone())
+                    .or_default()
+                    .entry(id.clone())
+                    .or_default();
+                let old: Vec<IpAddr> = published
+                    .addr
+                    .into_iter()
+                    .chain(published.additional.iter().copied())
+                    .collect();
+                let describe = |addrs: &[IpAddr]| {
+                    (!addrs.is_empty()).then(|| {
+                        addrs
+                            .iter()
+                            .map(IpAddr::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                };
+                let change = config.outbound.call(&id, || async {
+                    if fallback.is_empty() {
+                        for name in std::iter::once(*record).chain(aliases.iter()) {
+                            provider
+                                .delete_records(*record_type, name.clone(), config.zone().clone())
+                                .await?;
+                        }
+                        Ok(())
+                    } else {
+                        provider
+                            .set_records_with_aliases(
+                                fallback.iter().copied().map(rdata).collect(),
+                                (*record).clone(),
+                                aliases,
+                                config.zone().clone(),
+                                None,
+                            )
+                            .await
+                    }
+                });
+                match describe(&fallback) {
+                    Some(fallback) => {
+                        tracing::info!("Setting {} to {} on {}", key, fallback, id)
+                    }
+                    None => tracing::info!("Deleting {} on {}", key, id),
+                }
+                let result = config
+                    .audited(
+                        provider,
+                        record,
+                        *record_type,
+                        describe(&old),
+                        describe(&fallback),
+                        change,
+                    )
+                    .await;
+                if let Err(error) = result {
+                    tracing::error!("Failed to withdraw {} on {}: {:#}", key, id, error);
+                    continue;
+                }
+                // The records are set again on the next start, as the
+                // current addresses differ from the cached ones.
+                published.addr = fallback.first().copied();
+                published.additional = fallback.iter().skip(1).copied().collect();
+                published.last_success = Some(now());
+                if !config.reverse_zones.is_empty() {
+                    publish_reverse(config, &id, provider, record, &[], &old).await;
+                }
+            }
+        }
+        if let Err(error) = self.store.write(&self.cache) {
+            tracing::error!("Failed to write cache: {:#?}", error);
+        }
+    }
+
+    /// Publish the addresses a dyndns2 client pushed to every provider, and
+    /// answer it with the result for each of its hostnames. The addresses are
+    /// subject to the bogon policy, like detected ones.
+    pub async fn push(&mut self, push: Push) {
+        let config = &self.config;
+        let names = push.authorize(config.server.as_ref());
+        let mut refused = Vec::new();
+        let addrs: Vec<IpAddr> = push
+            .addrs
+            .iter()
+            .copied()
+            .filter(|addr| publishable(config, *addr, &mut refused))
+            .collect();
+        let (v4, v6): (Vec<_>, Vec<_>) = addrs.iter().partition(|addr| addr.is_ipv4());
+        let cache = Mutex::new(std::mem::take(&mut self.cache));
+        let mut answers = Vec::new();
+        for name in &names {
+            let name = match name {
+                Ok(name) => name,
+                Err(answer) => {
+                    answers.push(answer.clone());
+                    continue;
+                }
+            };
+            if let Some(server) = &config.server
+                && self
+                    .pushed
+                    .take(name.clone(), server.hostname_limit)
+                    .is_err()
+            {
+                tracing::warn!("{} pushed {} too often, refusing", push.username, name);
+                answers.push(Answer::Abuse);
+                continue;
+            }
+            let targets: Vec<_> = [v4.clone(), v6.clone()]
+                .into_iter()
+                .filter(|current| !current.is_empty())
+                .map(|current| Target {
+                    record: name,
+                    aliases: &[],
+                    current,
+                })
+                .collect();
+            let changed = targets.iter().any(|target| {
+                config
+                    .providers()
+                    .any(|(id, _)| !published(&cache::lock(&cache), &id, target))
+            });
+            tracing::info!("{} pushed {:?} for {}", push.username, addrs, name);
+            let mut failed = refused.clone();
+            publish(config, &cache, &self.store, &targets, &mut failed).await;
+            answers.push(if !failed.is_empty() {
+                Answer::DnsErr
+            } else if changed {
+                Answer::Good(push.addrs.clone())
+            } else {
+                Answer::NoChange(push.addrs.clone())
+            });
+        }
+        self.cache = cache
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(error) = self.store.write(&self.cache) {
+            tracing::error!("Failed to write cache: {:#?}", error);
+        }
+        push.reply(answers);
+    }
+
+    /// Keep the records up to date until the future is dropped.
+    pub async fn run(&mut self) {
+        self.check_clocks().await;
+        self.seed().await;
+        loop {
+            self.tick().await;
+            sleep_until(self.next_due()).await;
+        }
+    }
+}
+
+/// Add to the history, if the store keeps one. Failing to do so doesn't fail
+/// the update.
+fn record_history(store: &Store, record: impl FnOnce(&Db) -> Result<()>) {
+    if let Some(db) = store.history()
+        && let Err(error) = record(db)
+    {
+        tracing::error!("Failed to record history: {:#?}", error);
+    }
+}
+
+/// Whether the family should be updated, as the host has a default route for
+/// it. Only changes between online and offline are logged, to keep planned
+/// outages from flooding the logs.
+fn online(config: &Config, version: Version, offline: &mut bool) -> bool {
+    if !config.skip_offline {
+        return true;
+    }
+    let family = match version {
+        Version::V6 => "IPv6",
+        _ => "IPv4",
+    };
+    let online = route::has_default(version);
+    match (online, *offline) {
+        (false, false) => tracing::info!("No {} default route, skipping updates", family),
+        (true, true) => tracing::info!("{} default route is back, resuming updates", family),
+        (false, true) => tracing::debug!("Still no {} default route", family),
+        (true, false) => {}
+    }
+    *offline = !online;
+    online
+}
+
+/// Bring the records of all providers up to date with the current addresses
+/// of the given families.
+///
+/// Every provider and address family is handled on its own, so a failure
+/// doesn't hold back the others. Providers that failed keep their last
+/// published address in the cache, so they're caught up in a later cycle,
+/// even if the address doesn't change in the meantime.
+#[tracing::instrument(skip(config, cache, store, given))]
+async fn update(
+    config: &Config,
+    cache: &mut Cache,
+    store: &Store,
+    ipv4: bool,
+    ipv6: bool,
+    given: Option<&[IpAddr]>,
+) -> Result<()> {
+    // Both families are handled at the same time, so a slow one doesn't hold
+    // back the other. They share the cache, which is only locked briefly.
+    let shared = Mutex::new(std::mem::take(cache));
+    let (failed_v4, failed_v6) = tokio::join!(
+        update_ipv4(config, &shared, store, ipv4, given),
+        update_ipv6(config, &shared, store, ipv6, given),
+    );
+    *cache = shared
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let failed = [failed_v4, failed_v6].concat();
+    if !failed.is_empty() {
+        bail!("Failed: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Detect and publish the current IPv4 address, or the `given` one, returning
+/// what failed.
+async fn update_ipv4(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    store: &Store,
+    enabled: bool,
+    given: Option<&[IpAddr]>,
+) -> Vec<String> {
+    let mut failed = Vec::new();
+    if !enabled {
+        return failed;
+    }
+    match detect(config, cache, Version::V4, given).await.as_deref() {
+        Some([IpAddr::V4(current), ..]) => {
+            tracing::debug!("fetched current IP: {}", current);
+            let old = cache::lock(cache).v4.replace(*current);
+            if old != Some(*current) {
+                record_history(store, |db| {
+                    db.record_change("ipv4", old.map(Into::into), (*current).into())
+                });
+            }
+            if publishable(config, (*current).into(), &mut failed) {
+                let target = Target {
+                    record: &config.domain,
+                    aliases: &config.aliases,
+                    current: vec![(*current).into()],
+                };
+                publish(config, cache, store, &[target], &mut failed).await;
+            }
+        }
+        _ => failed.push("IPv4 address detection".to_string()),
+    }
+    failed
+}
+
+/// Detect and publish the current IPv6 address, or the `given` one, along
+/// with the addresses of the LAN hosts, returning what failed.
+async fn update_ipv6(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    store: &Store,
+    enabled: bool,
+    given: Option<&[IpAddr]>,
+) -> Vec<String> {
+    let mut failed = Vec::new();
+    if !enabled {
+        return failed;
+    }
+    match detect(config, cache, Version::V6, given).await.as_deref() {
+        Some(addrs @ [IpAddr::V6(current), ..]) => {
+            tracing::debug!("fetched current IP: {}", current);
+            let old = cache::lock(cache).v6.replace(*current);
+            if old != Some(*current) {
+                record_history(store, |db| {
+                    db.record_change("ipv6", old.map(Into::into), (*current).into())
+                });
+            }
+            if publishable(config, (*current).into(), &mut failed) {
+                let mut published = vec![(*current).into()];
+                if config.publish_all_ipv6 {
+                    // Further bogons are left out quietly, as long as the
+                    // first address is fine.
+                    published = addrs
+                        .iter()
+                        .copied()
+                        .filter(|addr| {
+                            addr.is_ipv6()
+                                && (bogon::reason(*addr).is_none()
+                                    || matches!(config.bogon_policy, BogonPolicy::Update))
+                        })
+                        .collect();
+                    published.sort();
+                    published.dedup();
+                }
+                // The LAN hosts change along with the prefix, so they're
+                // published together, in one update where possible.
+                let targets: Vec<_> = std::iter::once(Target {
+                    record: &config.domain,
+                    aliases: &config.aliases,
+                    current: published,
+                })
+                .chain(config.lan.addresses(*current).map(|(host, addr)| Target {
+                    record: &host.domain,
+                    aliases: &host.aliases,
+                    current: vec![addr.into()],
+                }))
+                .collect();
+                publish(config, cache, store, &targets, &mut failed).await;
+            }
+        }
+        _ => failed.push("IPv6 address detection".to_string()),
+    }
+    failed
+}
+
+/// The current addresses of the family of `version`, the `given` ones if
+/// there are any, or else the ones detected by the sources, after filtering.
+async fn detect(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    version: Version,
+    given: Option<&[IpAddr]>,
+) -> Option<Vec<IpAddr>> {
+    let filters = match version {
+        Version::V6 => &config.filters.ipv6,
+        _ => &config.filters.ipv4,
+    };
+    let Some(given) = given else {
+        return source::detect(version, cache, &config.sources, filters, &config.outbound).await;
+    };
+    let given = given
+        .iter()
+        .copied()
+        .filter(|addr| addr.is_ipv6() == matches!(version, Version::V6))
+        .collect();
+    Some(filter::apply(filters, given))
+}
+
+/// Whether the detected address may be published, according to the bogon
+/// policy. Refused addresses are added to `failed` with the fail policy.
+fn publishable(config: &Config, addr: IpAddr, failed: &mut Vec<String>) -> bool {
+    let Some(reason) = bogon::reason(addr) else {
+        return true;
+    };
+    match config.bogon_policy {
+        BogonPolicy::Skip => {
+            tracing::warn!("{} is {}, keeping the records as they are", addr, reason);
+            false
+        }
+        BogonPolicy::Fail => {
+            tracing::error!("{} is {}, refusing to publish it", addr, reason);
+            failed.push(format!("publishing {addr}, which is {reason}"));
+            false
+        }
+        BogonPolicy::Update => {
+            tracing::warn!("{} is {}, publishing it anyway", addr, reason);
+            true
+        }
+    }
+}
+
+/// Whether the current addresses of `target` are what's published on the
+/// provider `id`, according to the cache.
+fn published(cache: &Cache, id: &str, target: &Target<'_>) -> bool {
+    let key = RecordKey {
+        name: target.record.clone(),
+        record_type: match target.current.first() {
+            Some(IpAddr::V6(_)) => RecordType::AAAA,
+            _ => RecordType::A,
+        },
+    };
+    cache
+        .records
+        .get(&key)
+        .and_then(|providers| providers.get(id))
+        .is_some_and(|published| {
+            published.addr.as_ref() == target.current.first()
+                && published.additional == target.current[1..]
+        })
+}
+
+/// The address of an A or AAAA record.
+fn addr(rdata: &RData) -> Option<IpAddr> {
+    match rdata {
+        RData::A(addr) => Some(IpAddr::V4(addr.0)),
+        RData::AAAA(addr) => Some(IpAddr::V6(addr.0)),
+        _ => None,
+    }
+}
+
+/// The A or AAAA record of `addr`.
+fn rdata(addr: IpAddr) -> RData {
+    match addr {
+        IpAddr::V4(addr) => RData::A(addr.into()),
+        IpAddr::V6(addr) => RData::AAAA(addr.into()),
+    }
+}
+
+/// Whether `record` is still set to the `current` addresses on the provider.
+/// Providers that can't be queried are never verified, so the record is set
+/// again instead.
+async fn verify(
+    config: &Config,
+    id: &str,
+    provider: &DnsProvider,
+    record: &RecordKey,
+    current: &[IpAddr],
+) -> bool {
+    if !provider.can_get_records() {
+        tracing::info!(
+            "Setting {} on {} again, as it can't be verified",
+            record,
+            id
+        );
+        return false;
+    }
+    let result = config
+        .outbound
+        .call(id, || {
+            provider.get_records(record.record_type, record.name.clone())
+        })
+        .await;
+    match result {
+        Ok(rdatas) => {
+            let mut published: Vec<IpAddr> = rdatas.iter().filter_map(addr).collect();
+            published.sort();
+            let mut current = current.to_vec();
+            current.sort();
+            if published == current {
+                tracing::debug!("{} verified on {}", record, id);
+                return true;
+            }
+            tracing::warn!(
+                "{} was changed on {} behind our back, setting it again",
+                record,
+                id
+            );
+        }
+        Err(error) => {
+            tracing::warn!(
+                "Failed to verify {} on {}, setting it again: {:#}",
+                record,
+                id,
+                error
+            );
+        }
+    }
+    false
+}
+
+/// A record and the addresses it's published with, all of the same family.
+struct Target<'a> {
+    record: &'a Name,
+    aliases: &'a [Name],
+    current: Vec<IpAddr>,
+}
+
+/// A record that has to be set on a provider, as found by [`prepare`].
+struct Change<'a> {
+    target: &'a Target<'a>,
+    key: RecordKey,
+    /// What was published before, a copy as the cache can't stay locked
+    /// during the update
+    published: Published,
+    /// Whether the record is only set again, to reassert it
+    unchanged: bool,
+    old: Option<String>,
+    new: String,
+    /// What the record has to hold for the update to apply, with
+    /// prerequisites enabled
+    expected: Option<Vec<RData>>,
+}
+
+impl Change<'_> {
+    fn rdatas(&self) -> Vec<RData> {
+        self.target.current.iter().copied().map(rdata).collect()
+    }
+
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "publish",
+            record = %self.target.record,
+            record_type = %self.key.record_type
+        )
+    }
+}
+
+/// Publish the current addresses of the `targets` to every provider that
+/// isn't up to date yet, adding the providers that failed to `failed`. Where
+/// the provider supports it, all changes are sent to it in one update.
+async fn publish(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    store: &Store,
+    targets: &[Target<'_>],
+    failed: &mut Vec<String>,
+) {
+    for (id, provider) in config.providers() {
+        let mut changes = Vec::new();
+        for target in targets {
+            let span = tracing::info_span!("publish", record = %target.record);
+            changes.extend(
+                prepare(config, cache, &id, provider, target)
+                    .instrument(span)
+                    .await,
+            );
+        }
+        let records: Vec<_> = changes.iter().map(|change| change.target.record).collect();
+        if changes.len() > 1 && provider.can_batch(&records) {
+            tracing::info!("Setting {} records on {} at once", changes.len(), id);
+            let replacements: Vec<_> = changes
+                .iter()
+                .map(|change| {
+                    let names = std::iter::once(change.target.record)
+                        .chain(change.target.aliases)
+                        .cloned()
+                        .collect();
+                    dns::Replacement::new(names, change.rdatas(), change.expected.clone())
+                })
+                .collect();
+            let started = Instant::now();
+            let batch = config
+                .outbound
+                .call(&id, || {
+                    provider.set_batch(replacements.clone(), config.zone().clone())
+                })
+                .instrument(tracing::info_span!("set_batch", provider = %id));
+            let audited = changes
+                .iter()
+                .map(|change| {
+                    (
+                        change.target.record,
+                        change.key.record_type,
+                        change.old.clone(),
+                        Some(change.new.clone()),
+                    )
+                })
+                .collect();
+            let result = config.audited_batch(provider, audited, batch).await;
+            let latency = started.elapsed();
+            for change in changes {
+                let span = change.span();
+                let result = result
+                    .as_ref()
+                    .map(|_| ())
+                    .map_err(|error| anyhow!("{error:#}"));
+                finish(
+                    config, cache, store, &id, provider, change, result, latency, failed,
+                )
+                .instrument(span)
+                .await;
+            }
+        } else {
+            for change in changes {
+                let span = change.span();
+                async {
+                    let started = Instant::now();
+                    let result = set(config, &id, provider, &change).await;
+                    let latency = started.elapsed();
+                    finish(
+                        config, cache, store, &id, provider, change, result, latency, failed,
+                    )
+                    .await;
+                }
+                .instrument(span)
+                .await;
+            }
+        }
+    }
+}
+
+/// The change needed to publish `target` on the provider `id`, or `None` if
+/// it's up to date there.
+async fn prepare<'a>(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    id: &str,
+    provider: &DnsProvider,
+    target: &'a Target<'a>,
+) -> Option<Change<'a>> {
+    let (first, additional) = target
+        .current
+        .split_first()
+        .expect("at least one address is published");
+    let record_type = match first {
+        IpAddr::V4(_) => RecordType::A,
+        IpAddr::V6(_) => RecordType::AAAA,
+    };
+    let key = RecordKey {
+        name: target.record.clone(),
+        record_type,
+    };
+    let published = cache::lock(cache)
+        .records
+        .entry(key.clone())
+        .or_default()
+        .entry(id.to_owned())
+        .or_default()
+        .clone();
+    let unchanged = published.addr == Some(*first) && published.additional == additional;
+    let reassert = config
+        .reassert_interval
+        .is_some_and(|interval| published.stale(interval));
+    if unchanged && !reassert {
+        tracing::debug!("{} unchanged on {}, continuing...", key, id);
+        return None;
+    }
+    if unchanged {
+        if verify(config, id, provider, &key, &target.current).await {
+            cache::lock(cache)
+                .records
+                .entry(key.clone())
+                .or_default()
+                .entry(id.to_owned())
+                .or_default()
+                .last_verified = Some(now());
+            return None;
+        }
+    } else if published.failing_since.is_some() {
+        tracing::info!("{} not yet published to {}, retrying", key, id);
+    } else {
+        tracing::info!("{} changed, setting record on {}", key, id);
+    }
+    let old = published.addr.map(|old| {
+        std::iter::once(old)
+            .chain(published.additional.iter().copied())
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    });
+    let new = target
+        .current
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let expected = published
+        .addr
+        .filter(|_| provider.checks_prerequisites())
+        .map(|addr| {
+            std::iter::once(addr)
+                .chain(published.additional.iter().copied())
+                .map(rdata)
+                .collect()
+        });
+    Some(Change {
+        target,
+        key,
+        published,
+        unchanged,
+        old,
+        new,
+        expected,
+    })
+}
+
+/// Set the record of `change` on its own.
+async fn set(config: &Config, id: &str, provider: &DnsProvider, change: &Change<'_>) -> Result<()> {
+    let Target {
+        record, aliases, ..
+    } = change.target;
+    let (first, additional) = change
+        .target
+        .current
+        .split_first()
+        .expect("at least one address is published");
+    let set = config
+        .outbound
+        .call(id, || async {
+            match (first, additional, aliases, &change.expected) {
+                (IpAddr::V4(addr), [], [], None) => {
+                    provider
+                        .set_ipv4(*addr, (*record).clone(), config.zone().clone())
+                        .await
+                }
+                (IpAddr::V6(addr), [], [], None) => {
+                    provider
+                        .set_ipv6(*addr, (*record).clone(), config.zone().clone())
+                        .await
+                }
+                _ => {
+                    provider
+                        .set_records_with_aliases(
+                            change.rdatas(),
+                            (*record).clone(),
+                            aliases,
+                            config.zone().clone(),
+                            change.expected.as_deref(),
+                        )
+                        .await
+                }
+            }
+        })
+        .instrument(tracing::info_span!("set_record", provider = %id));
+    config
+        .audited(
+            provider,
+            record,
+            change.key.record_type,
+            change.old.clone(),
+            Some(change.new.clone()),
+            set,
+        )
+        .await
+}
+
+/// Record the `result` of `change` in the history and the cache, and tell
+/// the notification channels and hooks about it.
+#[allow(clippy::too_many_arguments)]
+async fn finish(
+    config: &Config,
+    cache: &Mutex<Cache>,
+    store: &Store,
+    id: &str,
+    provider: &DnsProvider,
+    change: Change<'_>,
+    result: Result<()>,
+    latency: Duration,
+    failed: &mut Vec<String>,
+) {
+    let Change {
+        target,
+        key,
+        published,
+        unchanged,
+        old,
+        new,
+        ..
+    } = change;
+    let record = target.record;
+    let record_type = key.record_type;
+    let (first, additional) = target
+        .current
+        .split_first()
+        .expect("at least one address is published");
+    let family = match first {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    };
+    record_history(store, |db| {
+        db.record_update(&history::Update {
+            timestamp: now(),
+            record: record.to_string(),
+            record_type: record_type.to_string(),
+            provider: id.to_owned(),
+            old: old.clone(),
+            new: new.clone(),
+            latency_ms: latency.as_millis() as u64,
+            error: result.as_ref().err().map(|error| format!("{error:#}")),
+        })
+    });
+    let mut event = Event::new(
+        record.to_string(),
+        record_type.to_string(),
+        id.to_owned(),
+        old,
+        new,
+        &result,
+    );
+    if result.is_err() {
+        // Most channels are only notified when the provider starts
+        // failing, not again on every retry.
+        event.retry = published.failing_since.is_some();
+        event.failing_since = Some(published.failing_since.unwrap_or_else(now));
+    }
+    // The cache is unlocked again before the notifications are sent and
+    // the hooks are run.
+    {
+        let mut cache = cache::lock(cache);
+        let published = cache
+            .records
+            .entry(key.clone())
+            .or_default()
+            .entry(id.to_owned())
+            .or_default();
+        match result {
+            Ok(()) => {
+                tracing::info!(
+                    provider = %id,
+                    old_ip = event.old.as_deref().unwrap_or_default(),
+                    new_ip = %event.new,
+                    "Set {} record of {} to {} on {}",
+                    record_type,
+                    record,
+                    event.new,
+                    id
+                );
+                if published.failing_since.take().is_some() {
+                    tracing::info!("{} caught up with the current {} address", id, family);
+                }
+                published.addr = Some(*first);
+                published.additional = additional.to_vec();
+                published.last_success = Some(now());
+                cache.last_update = Some(now());
+                if let Err(error) = store.write(&cache) {
+                    tracing::error!("Failed to write cache: {:#?}", error);
+                }
+            }
+            Err(error) => {
+                tracing::error!(
+                    provider = %id,
+                    old_ip = event.old.as_deref().unwrap_or_default(),
+                    new_ip = %event.new,
+                    "Failed to update {} record of {} on {}: {:#?}",
+                    record_type,
+                    record,
+                    id,
+                    error
+                );
+                published.failing_since.get_or_insert_with(now);
+                failed.push(format!("{record_type} record of {record} on {id}"));
+            }
+        }
+    }
+    if event.success && config.metadata_record {
+        publish_metadata(config, id, provider, record).await;
+    }
+    if event.success && !config.reverse_zones.is_empty() {
+        let old: Vec<_> = published
+            .addr
+            .into_iter()
+            .chain(published.additional.iter().copied())
+            .collect();
+        publish_reverse(config, id, provider, record, &target.current, &old).await;
+    }
+    // Setting an unchanged record again is no change to tell anyone about.
+    if unchanged && event.success {
+        return;
+    }
+    config.notifications.send(&event, &config.outbound).await;
+    config.hooks.run(&event).await;
+}
+
+/// Set the metadata record of `record` to the current time and the version of
+/// dyndnsd. Failures are only logged, as the record itself is up to date.
+async fn publish_metadata(config: &Config, id: &str, provider: &DnsProvider, record: &Name) {
+    if !provider.can_set_records() {
+        tracing::debug!("{} can't set TXT records, skipping the metadata record", id);
+        return;
+    }
+    let name = match metadata_name(record) {
+        Ok(name) => name,
+        Err(error) => {
+            tracing::warn!("{:#}", error);
+            return;
+        }
+    };
+    let updated = format!(
+        "updated={}",
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    );
+    let version = format!("version=dyndnsd {}", env!("CARGO_PKG_VERSION"));
+    // TXT displays its strings without separating them.
+    let value = format!("\"{updated}\" \"{version}\"");
+    let rdata = RData::TXT(TXT::new(vec![updated, version]));
+    let change = config.outbound.call(id, || {
+        provider.set_record(rdata.clone(), name.clone(), config.zone().clone())
+    });
+    if let Err(error) = config
+        .audited(provider, &name, RecordType::TXT, None, Some(value), change)
+        .await
+    {
+        tracing::warn!("Failed to set the metadata record {}: {:#}", name, error);
+    }
+}
+
+/// Point the PTR records of the `current` addresses of `record` at it, and
+/// delete the ones of its `old` addresses, in the configured reverse zones.
+/// Failures are only logged, as the record itself is up to date.
+async fn publish_reverse(
+    config: &Config,
+    id: &str,
+    provider: &DnsProvider,
+    record: &Name,
+    current: &[IpAddr],
+    old: &[IpAddr],
+) {
+    if !provider.can_set_records() {
+        tracing::debug!("{} can't set PTR records, skipping the reverse zones", id);
+        return;
+    }
+    for addr in current {
+        let Some(zone) = config.reverse_zone(*addr) else {
+            tracing::debug!("No reverse zone configured for {}", addr);
+            continue;
+        };
+        let name = Name::from(*addr);
+        let rdata = RData::PTR(PTR(record.clone()));
+        let change = config.outbound.call(id, || {
+            provider.set_record(rdata.clone(), name.clone(), zone.clone())
+        });
+        if let Err(error) = config
+            .audited(
+                provider,
+                &name,
+                RecordType::PTR,
+                None,
+                Some(record.to_string()),
+                change,
+            )
+            .await
+        {
+            tracing::warn!("Failed to set the PTR record of {}: {:#}", addr, error);
+        }
+    }
+    for addr in old.iter().filter(|addr| !current.contains(addr)) {
+        let Some(zone) = config.reverse_zone(*addr) else {
+            continue;
+        };
+        let name = Name::from(*addr);
+        let change = config.outbound.call(id, || {
+            provider.delete_records(RecordType::PTR, name.clone(), zone.clone())
+        });
+        if let Err(error) = config
+            .audited(
+                provider,
+                &name,
+                RecordType::PTR,
+                Some(record.to_string()),
+                None,
+                change,
+            )
+            .await
+        {
+            tracing::warn!("Failed to delete the PTR record of {}: {:#}", addr, error);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Secrets read from a HashiCorp Vault KV v2 engine while the config is
+//! loaded, e.g. `key = { vault = "dyndnsd/tsig", field = "key" }`.
+
+use std::{cell::RefCell, collections::HashMap, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+use crate::{
+    proxy::{self, Proxy},
+    secret::Secret,
+};
+
+/// Timeout of each request to Vault
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    /// Base URL of the Vault server, e.g. `https://vault.example.com:8200`
+    address: String,
+    /// Path the KV v2 secrets engine is mounted at
+    #[serde(default = "default_mount")]
+    mount: String,
+    /// Vault Enterprise namespace
+    namespace: Option<String>,
+    token: Option<Secret<String>>,
+    approle: Option<AppRole>,
+    /// The proxy configured alongside, as the secrets are read before it's
+    /// applied to all clients.
+    #[serde(skip)]
+    proxy: Option<Proxy>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AppRole {
+    role_id: String,
+    secret_id: Secret<String>,
+    /// Path the AppRole auth method is mounted at
+    #[serde(default = "default_approle_mount")]
+    mount: String,
+}
+
+fn default_mount() -> String {
+    "secret".into()
+}
+
+fn default_approle_mount() -> String {
+    "approle".into()
+}
+
+/// A logged in client, along with the secrets read so far, so a path holding
+/// several fields is only read once.
+pub(crate) struct Session {
+    config: Config,
+    token: Secret<String>,
+    secrets: HashMap<String, Map<String, Value>>,
+}
+
+thread_local! {
+    /// The session secrets are read with while the config is deserialized.
+    static SESSION: RefCell<Option<Session>> = const { RefCell::new(None) };
+}
+
+impl Config {
+    /// The `[vault]` section of the config, if it has one.
+    pub(crate) fn read(config: &str) -> Result<Option<Self>, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Section {
+            vault: Option<Config>,
+            proxy: Option<Proxy>,
+        }
+        let section: Section = toml::from_str(config)?;
+        Ok(section.vault.map(|vault| Self {
+            proxy: section.proxy,
+            ..vault
+        }))
+    }
+
+    pub(crate) fn login(&self) -> Result<Session> {
+        let token = match (&self.token, &self.approle) {
+            (Some(token), None) => token.clone(),
+            (None, Some(approle)) => block_on(self.login_approle(approle))
+                .context("Failed to log in to Vault with AppRole")?,
+            _ => bail!("Either a token or approle has to be set for Vault"),
+        };
+        Ok(Session {
+            config: self.clone(),
+            token,
+            secrets: HashMap::new(),
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> Result<reqwest::RequestBuilder> {
+        let client = proxy::with(self.proxy.as_ref()).timeout(TIMEOUT).build()?;
+        let url = format!("{}/v1/{}", self.address.trim_end_matches('/'), path);
+        let request = client.request(method, url);
+        Ok(match &self.namespace {
+            Some(namespace) => request.header("X-Vault-Namespace", namespace),
+            None => request,
+        })
+    }
+
+    async fn login_approle(&self, approle: &AppRole) -> Result<Secret<String>> {
+        #[derive(Deserialize)]
+        struct Login {
+            auth: Auth,
+        }
+        #[derive(Deserialize)]
+        struct Auth {
+            client_token: Secret<String>,
+        }
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("auth/{}/login", approle.mount),
+            )?
+            .json(&json!({
+                "role_id": approle.role_id,
+                "secret_id": approle.secret_id.as_str(),
+            }))
+            .send()
+            .await
+            .context("Failed to send request to Vault")?;
+        let login: Login = parse(response).await?;
+        Ok(login.auth.client_token)
+    }
+
+    /// The fields of the secret at `path`.
+    async fn get(&self, token: &str, path: &str) -> Result<Map<String, Value>> {
+        #[derive(Deserialize)]
+        struct Response {
+            data: Data,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            data: Map<String, Value>,
+        }
+        let response = self
+            .request(
+                reqwest::Method::GET,
+                &format!("{}/data/{}", self.mount, path.trim_start_matches('/')),
+            )?
+            .header("X-Vault-Token", token)
+            .send()
+            .await
+            .context("Failed to send request to Vault")?;
+        let response: Response = parse(response).await?;
+        Ok(response.data.data)
+    }
+}
+
+/// The JSON body of a successful response, or the errors Vault returned.
+async fn parse<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    #[derive(Deserialize)]
+    struct Errors {
+        #[serde(default)]
+        errors: Vec<String>,
+    }
+    let status = response.status();
+    if !status.is_success() {
+        let errors = response
+            .json::<Errors>()
+            .await
+            .unwrap_or(Errors { errors: Vec::new() });
+        bail!(
+            "Vault returned an error ({}): {}",
+            status,
+            errors.errors.join(", ")
+        );
+    }
+    response
+        .json()
+        .await
+        .context("Failed to parse the response of Vault")
+}
+
+/// Run `future` on a runtime of its own, in a thread of its own, as secrets
+/// are read while the config is deserialized, which can't wait for it.
+fn block_on<T: Send>(future: impl Future<Output = Result<T>> + Send) -> Result<T> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .context("Failed to start the runtime")?
+                    .block_on(future)
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Deserialize the config with `parse`, reading secrets through `session`.
+pub(crate) fn with_session<T>(session: Session, parse: impl FnOnce() -> T) -> T {
+    SESSION.with_borrow_mut(|current| *current = Some(session));
+    let result = parse();
+    SESSION.with_borrow_mut(|current| *current = None);
+    result
+}
+
+/// The `field` of the secret at `path`, read through the current session.
+pub(crate) fn secret(path: &str, field: &str) -> Result<Secret<String>> {
+    SESSION.with_borrow_mut(|session| {
+        let session = session
+            .as_mut()
+            .ok_or_else(|| anyhow!("Secrets can only be read from Vault with a [vault] section"))?;
+        if !session.secrets.contains_key(path) {
+            let fields = block_on(session.config.get(&session.token, path))
+                .with_context(|| format!("Failed to read {path} from Vault"))?;
+            tracing::debug!("Read {} from Vault", path);
+            session.secrets.insert(path.to_owned(), fields);
+        }
+        match session.secrets[path].get(field) {
+            Some(Value::String(value)) => Ok(Secret::new(value.clone())),
+            Some(_) => bail!("{field} of {path} in Vault isn't a string"),
+            None => bail!("{path} in Vault has no field {field}"),
+        }
+    })
+}
@@ -0,0 +1,122 @@
+// Copyright (C) 2021-2024 Jan Christian Grünhage <jan.christian@gruenhage.xyz>
+//
+// This file is part of dyndnsd.
+//
+// dyndnsd is non-violent software: you can use, redistribute, and/or modify it
+// under the terms of the CNPLv7+ as found in the LICENSE.md file in the source code root directory
+// or at <https://git.pixie.town/thufie/npl-builder>.
+//
+// dyndnsd comes with ABSOLUTELY NO WARRANTY, to the extent permitted by applicable
+// law. See the LICENSE.md for details.
+
+//! Discovery of the zone containing the domain, for configs without `zone`.
+
+use std::{
+    fs::read_to_string,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use hickory_client::client::{Client, ClientHandle};
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{DNSClass, Name, RData, RecordType},
+    runtime::TokioRuntimeProvider,
+    udp::UdpClientStream,
+};
+
+use crate::{outbound::Outbound, provider::Config as ProviderConfig};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Find the apex of the zone containing `domain`, by querying the SOA record
+/// of `domain` and then each of its parents until one has one.
+///
+/// The queries go to the first provider able to read records, as that's the
+/// server the updates are sent to, and to the resolvers from
+/// `/etc/resolv.conf` otherwise.
+pub async fn discover(
+    domain: &Name,
+    providers: &[ProviderConfig],
+    outbound: &Outbound,
+) -> Result<Name> {
+    let provider = providers.iter().find(|provider| provider.can_get_records());
+    let resolvers = match provider {
+        Some(_) => Vec::new(),
+        None => resolvers()?,
+    };
+    let mut candidate = domain.clone();
+    while !candidate.is_root() {
+        let records = outbound
+            .call("zone-discovery", || async {
+                match provider {
+                    Some(provider) => {
+                        provider
+                            .get_records(RecordType::SOA, candidate.clone())
+                            .await
+                    }
+                    None => query(&resolvers, &candidate).await,
+                }
+            })
+            .await
+            .with_context(|| format!("Failed to query the SOA record of {candidate}"))?;
+        if !records.is_empty() {
+            return Ok(candidate);
+        }
+        candidate = candidate.base_name();
+    }
+    bail!("Neither {} nor any of its parents has a SOA record", domain)
+}
+
+/// The nameservers configured in `/etc/resolv.conf`.
+fn resolvers() -> Result<Vec<SocketAddr>> {
+    let resolv_conf =
+        read_to_string(RESOLV_CONF).with_context(|| format!("Failed to read {RESOLV_CONF}"))?;
+    let resolvers: Vec<_> = resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .map(|addr| SocketAddr::new(addr, 53))
+        .collect();
+    if resolvers.is_empty() {
+        bail!("No nameserver configured in {}", RESOLV_CONF);
+    }
+    Ok(resolvers)
+}
+
+/// Query the SOA record of `name` from the first resolver that answers.
+async fn query(resolvers: &[SocketAddr], name: &Name) -> Result<Vec<RData>> {
+    let mut last_error = None;
+    for resolver in resolvers {
+        let result = async {
+            let conn = UdpClientStream::builder(*resolver, TokioRuntimeProvider::default()).build();
+            let (mut client, bg) = Client::connect(conn).await?;
+            tokio::spawn(bg);
+            let response = client
+                .query(name.clone(), DNSClass::IN, RecordType::SOA)
+                .await?;
+            match response.response_code() {
+                // Resolvers follow CNAMEs, so only a SOA owned by the name
+                // itself marks it as the apex.
+                ResponseCode::NoError | ResponseCode::NXDomain => Ok(response
+                    .answers()
+                    .iter()
+                    .filter(|record| {
+                        record.record_type() == RecordType::SOA && record.name() == name
+                    })
+                    .map(|record| record.data().clone())
+                    .collect()),
+                code => bail!("{} refused the query: {}", resolver, code),
+            }
+        }
+        .await;
+        match result {
+            Ok(records) => return Ok(records),
+            Err(error) => {
+                tracing::debug!("{} failed to answer: {:#}", resolver, error);
+                last_error = Some(error);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("No resolver configured")))
+}